@@ -1,207 +1,790 @@
 #ifndef CLIENT_H
 #define CLIENT_H
 
-#ifdef __cplusplus
-extern "C" {
-#endif // def __cplusplus
+// Generated from the Rust FFI definitions in rs/client by cbindgen. Do not edit.
+// To regenerate: UPDATE_HEADER=1 cargo test -p client --test header
 
+#include <stddef.h>
 #include <stdint.h>
+#include <stdbool.h>
 
-struct ClientHandle_t;
 typedef struct ClientHandle_t *ClientHandle;
 
-struct StringInputParameter_t {
-    const char *const string;
-};
-struct StringOutputParameter_t {
-    const char *string;
-    void (*release)(const char*);
-};
-
-struct ArrayInputParameter_t {
-    const int length;
-    const void *const data;
-};
-struct ArrayOutputParameter_t {
-    int length;
-    void *data;
-    void (*release)(int, void*);
-};
+// The length of StreamStats::last_error, including the terminating NUL.
+#define STREAM_ERROR_LENGTH 256
 
 enum ErrorCode {
-    /// Success
+    // Success
     NoError = 0,
-    /// An invalid handle was passed (and the error was detected)
+    // An invalid handle was passed (and the error was detected)
     InvalidHandle = 1,
-    /// An operation that requires a connected handle was called
-    /// with an unconencted handle.
+    // An operation that requires a connected handle was called
+    // with an unconencted handle.
     NotConnected = 2,
-    /// An operation that requires an unconnected handle was called
-    /// with a conencted handle.
+    // An operation that requires an unconnected handle was called
+    // with a conencted handle.
     AlreadyConnected = 3,
-    /// A required parameter was null.
+    // A required parameter was null.
     NullParameter = 4,
-    /// A string was not UTF8.
+    // A string was not UTF8.
     NonUtf8String = 5,
-    /// A parameter was invalid.
+    // A parameter was invalid.
     InvalidParameter = 6,
-    /// An error occurred reading a message
+    // An error occurred reading a message
     MessageReadError = 7,
-    /// The server sent an invalid message
+    // The server sent an invalid message
     InvalidMessageReceived = 8,
-    /// An error occurred writing a message
+    // An error occurred writing a message
     MessageWriteError = 9,
-    /// Tried to register a axis/function/sensor/stream with the same name as an
-    /// existing one of the same thing.
+    // Tried to register a axis/function/sensor/stream with the same name as an
+    // existing one of the same thing.
     DuplicateName = 10,
-    /// The server disconnected.
+    // The server disconnected.
     ServerDisconnected = 11,
-    /// The operation is unsupported (e.g. streams on Windows).
+    // The operation is unsupported (e.g. streams on Windows).
     Unsupported = 12,
-    /// The server rejected the connection.
+    // The server rejected the connection.
     ConnectionRejected = 13,
-    /// Failed to connect because a required value (e.g. name) was not set.
+    // Failed to connect because a required value (e.g. name) was not set.
     MissingRequiredValue = 14,
-    /// Error connecting to server.
+    // Error connecting to server.
     ConnectionError = 15,
-    /// Other error (nonfatal) e.g. server sent a FunctionCall with invalid parameters
-    /// or a message that should never be sent to machine (e.g. AxisReturn)
-    Other = 16
+    // Other error (nonfatal), e.g. server sent a FunctionCall with invalid parameters,
+    // or a message that should never be sent to machine (e.g. AxisReturn)
+    OtherError = 16,
+    // A machine config file could not be read, or was invalid.
+    InvalidConfig = 17,
+    // Data was written to a BufferNoDiscard stream while the server had paused it, so it was not
+    // queued (StreamPaused is the name of the stream state).
+    StreamIsPaused = 18,
+};
+
+// What to do with a stream's data when the server falls behind (see RegisterStreamEx).
+enum StreamBufferMethod {
+    // Wait for the server. No data is discarded.
+    BufferNoDiscard = 0,
+    // Buffer up to buffer_size frames, then discard the oldest.
+    BufferFrames = 1,
+    // Buffer up to buffer_size bytes (but at least one frame), then discard the oldest frames.
+    BufferBytes = 2,
+};
+
+// The layout of a sample of a pcm stream (see RegisterPcmStream).
+enum PcmSampleFormat {
+    // Unsigned 8-bit
+    SampleU8 = 0,
+    // Signed 16-bit little-endian
+    SampleS16LE = 1,
+    // Signed 16-bit big-endian
+    SampleS16BE = 2,
+    // Signed 32-bit little-endian
+    SampleS32LE = 3,
+    // Signed 32-bit big-endian
+    SampleS32BE = 4,
+    // 32-bit float little-endian
+    SampleF32LE = 5,
+    // 32-bit float big-endian
+    SampleF32BE = 6,
+};
+
+// Whether a stream is being sent to the server, as controlled by the server (see SetStreamControlCallback).
+enum StreamState {
+    // The stream's data is being sent. Streams start in this state.
+    StreamStarted = 0,
+    // The stream's data is not being sent, and what has been buffered is kept for when it resumes.
+    // With BufferNoDiscard, the stream's fd is not read (and StreamWrite returns StreamIsPaused)
+    // until it resumes; otherwise, the stream's data is discarded until it resumes.
+    StreamPaused = 1,
+    // The stream's data (including what has been buffered) is discarded, e.g. because nobody is
+    // watching it, until it is started again, from its next keyframe.
+    StreamStopped = 2,
+};
+
+// How a stream's data is sent to the server (see SetStreamTransport).
+enum StreamTransport {
+    // Over a TCP connection. No data is lost, but a lost packet delays the rest of the stream.
+    TransportTcp = 0,
+    // As UDP datagrams. A lost datagram loses its frame, but does not delay the rest of the stream.
+    TransportUdp = 1,
+};
+
+// What has become of the threads sending a stream to the server (see GetStreamStatus).
+enum StreamStatus {
+    // The stream is being sent to the server.
+    StreamRunning = 0,
+    // The stream's data has ended (its fd reached end-of-file), and all of it has been sent.
+    StreamEnded = 1,
+    // Reading the stream's fd, or writing to the server, failed. The stream is no longer sent.
+    StreamFailed = 2,
+};
+
+struct ClientHandle_t;
+
+// What the application writes the data of a stream without an fd through (see GetStreamWriter).
+// It does not borrow the client handle, so it may be used from any thread.
+struct StreamWriter;
+
+// A callback of any type, as returned by the lookup function passed to LoadMachineConfig.
+typedef void (*GenericCallback_t)(void);
+
+// Statistics of a stream being sent to the server (see GetStreamStats).
+struct StreamStats {
+    // The number of bytes written to the server, including frame headers.
+    uint64_t bytes_sent;
+    // The number of frames written to the server.
+    uint64_t frames_sent;
+    // The number of frames discarded by the stream's buffer method, or because the server had
+    // paused or stopped the stream, or (for udp streams) that could not be sent for the moment.
+    uint64_t frames_dropped;
+    // The number of frames waiting to be sent.
+    uint64_t frames_queued;
+    // The number of bytes written to the server per second, over about the last second.
+    double send_rate;
+    // Whether the stream is still being sent to the server.
+    enum StreamStatus status;
+    // Whether the server has started, paused, or stopped the stream.
+    enum StreamState state;
+    // Why the stream failed (truncated if necessary), or an empty string if it has not failed.
+    char last_error[STREAM_ERROR_LENGTH];
+};
+
+// A string parameter, as passed to a callback.
+struct StringInputParameter_t {
+    // The NUL-terminated, UTF-8 string.
+    const char *string;
+};
+
+// A string return, as written by a callback.
+struct StringOutputParameter_t {
+    // The NUL-terminated, UTF-8 string, initially NULL.
+    const char *string;
+    // If not NULL, called with string after it has been sent to the server.
+    void (*release)(const char*);
+};
+
+// An array parameter (or bytes), as passed to a callback.
+struct ArrayInputParameter_t {
+    // The number of elements.
+    int32_t length;
+    // The elements (e.g. const double[length], or const char *const[length] for a string[]).
+    const void *data;
+};
+
+// An array return (or bytes), as written by a callback.
+struct ArrayOutputParameter_t {
+    // The number of elements, initially 0.
+    int32_t length;
+    // The elements (e.g. double[length]), initially NULL.
+    void *data;
+    // If not NULL, called with length and data after they have been sent to the server.
+    void (*release)(int32_t, void*);
+};
+
+// A map parameter, as passed to a callback.
+struct MapInputParameter_t {
+    // The number of entries.
+    int32_t length;
+    // The keys (const char *const[length]).
+    const char *const *keys;
+    // The values, laid out as in an array of the value type (e.g. const double[length]).
+    const void *values;
+};
+
+// A map return, as written by a callback.
+struct MapOutputParameter_t {
+    // The number of entries, initially 0.
+    int32_t length;
+    // The keys (each with its own release, as in a string[]), initially NULL.
+    struct StringOutputParameter_t *keys;
+    // The values, laid out as in an array of the value type (e.g. double[length]), initially NULL.
+    void *values;
+    // If not NULL, called with length, keys, and values after the keys and values
+    // have been released individually.
+    void (*release)(int32_t, struct StringOutputParameter_t*, void*);
 };
 
-/**
-* Initialize the library and return a handle that will be passed to all library functions.
-* On success: returns a non-null handle (pointer).
-* On failure: returns ((ClientHandle)NULL)
-*/
-ClientHandle InitializeLibrary(void);
-
-/**
-* Set the name of the client.
-* On success: returns true
-* On failure: returns false
-*/
-enum ErrorCode SetName(ClientHandle, const char *name);
-
-/**
-* Set the reset function. This will be called when the server wants to reset the client
-* to a known safe state (e.g. if the unity client disconnects).
-* If this function pointer is NULL, or if the client never sets the function, then
-* the client library will do nothing upon recieving a reset message, except posibly print a
-* diagnostic message.
-* On success: returns true
-* On failure: returns false
-*/
-enum ErrorCode SetReset(ClientHandle, void (*reset)(void));
-
-/**
-* Registers a function.
-* TODO: document how callback works
-* @param handle     The client handle
-* @param parameters Parameter descriptors for input parameters
-* @param returns    Parameter descriptors for output parameters
-* @param callback   The callback function to call when the server calls the function
-* @returns enum ErrorCode success (Was the function registered successfully)
-* Parameter descriptor: A parameter descriptor is an array of two const char*,
-* the name and type, respectively, of the parameter.
-* The arrays of parameter descritptors passed into this function should be
-* terminated by {NULL, NULL}.
-*/
-enum ErrorCode RegisterFunction(
-    ClientHandle handle,
-    const char *name,
-    const char *(*parameters)[2],
-    const char *(*returns)[2],
-    void (*callback)(const void *const*const, void *const*const)
-);
-
-/**
-* Registers a sensor.
-* TODO: document how callback works
-* @param handle     The client handle
-* @param min        The minimum value that this sensor can have (not enforced)
-* @param max        The maximum value that this sensor can have (not enforced)
-* @param callback   The callback function to call when the server reads the sensor
-* @returns enum ErrorCode success (Was the sensor registered successfully)
-* Type descriptor: A type descriptor is const char*, the type of the parameter.
-*/
-enum ErrorCode RegisterSensor(
-    ClientHandle handle,
-    const char *name,
-    double min,
-    double max,
-    void (*callback)(double *const)
-);
-
-/**
-* Registers an axis.
-* TODO: document how callback works
-* @param handle     The client handle
-* @param min        The minimum value that this axis can have (not enforced)
-* @param max        The maximum value that this axis can have (not enforced)
-* @param group      The group that this axis is a member of
-* @param direction  The direction that this axis is in
-* @param callback   The callback function to call when the server moves the axis
-* @returns enum ErrorCode success (Was the axis registered successfully)
-* The callback must take a single `double` parameter
-*/
-enum ErrorCode RegisterAxis(
-    ClientHandle handle,
-    const char *name,
-    double min,
-    double max,
-    const char *group,
-    const char *direction,
-    void (*callback)(const double)
-);
-
-/**
-* Registers a stream.
-* @param handle     The client handle
-* @param name       The name of the stream
-* @param format     The format of the stream (only mjpeg is currently supported)
-* @param fd         The file descriptor to read stream data from.
-* @returns enum ErrorCode success (Was the stream registered successfully)
-*/
-enum ErrorCode RegisterStream(
-    ClientHandle handle,
-    const char *name,
-    const char *format,
-    int fd
-);
-
-/**
-* Connects to a server
-* @param server     String that is the domain name or IP address (v4 or v6) of the server.
-* @param port       uint16_t that is the port to connect to on the server.
-* @returns enum ErrorCode success (Did the client connect successfully)
-*/
-enum ErrorCode ConnectToServer(
-    ClientHandle handle,
-    const char *server,
-    uint16_t port,
-    uint16_t stream_port
-);
-
-/**
-* Updates internal library state and calls any necessary callbacks.
-*/
-enum ErrorCode LibraryUpdate(ClientHandle);
-
-/**
-* Returns (in *result) the number of milliseconds since the last message from the server was handled.
-* If no messages have yet been received, returns -1 in *result.
-* May also return -1 spuriously if the platforms monotonic clock is not actually monotonic.
-*/
-enum ErrorCode MillisecondsSinceLastMessage(ClientHandle, signed long *result);
-
-/**
-* Deinitialize and shut down the library.
-*/
-void ShutdownLibrary(ClientHandle);
+// A nullable parameter, as passed to a callback.
+struct NullableInputParameter_t {
+    // false if the value is null.
+    bool present;
+    // What a parameter of the type without the "?" would point to, or NULL if the value is null.
+    const void *value;
+};
+
+// A nullable return, as written by a callback.
+struct NullableOutputParameter_t {
+    // Initially false. The value is null unless the callback sets this to true.
+    bool present;
+    // Where the callback should write the value, as it would for a return of the type without the "?".
+    void *value;
+};
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Initialize the library and return a handle that will be passed to all library functions.
+// On success: returns a non-null handle (pointer).
+// On failure: returns ((ClientHandle)NULL)
+struct ClientHandle_t *InitializeLibrary(void);
+
+// Deinitialize and shut down the library.
+// Stops sending streams to the server, and waits for the threads sending them to exit.
+void ShutdownLibrary(struct ClientHandle_t *handle);
+
+// Set the name of the client.
+// @returns enum ErrorCode success (Was the name set successfully)
+enum ErrorCode SetName(struct ClientHandle_t *handle, const char *name);
+
+// Set the reset function. This will be called when the server wants to reset the client
+// to a known safe state (e.g. if the unity client disconnects).
+// If this function pointer is NULL, or if the client never sets the function, then
+// the client library will do nothing upon recieving a reset message, except posibly print a
+// diagnostic message.
+// @returns enum ErrorCode success (Was the reset function set successfully)
+enum ErrorCode SetReset(struct ClientHandle_t *handle, void (*reset)(void));
+
+// Set whether function calls from the server may include parameters that the function
+// did not declare. By default (strict), a function call with unknown parameters, missing
+// parameters, or parameters of the wrong type is rejected, and the server is sent an error
+// listing every invalid parameter. When lenient, unknown parameters are ignored instead
+// (missing parameters and parameters of the wrong type are still rejected).
+// Must be called before connecting to the server.
+// @param lenient    true to ignore unknown parameters, false to reject them
+// @returns enum ErrorCode success (Was the setting changed successfully)
+enum ErrorCode SetLenientParameters(struct ClientHandle_t *handle, bool lenient);
+
+// Returns whether the server supplied the parameter at the given index of the currently
+// running function callback (i.e. false if it was omitted, and NULL or its default value
+// was passed instead). Returns false if not called from a function callback.
+// @param index      The index of the parameter in the function's parameter descriptors
+bool ParameterSupplied(int index);
+
+// Set an error message describing why the currently running callback failed.
+// Only meaningful when called from a status-returning callback (registered with
+// RegisterFunctionEx, RegisterSensorEx, or RegisterAxisEx) that then returns a nonzero status.
+// The message is copied, and is sent to the server along with the status.
+// @param message    The error message
+// @returns enum ErrorCode success (Was the message set successfully)
+enum ErrorCode SetCallbackError(const char *message);
+
+// Updates internal library state and calls any necessary callbacks.
+enum ErrorCode LibraryUpdate(struct ClientHandle_t *handle);
+
+// Registers a struct type, which can then be used by name as the type of function parameters,
+// function returns, sensors (see RegisterSensorTyped), and fields of other structs.
+// @param handle     The client handle
+// @param name       The name of the type
+// @param fields     Parameter descriptors for the fields of the struct (see RegisterFunction),
+//                   which may not be optional or have defaults.
+// @returns enum ErrorCode success (Was the struct registered successfully)
+// In JSON, a struct is an object with exactly the given fields (in order, when returned),
+// except that fields of a nullable type may be missing, in which case they are null.
+// When passed to a callback, a struct is an array of pointers, one for each field in order,
+// the same way that parameters and returns are passed to a function callback.
+// I.e. a struct parameter parameters[i] points to a const void *const[number of fields],
+// and a struct return returns[i] points to a void *const[number of fields].
+enum ErrorCode RegisterStruct(struct ClientHandle_t *handle,
+                              const char *name,
+                              const char *(*fields)[2]);
+
+// Registers an enum type, which can then be used by name the same way as a struct type
+// (see RegisterStruct).
+// @param handle     The client handle
+// @param name       The name of the type
+// @param values     The allowed values of the enum, terminated by NULL.
+// @returns enum ErrorCode success (Was the enum registered successfully)
+// In JSON, an enum is a string, which must be one of the allowed values.
+// When passed to a callback, an enum is an int, the index of the value in values.
+enum ErrorCode RegisterEnum(struct ClientHandle_t *handle,
+                            const char *name,
+                            const char *const *values);
+
+// Registers a function.
+// TODO: document how callback works
+// @param handle     The client handle
+// @param parameters Parameter descriptors for input parameters
+// @param returns    Parameter descriptors for output parameters
+// @param callback   The callback function to call when the server calls the function
+// @returns enum ErrorCode success (Was the function registered successfully)
+// Parameter descriptor: A parameter descriptor is an array of two const char*,
+// the name and type, respectively, of the parameter.
+// The arrays of parameter descritptors passed into this function should be
+// terminated by {NULL, NULL}.
+// Types: bool, byte (int8_t), short (int16_t), int (int32_t), long (int64_t),
+// ubyte (uint8_t), ushort (uint16_t), uint (uint32_t), ulong (uint64_t), float, double, and string,
+// or an array of any of those (e.g. "double[]"), bytes, structs registered with RegisterStruct,
+// and enums registered with RegisterEnum.
+// Bytes are base64-encoded in JSON, and are passed to and from the callback the same way as
+// a ubyte[] (struct ArrayInputParameter_t and struct ArrayOutputParameter_t). The fixed-width names i8, i16, i32, i64,
+// u8, u16, u32, and u64 may be used instead of the integer type names.
+// A primitive type followed by one or more fixed lengths (e.g. "double[3]" or "float[4][4]")
+// is a fixed-size array, which is a nested array of exactly those lengths in JSON, and is passed
+// to and from the callback as a pointer to its elements, in row-major order, with no length
+// (e.g. parameters[i] points to a const float[4][4], and returns[i] points to a float[4][4]).
+// A fixed-size array may have at most 1048576 (2^20) elements in all.
+// "map<string, T>", where T is bool, a numeric type, or string, is an object in JSON, and is passed
+// to the callback as a struct MapInputParameter_t, whose keys and values are parallel arrays of length
+// length, with values laid out as in a T[] (e.g. const double[length], or const char *const[length]).
+// It is returned from the callback as a struct MapOutputParameter_t, whose keys are strings
+// (each with its own release, as in a string[]) and whose values are laid out as in a T[]
+// (e.g. double[length], or struct StringOutputParameter_t[length]). After the keys and values
+// are released individually, release (if not NULL) is called with length, keys, and values.
+// Any type followed by "?" (e.g. "double?" or "string[]?") is nullable: its JSON value may also be null.
+// It is passed to the callback as a struct NullableInputParameter_t, whose value points to what a
+// parameter of the type without the "?" would point to, or is NULL if the JSON value was null.
+// It is returned from the callback as a struct NullableOutputParameter_t, whose present is false
+// when the callback is called, and whose value points to where the callback should write the value;
+// the JSON value is null unless the callback sets present to true.
+// Integer parameters whose JSON value does not fit in the type are rejected.
+// The type of an input parameter may be followed by " optional" (e.g. "int optional"),
+// in which case the server may omit it, and the callback will receive NULL for it,
+// or by "= " and a JSON default value (e.g. "double = 1.5" or "string = \"hi\""),
+// in which case the default value will be used if the server omits it.
+// See ParameterSupplied to check whether the server supplied a parameter.
+enum ErrorCode RegisterFunction(struct ClientHandle_t *handle,
+                                const char *name,
+                                const char *(*parameters)[2],
+                                const char *(*returns)[2],
+                                void (*callback)(const void*const *, void*const *));
+
+// Registers a function whose callback can fail.
+// Same as RegisterFunction, except that the callback returns a status code:
+// 0 on success, or nonzero on failure. On failure, the returns are not sent to the server;
+// an error reply is sent instead (see SetCallbackError).
+enum ErrorCode RegisterFunctionEx(struct ClientHandle_t *handle,
+                                  const char *name,
+                                  const char *(*parameters)[2],
+                                  const char *(*returns)[2],
+                                  int (*callback)(const void*const *, void*const *));
+
+// Registers a sensor.
+// TODO: document how callback works
+// @param handle     The client handle
+// @param min        The minimum value that this sensor can have (not enforced)
+// @param max        The maximum value that this sensor can have (not enforced)
+// @param callback   The callback function to call when the server reads the sensor
+// @returns enum ErrorCode success (Was the sensor registered successfully)
+// Type descriptor: A type descriptor is const char*, the type of the parameter.
+enum ErrorCode RegisterSensor(struct ClientHandle_t *handle,
+                              const char *name,
+                              double min,
+                              double max,
+                              void (*callback)(double*));
+
+// Registers a sensor whose callback can fail.
+// Same as RegisterSensor, except that the callback returns a status code:
+// 0 on success, or nonzero on failure. On failure, the value is not sent to the server;
+// an error reply is sent instead (see SetCallbackError).
+enum ErrorCode RegisterSensorEx(struct ClientHandle_t *handle,
+                                const char *name,
+                                double min,
+                                double max,
+                                int (*callback)(double*));
+
+// Registers a sensor of any type that a function can return (see RegisterFunction).
+// @param handle     The client handle
+// @param name       The name of the sensor
+// @param type       The type of the sensor
+// @param callback   The callback function to call when the server reads the sensor
+// @returns enum ErrorCode success (Was the sensor registered successfully)
+// The callback's parameter points to the same thing that a function return of the sensor's type
+// would (e.g. a double for "double", or a struct ArrayOutputParameter_t for "bytes").
+// The callback returns 0 on success, or nonzero on failure (see RegisterSensorEx).
+enum ErrorCode RegisterSensorTyped(struct ClientHandle_t *handle,
+                                   const char *name,
+                                   const char *type,
+                                   int (*callback)(void*));
+
+// Registers a stream.
+// @param handle     The client handle
+// @param name       The name of the stream
+// @param format     The format of the stream: "mjpeg", "h264" (Annex B), or "ndjson"
+//                   (for pcm, see RegisterPcmStream)
+// @param fd         The file descriptor to read stream data from, or -1 to write the data
+//                   with StreamWrite or StreamWriteFrame instead.
+// @returns enum ErrorCode success (Was the stream registered successfully)
+// Same as RegisterStreamEx with BufferNoDiscard.
+enum ErrorCode RegisterStream(struct ClientHandle_t *handle,
+                              const char *name,
+                              const char *format,
+                              int fd);
+
+// Registers a stream, with what to do with its data when the server falls behind.
+// @param handle        The client handle
+// @param name          The name of the stream
+// @param format        The format of the stream: "mjpeg", "h264" (Annex B), or "ndjson"
+//                      (for pcm, see RegisterPcmStream)
+// @param fd            The file descriptor to read stream data from, or -1 to write the data
+//                      with StreamWrite or StreamWriteFrame instead.
+// @param buffer_method What to do when the server falls behind (see enum StreamBufferMethod)
+// @param buffer_size   The number of frames (BufferFrames) or bytes (BufferBytes) to buffer
+//                      before discarding the oldest, which must be positive. Ignored for BufferNoDiscard.
+// @returns enum ErrorCode success (Was the stream registered successfully)
+// The buffer method and size are sent to the server, and are also applied to the data waiting
+// to be sent to the server. Data read from fd (or written with StreamWrite) is split into frames
+// according to the format, and only whole frames are discarded. For mjpeg, each frame is one JPEG
+// image, and anything between images (such as multipart boundaries) is discarded. For h264, each
+// frame is one access unit (all of its NAL units, with their start codes). For ndjson, each frame
+// is one record, with its newline.
+// Each frame is sent to the server with its length, a sequence number, the time it was captured
+// (when it was read from fd or written, see MonotonicMicroseconds), and whether it is a keyframe
+// (for h264, whether it contains an IDR picture).
+enum ErrorCode RegisterStreamEx(struct ClientHandle_t *handle,
+                                const char *name,
+                                const char *format,
+                                int fd,
+                                enum StreamBufferMethod buffer_method,
+                                uint64_t buffer_size);
+
+// Registers a stream of raw PCM audio, with the layout of its samples.
+// @param handle        The client handle
+// @param name          The name of the stream
+// @param fd            The file descriptor to read stream data from, or -1 to write the data
+//                      with StreamWrite or StreamWriteFrame instead.
+// @param buffer_method What to do when the server falls behind (see RegisterStreamEx)
+// @param buffer_size   The number of frames or bytes to buffer (see RegisterStreamEx)
+// @param sample_rate   Samples per second, per channel, which must be positive
+// @param channels      The number of channels, whose samples are interleaved, which must be positive
+// @param sample_format The layout of each sample (see enum PcmSampleFormat)
+// @returns enum ErrorCode success (Was the stream registered successfully)
+// The layout is sent to the server with the stream's format, "pcm". Each frame is the whole samples
+// (for every channel) of a read from fd (or StreamWrite), and the rest is kept for the next frame.
+enum ErrorCode RegisterPcmStream(struct ClientHandle_t *handle,
+                                 const char *name,
+                                 int fd,
+                                 enum StreamBufferMethod buffer_method,
+                                 uint64_t buffer_size,
+                                 uint32_t sample_rate,
+                                 uint16_t channels,
+                                 enum PcmSampleFormat sample_format);
+
+// Registers an axis.
+// TODO: document how callback works
+// @param handle     The client handle
+// @param min        The minimum value that this axis can have (not enforced)
+// @param max        The maximum value that this axis can have (not enforced)
+// @param group      The group that this axis is a member of
+// @param direction  The direction that this axis is in
+// @param callback   The callback function to call when the server moves the axis
+// @returns enum ErrorCode success (Was the axis registered successfully)
+// The callback must take a single `double` parameter
+enum ErrorCode RegisterAxis(struct ClientHandle_t *handle,
+                            const char *name,
+                            double min,
+                            double max,
+                            const char *group,
+                            const char *direction,
+                            void (*callback)(double));
+
+// Registers an axis whose callback can fail.
+// Same as RegisterAxis, except that the callback returns a status code:
+// 0 on success, or nonzero on failure. On failure, an error reply is sent to the
+// server (see SetCallbackError).
+enum ErrorCode RegisterAxisEx(struct ClientHandle_t *handle,
+                              const char *name,
+                              double min,
+                              double max,
+                              const char *group,
+                              const char *direction,
+                              int (*callback)(double));
+
+// Sets the human-readable metadata of a registered function, which is sent to the server.
+// @param handle      The client handle
+// @param function    The name of the function
+// @param description A description of the function, or NULL for none
+// @param unit        The unit of the function's result (e.g. "m/s", "degrees", or "A"), or NULL for none
+// @param tags        Free-form tags, terminated by NULL, or NULL for none
+// @returns enum ErrorCode success (Was the metadata set successfully)
+// The function must already be registered, and the metadata replaces any previously set.
+// Returns InvalidParameter if no function with that name is registered.
+enum ErrorCode SetFunctionMetadata(struct ClientHandle_t *handle,
+                                   const char *function,
+                                   const char *description,
+                                   const char *unit,
+                                   const char *const *tags);
+
+// Sets the human-readable metadata of a parameter or return of a registered function.
+// @param handle      The client handle
+// @param function    The name of the function
+// @param parameter   The name of the parameter or return
+// @param description A description of the parameter, or NULL for none
+// @param unit        The unit of the parameter (e.g. "m/s", "degrees", or "A"), or NULL for none
+// @param tags        Free-form tags, terminated by NULL, or NULL for none
+// @returns enum ErrorCode success (Was the metadata set successfully)
+// See SetFunctionMetadata.
+enum ErrorCode SetParameterMetadata(struct ClientHandle_t *handle,
+                                    const char *function,
+                                    const char *parameter,
+                                    const char *description,
+                                    const char *unit,
+                                    const char *const *tags);
+
+// Sets the human-readable metadata of a registered sensor.
+// See SetFunctionMetadata.
+enum ErrorCode SetSensorMetadata(struct ClientHandle_t *handle,
+                                 const char *sensor,
+                                 const char *description,
+                                 const char *unit,
+                                 const char *const *tags);
+
+// Sets the human-readable metadata of a registered axis.
+// See SetFunctionMetadata.
+enum ErrorCode SetAxisMetadata(struct ClientHandle_t *handle,
+                               const char *axis,
+                               const char *description,
+                               const char *unit,
+                               const char *const *tags);
+
+// Sets the human-readable metadata of a registered stream.
+// See SetFunctionMetadata.
+enum ErrorCode SetStreamMetadata(struct ClientHandle_t *handle,
+                                 const char *stream,
+                                 const char *description,
+                                 const char *unit,
+                                 const char *const *tags);
+
+// Sets the function called when the server starts, pauses, or stops a registered stream
+// (e.g. to shut down a camera pipeline while nobody is watching it).
+// @param handle     The client handle
+// @param stream     The name of the stream
+// @param callback   Called (by LibraryUpdate) with the stream's new state (see enum StreamState),
+//                   or NULL for none
+// @returns enum ErrorCode success (Was the callback set successfully)
+// Streams are started when the client connects, and the callback is only called when the
+// state changes.
+enum ErrorCode SetStreamControlCallback(struct ClientHandle_t *handle,
+                                        const char *stream,
+                                        void (*callback)(enum StreamState state));
+
+// Sets how a registered stream's data is sent to the server (see enum StreamTransport).
+// @param handle     The client handle
+// @param stream     The name of the stream
+// @param transport  TransportTcp (the default) or TransportUdp
+// @returns enum ErrorCode success (Was the transport set successfully)
+// With TransportUdp, a frame is lost if any of its datagrams is lost, but a lost datagram does
+// not delay the frames after it, which suits e.g. live video. Must be called before connecting.
+enum ErrorCode SetStreamTransport(struct ClientHandle_t *handle,
+                                  const char *stream,
+                                  enum StreamTransport transport);
+
+// Enables rate control for a registered stream, so that when the connection to the server is slower
+// than the stream's producer, frames are discarded according to the stream's buffer method (or with
+// BufferNoDiscard, the producer waits) instead of piling up in the socket, and the producer is told
+// what rate the connection can take.
+// @param handle     The client handle
+// @param stream     The name of the stream
+// @param max_unsent The number of bytes that may be waiting in the stream's socket before frames are
+//                   held back in the stream's buffer, or 0 for the default (64 KiB). Only measured
+//                   on Linux; elsewhere, frames are held back only while writing to the socket blocks.
+// @param callback   Called (by LibraryUpdate) with a target bitrate (in bits per second) and frame
+//                   rate (in frames per second) for the producer, when they change, or with 0 and 0
+//                   when the producer is no longer limited; or NULL for none
+// @returns enum ErrorCode success (Was rate control enabled successfully)
+// The targets are measured about once per second, from the rate at which the server received the
+// stream while it was congested, and are raised when it is not, until the producer no longer uses them.
+// Must be called before connecting.
+enum ErrorCode SetStreamRateControl(struct ClientHandle_t *handle,
+                                    const char *stream,
+                                    uint64_t max_unsent,
+                                    void (*callback)(double bitrate, double frame_rate));
+
+// Loads a machine config file, which declares the name and any of the types, functions, sensors,
+// axes, and streams that could otherwise be registered with the functions above, with their metadata.
+// @param handle     The client handle
+// @param path       The path of the config file, which must end in .toml (TOML) or .json (JSON)
+// @param lookup     Function that returns the callback with the given name (as named in the config file),
+//                   cast to GenericCallback_t, or NULL if there is none. user_data is passed to it.
+// @param user_data  Passed to lookup
+// @returns enum ErrorCode success (Was the config loaded successfully)
+// The callback returned by lookup must have the type that the corresponding Register* function takes.
+// If the file is invalid (including referring to unknown types or callbacks), nothing is registered,
+// InvalidConfig is returned, and the error (with the line number, where possible) is printed to stderr.
+// If anything in the file was already registered, nothing is registered, and DuplicateName is returned.
+//
+// The file has the following keys, all of which are optional (shown in TOML; JSON is the same shape):
+//   name = "machine name"                   (see SetName)
+//   lenient_parameters = true               (see SetLenientParameters)
+//   [types.pose]                            (see RegisterStruct; must come before anything using it)
+//   kind = "struct"
+//   fields = [{ name = "x", type = "double" }, { name = "y", type = "double" }]
+//   [types.speed_level]                     (see RegisterEnum)
+//   kind = "enum"
+//   values = ["low", "medium", "high"]
+//   [functions.multiply]                    (see RegisterFunction)
+//   callback = "multiply"                   (the name passed to lookup)
+//   status = false                          (true for a callback as in RegisterFunctionEx)
+//   parameters = [{ name = "x", type = "int" }, { name = "y", type = "int = 1" }]
+//   returns = [{ name = "product", type = "int" }]
+//   [sensors.count]                         (see RegisterSensor)
+//   callback = "count"
+//   status = false                          (true for a callback as in RegisterSensorEx)
+//   type = "double?"                        (for a callback as in RegisterSensorTyped)
+//   min = 0.0                               (only without type)
+//   max = 100.0
+//   [axes.example]                          (see RegisterAxis)
+//   callback = "example"
+//   status = false                          (true for a callback as in RegisterAxisEx)
+//   min = -1.0
+//   max = 1.0
+//   group = "example_group"
+//   direction = "x"
+//   [streams.webcam]                        (see RegisterStream)
+//   format = "mjpeg"                        ("mjpeg", "h264", "pcm", or "ndjson")
+//   fd = 0                                  (or path = "/path/to/fifo", which is opened for reading,
+//                                            or neither, to write the data with StreamWrite)
+//   buffer_method = "Frames"                (see RegisterStreamEx; "Frames", "Bytes", or "NoDiscard")
+//   buffer_size = 30
+//   control_callback = "webcam_control"     (see SetStreamControlCallback)
+//   rate_control = true                     (see SetStreamRateControl)
+//   max_unsent = 65536                      (with rate_control, optional)
+//   rate_callback = "webcam_rate"           (with rate_control, optional)
+//   transport = "udp"                       (see SetStreamTransport; "tcp" or "udp")
+//   [streams.microphone]                    (see RegisterPcmStream)
+//   format = "pcm"
+//   sample_rate = 48000
+//   channels = 2
+//   sample_format = "s16le"                 ("u8", "s16le", "s16be", "s32le", "s32be", "f32le", or "f32be")
+// Functions, parameters and returns, sensors, axes, and streams may also have description, unit,
+// and tags keys (see SetFunctionMetadata), e.g. { name = "x", type = "int", unit = "mm" }.
+enum ErrorCode LoadMachineConfig(struct ClientHandle_t *handle,
+                                 const char *path,
+                                 GenericCallback_t (*lookup)(const char *name, void *user_data),
+                                 void *user_data);
+
+// Exports the machine description that ConnectToServer would send to the server, without connecting.
+// @param handle     The client handle (which must not be connected yet)
+// @param json_out   Where to store the machine description message, as a JSON string,
+//                   which must be freed with FreeString
+// @returns enum ErrorCode success (Was the machine description exported successfully)
+// The message is the same as the one ConnectToServer would send at that point, except for its message_id.
+// Like ConnectToServer, requires a name to be set (see SetName).
+enum ErrorCode ExportMachineDescription(struct ClientHandle_t *handle,
+                                        char **json_out);
+
+// Frees a string returned by the library (e.g. by ExportMachineDescription). Does nothing if string is NULL.
+void FreeString(char *string);
+
+// Connects to a server
+// @param server     String that is the domain name or IP address (v4 or v6) of the server.
+// @param port       uint16_t that is the port to connect to on the server.
+// @returns enum ErrorCode success (Did the client connect successfully)
+enum ErrorCode ConnectToServer(struct ClientHandle_t *handle,
+                               const char *server,
+                               uint16_t port,
+                               uint16_t stream_port);
+
+// Writes data to a stream that was registered without an fd (see RegisterStreamEx).
+// @param handle     The client handle (which must be connected)
+// @param stream     The name of the stream
+// @param data       The data, which is split into frames according to the stream's format,
+//                   the same way as data read from an fd (for mjpeg, one JPEG image per frame)
+// @param len        The length of data in bytes
+// @returns enum ErrorCode success (Was the data queued successfully)
+// The data is copied, and queued to be sent to the server according to the stream's buffer method.
+// With BufferNoDiscard, waits until the data previously written has been sent, and returns
+// StreamIsPaused (without queueing the data) while the server has paused the stream.
+// Returns ServerDisconnected if the stream can no longer be sent to the server (see GetStreamStatus).
+// StreamWrite, StreamWriteFrame, and StreamWriteFrameAt may be called from any thread, including
+// concurrently with each other, but not concurrently with other functions taking the same handle.
+// To write from a thread while the handle is in use (e.g. by LibraryUpdate), use a stream writer
+// (see GetStreamWriter) instead.
+enum ErrorCode StreamWrite(const struct ClientHandle_t *handle,
+                           const char *stream,
+                           const void *data,
+                           size_t len);
+
+// Writes one frame to a stream that was registered without an fd (see RegisterStreamEx).
+// Same as StreamWrite, except that data is sent as one frame, regardless of the stream's format,
+// so that the buffer method keeps or discards it as a whole.
+enum ErrorCode StreamWriteFrame(const struct ClientHandle_t *handle,
+                                const char *stream,
+                                const void *data,
+                                size_t len);
+
+// Same as StreamWriteFrame, with the time the frame was captured (by default, when it is written).
+// @param timestamp  The capture time, on the clock returned by MonotonicMicroseconds (CLOCK_MONOTONIC,
+//                   which e.g. V4L2 buffer timestamps also use), in microseconds
+enum ErrorCode StreamWriteFrameAt(const struct ClientHandle_t *handle,
+                                  const char *stream,
+                                  const void *data,
+                                  size_t len,
+                                  uint64_t timestamp);
+
+// Returns (in *writer_ptr) a writer for a stream that was registered without an fd (see RegisterStreamEx),
+// through which the application writes the stream's data (see StreamWriterWrite).
+// @param handle     The client handle (which must be connected)
+// @param stream     The name of the stream
+// @returns enum ErrorCode success (Was the writer returned successfully)
+// The writer must be freed with FreeStreamWriter. It does not depend on the handle, so it may be
+// used from any thread (e.g. a camera's capture callback), concurrently with any function taking the
+// handle, including LibraryUpdate. After ShutdownLibrary, writing to it returns ServerDisconnected.
+enum ErrorCode GetStreamWriter(const struct ClientHandle_t *handle,
+                               const char *stream,
+                               struct StreamWriter **writer_ptr);
+
+// Frees a writer returned by GetStreamWriter. Does nothing if writer is NULL.
+void FreeStreamWriter(struct StreamWriter *writer);
+
+// Same as StreamWrite, through a stream writer (see GetStreamWriter).
+// StreamWriterWrite, StreamWriterWriteFrame, and StreamWriterWriteFrameAt may be called from any
+// thread, including concurrently with each other and with functions taking the client handle.
+enum ErrorCode StreamWriterWrite(const struct StreamWriter *writer, const void *data, size_t len);
+
+// Same as StreamWriteFrame, through a stream writer (see GetStreamWriter).
+enum ErrorCode StreamWriterWriteFrame(const struct StreamWriter *writer,
+                                      const void *data,
+                                      size_t len);
+
+// Same as StreamWriteFrameAt, through a stream writer (see GetStreamWriter).
+enum ErrorCode StreamWriterWriteFrameAt(const struct StreamWriter *writer,
+                                        const void *data,
+                                        size_t len,
+                                        uint64_t timestamp);
+
+// Returns (in *status_ptr) whether a stream is still being sent to the server (see enum StreamStatus),
+// and (in *error_ptr, if error_ptr is not NULL) why it failed, or NULL if it has not failed.
+// @param handle     The client handle (which must be connected)
+// @param stream     The name of the stream
+// @returns enum ErrorCode success (Was the status returned successfully)
+// The error string must be freed with FreeString.
+// A stream that fails (e.g. because its fd could not be read, or the server closed the stream's
+// connection) stops being sent, but the client stays connected.
+enum ErrorCode GetStreamStatus(const struct ClientHandle_t *handle,
+                               const char *stream,
+                               enum StreamStatus *status_ptr,
+                               char **error_ptr);
+
+// Returns (in *stats_ptr) statistics of a stream being sent to the server (see struct StreamStats),
+// e.g. to find out why a stream is stuttering.
+// @param handle     The client handle (which must be connected)
+// @param stream     The name of the stream
+// @returns enum ErrorCode success (Were the statistics returned successfully)
+enum ErrorCode GetStreamStats(const struct ClientHandle_t *handle,
+                              const char *stream,
+                              struct StreamStats *stats_ptr);
+
+// Returns the client's monotonic clock (CLOCK_MONOTONIC), in microseconds.
+// Stream frames are sent with their capture time on this clock, and the server can estimate its
+// offset from the server's clock (see GetClockOffset).
+uint64_t MonotonicMicroseconds(void);
+
+// Returns (in *offset_ptr) the server's latest estimate of the client's clock (see MonotonicMicroseconds)
+// minus the server's clock, in microseconds, and (in *uncertainty_ptr) its maximum error.
+// If the server has not sent an estimate, returns 0 in *offset_ptr and UINT64_MAX in *uncertainty_ptr.
+// Estimates are received by LibraryUpdate.
+enum ErrorCode GetClockOffset(const struct ClientHandle_t *handle,
+                              int64_t *offset_ptr,
+                              uint64_t *uncertainty_ptr);
+
+// Returns (in *result_ptr) the number of milliseconds since the last message from the server was handled.
+// If no messages have yet been received, returns -1 in *result_ptr.
+// May also return -1 spuriously if the platforms monotonic clock is not actually monotonic.
+enum ErrorCode MillisecondsSinceLastMessage(struct ClientHandle_t *handle,
+                                            long *result_ptr);
 
 #ifdef __cplusplus
-} // extern "C"
-#endif // def __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
 
-#endif // ndef CLIENT_H
+#endif  /* CLIENT_H */
@@ -1,15 +1,21 @@
 use indexmap::map::IndexMap;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use libc::c_int;
 use serde_json::value::RawValue;
+use std::sync::Arc;
 use crate::marshall::{
     InputMarshall,
     OutputMarshall,
     InputMarshaller,
     OutputMarshaller,
-    INPUT_MARSHALLERS,
-    OUTPUT_MARSHALLERS,
+    input_marshaller,
+    output_marshaller,
 };
 use crate::RawFd;
+use crate::stream::StreamState;
+use common::util::*;
+use common::message;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub(crate) enum PrimType {
@@ -18,21 +24,50 @@ pub(crate) enum PrimType {
     Short,
     Int,
     Long,
+    UByte,
+    UShort,
+    UInt,
+    ULong,
     Float,
     Double,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub(crate) enum Type {
     Prim(PrimType),
     PrimArray(PrimType),
     String,
     StringArray,
+    Bytes,
+    /// A struct type registered with RegisterStruct.
+    Struct(Arc<StructType>),
+    /// An enum type registered with RegisterEnum.
+    Enum(Arc<EnumType>),
+    /// An array with fixed dimensions, e.g. double[3] or float[4][4] (outermost dimension first).
+    FixedArray(PrimType, Vec<usize>),
+    /// A map from strings to values of a primitive type or string, e.g. map<string, double>.
+    Map(Box<Type>),
+    /// A value that may be null, e.g. double?.
+    Nullable(Box<Type>),
+}
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub(crate) struct StructType {
+    pub(crate) name: String,
+    pub(crate) fields: Vec<(String, Type)>,
 }
 
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub(crate) struct EnumType {
+    pub(crate) name: String,
+    pub(crate) values: Vec<String>,
+}
+
+/// Aliases are accepted by from_str, but never produced by builtin_name.
 macro_rules! to_and_from_str {
     ($ty:ident :
         $( $s:literal => $a:ident $( ( $b:ident ) )? ),* $(,)?
+        $( ; aliases: $( $alias:literal => $alias_a:ident $( ( $alias_b:ident ) )? ),* $(,)? )?
     ) => {
         impl $ty {
             pub(crate) fn from_str(s: &str) -> Option<Self> {
@@ -40,15 +75,17 @@ macro_rules! to_and_from_str {
                 use PrimType::*;
                 Some(match s {
                     $( $s => $a $( ( $b ) )? ),* ,
+                    $( $( $alias => $alias_a $( ( $alias_b ) )? ),* , )?
                     _ => return None,
                 })
             }
-            pub(crate) fn to_str(&self) -> &'static str {
+            pub(crate) fn builtin_name(&self) -> Option<&'static str> {
                 use $ty::*;
                 use PrimType::*;
-                match self {
-                    $( $a $( ( $b ) )? => $s ),*
-                }
+                Some(match self {
+                    $( $a $( ( $b ) )? => $s ),* ,
+                    _ => return None,
+                })
             }
         }
     }
@@ -65,6 +102,10 @@ to_and_from_str!(Type:
     "short" => Prim(Short),
     "int" => Prim(Int),
     "long" => Prim(Long),
+    "ubyte" => Prim(UByte),
+    "ushort" => Prim(UShort),
+    "uint" => Prim(UInt),
+    "ulong" => Prim(ULong),
     "float" => Prim(Float),
     "double" => Prim(Double),
     "bool[]" => PrimArray(Bool),
@@ -72,23 +113,219 @@ to_and_from_str!(Type:
     "short[]" => PrimArray(Short),
     "int[]" => PrimArray(Int),
     "long[]" => PrimArray(Long),
+    "ubyte[]" => PrimArray(UByte),
+    "ushort[]" => PrimArray(UShort),
+    "uint[]" => PrimArray(UInt),
+    "ulong[]" => PrimArray(ULong),
     "float[]" => PrimArray(Float),
     "double[]" => PrimArray(Double),
     "string" => String,
     "string[]" => StringArray,
+    "bytes" => Bytes;
+aliases:
+    "i8" => Prim(Byte),
+    "i16" => Prim(Short),
+    "i32" => Prim(Int),
+    "i64" => Prim(Long),
+    "u8" => Prim(UByte),
+    "u16" => Prim(UShort),
+    "u32" => Prim(UInt),
+    "u64" => Prim(ULong),
+    "i8[]" => PrimArray(Byte),
+    "i16[]" => PrimArray(Short),
+    "i32[]" => PrimArray(Int),
+    "i64[]" => PrimArray(Long),
+    "u8[]" => PrimArray(UByte),
+    "u16[]" => PrimArray(UShort),
+    "u32[]" => PrimArray(UInt),
+    "u64[]" => PrimArray(ULong),
 );
 //            _ => return None,
 //        })
 //    }
 //}
 
-pub(crate) struct Function {
-    pub(crate) parameters: IndexMap<String, (Type, InputMarshaller)>,
-    pub(crate) returns: IndexMap<String, (Type, OutputMarshaller)>,
-    pub(crate) fn_ptr: unsafe extern "C" fn(
+impl Type {
+    /// The number of elements (in all dimensions) a fixed-size array may have, since a buffer for
+    /// all of them is allocated for every call.
+    pub(crate) const MAX_FIXED_ARRAY_ELEMENTS: usize = 1 << 20;
+
+    /// Parses a type name, which may be a builtin type or a type registered with RegisterStruct or RegisterEnum.
+    pub(crate) fn parse(s: &str, named: &IndexMap<String, Type>) -> Option<Self> {
+        Self::from_str(s)
+            .or_else(|| named.get(s).cloned())
+            .or_else(|| Self::parse_fixed_array(s))
+            .or_else(|| Self::parse_map(s))
+            .or_else(|| Self::parse_nullable(s, named))
+    }
+    /// Parses a nullable type, e.g. double? or pose?.
+    fn parse_nullable(s: &str, named: &IndexMap<String, Type>) -> Option<Self> {
+        match Self::parse(s.strip_suffix('?')?.trim_end(), named)? {
+            Type::Nullable(_) => None,
+            r#type => Some(Type::Nullable(Box::new(r#type))),
+        }
+    }
+    /// Parses a map type, e.g. map<string, double>.
+    fn parse_map(s: &str) -> Option<Self> {
+        let s = s.strip_prefix("map")?.trim_start().strip_prefix('<')?.strip_suffix('>')?;
+        let (key_type, value_type) = s.split_once(',')?;
+        if key_type.trim() != "string" {
+            return None;
+        }
+        match Self::from_str(value_type.trim())? {
+            value_type @ (Type::Prim(_) | Type::String) => Some(Type::Map(Box::new(value_type))),
+            _ => None,
+        }
+    }
+    /// Parses a fixed-size array type, e.g. double[3] or float[4][4].
+    fn parse_fixed_array(s: &str) -> Option<Self> {
+        let mut dimensions = vec![];
+        let mut rest = s;
+        while let Some(r) = rest.strip_suffix(']') {
+            let (r, dimension) = r.rsplit_once('[')?;
+            let dimension = dimension.trim().parse::<usize>().ok().filter(|&d| d > 0)?;
+            dimensions.push(dimension);
+            rest = r;
+        }
+        if dimensions.is_empty() {
+            return None;
+        }
+        dimensions.reverse();
+        dimensions.iter().try_fold(1usize, |acc, &d| acc.checked_mul(d).filter(|&n| n <= Self::MAX_FIXED_ARRAY_ELEMENTS))?;
+        match Self::from_str(rest.trim())? {
+            Type::Prim(prim) => Some(Type::FixedArray(prim, dimensions)),
+            _ => None,
+        }
+    }
+    /// The definition of a named type, to be sent to the server.
+    pub(crate) fn definition(&self) -> Option<message::TypeDefinition> {
+        match self {
+            Type::Struct(struct_type) => Some(message::TypeDefinition::Struct {
+                fields: struct_type.fields.iter().map(|(name, r#type)| message::Field {
+                    name: name.clone(),
+                    field_type: r#type.to_string(),
+                }).collect(),
+            }),
+            Type::Enum(enum_type) => Some(message::TypeDefinition::Enum {
+                values: enum_type.values.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Type::Struct(struct_type) => f.write_str(&struct_type.name),
+            Type::Enum(enum_type) => f.write_str(&enum_type.name),
+            Type::FixedArray(prim, dimensions) => {
+                f.write_str(Type::Prim(*prim).builtin_name().unwrap_or("unknown"))?;
+                dimensions.iter().try_for_each(|d| write!(f, "[{}]", d))
+            },
+            Type::Map(value_type) => write!(f, "map<string, {}>", value_type),
+            Type::Nullable(r#type) => write!(f, "{}?", r#type),
+            r#type => f.write_str(r#type.builtin_name().unwrap_or("unknown")),
+        }
+    }
+}
+
+/// A function callback, as registered by RegisterFunction or RegisterFunctionEx.
+#[derive(Clone, Copy)]
+pub(crate) enum FunctionCallback {
+    Void(unsafe extern "C" fn(
+        parameters: *const *const libc::c_void,
+        returns: *const *mut libc::c_void,
+    )),
+    /// Returns 0 on success, nonzero on failure.
+    Status(unsafe extern "C" fn(
         parameters: *const *const libc::c_void,
         returns: *const *mut libc::c_void,
-    ),
+    ) -> c_int),
+}
+
+/// An axis callback, as registered by RegisterAxis or RegisterAxisEx.
+#[derive(Clone, Copy)]
+pub(crate) enum AxisCallback {
+    Void(unsafe extern "C" fn(input: f64)),
+    /// Returns 0 on success, nonzero on failure.
+    Status(unsafe extern "C" fn(input: f64) -> c_int),
+}
+
+/// A sensor callback, as registered by RegisterSensor, RegisterSensorEx, or RegisterSensorTyped.
+#[derive(Clone, Copy)]
+pub(crate) enum SensorCallback {
+    Void(unsafe extern "C" fn(output: *mut f64)),
+    /// Returns 0 on success, nonzero on failure.
+    Status(unsafe extern "C" fn(output: *mut f64) -> c_int),
+    /// Output points to the same thing as a function return of the sensor's type would.
+    /// Returns 0 on success, nonzero on failure.
+    Typed(unsafe extern "C" fn(output: *mut libc::c_void) -> c_int),
+}
+
+thread_local! {
+    /// Error message set by a failing callback through SetCallbackError.
+    static CALLBACK_ERROR: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+pub(crate) fn set_callback_error(message: String) {
+    CALLBACK_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+/// Runs a callback, and turns a nonzero status into an error,
+/// including the message given to SetCallbackError, if any.
+fn check_status(callback: impl FnOnce() -> c_int) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    CALLBACK_ERROR.with(|e| *e.borrow_mut() = None);
+    let status = callback();
+    let message = CALLBACK_ERROR.with(|e| e.borrow_mut().take());
+    match (status, message) {
+        (0, _) => Ok(()),
+        (status, Some(message)) => Err(format!("callback failed (status {}): {}", status, message).into()),
+        (status, None) => Err(format!("callback failed (status {})", status).into()),
+    }
+}
+
+thread_local! {
+    /// Whether each parameter of the currently running function callback was supplied by the server.
+    static PARAMETERS_SUPPLIED: RefCell<Vec<bool>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Whether the parameter at `index` of the currently running function callback was supplied
+/// by the server. False if not called from a function callback.
+pub(crate) fn parameter_supplied(index: c_int) -> bool {
+    let index: usize = unwrap_or_return!(index.try_into(), false);
+    PARAMETERS_SUPPLIED.with(|supplied| supplied.borrow().get(index).copied().unwrap_or(false))
+}
+
+/// What happens when the server omits a function parameter.
+#[derive(Debug, Clone)]
+pub(crate) enum Presence {
+    /// The parameter must be supplied.
+    Required,
+    /// The parameter may be omitted, in which case the callback receives a null pointer for it.
+    Optional,
+    /// The parameter may be omitted, in which case this (JSON) value is used instead.
+    Default(Box<RawValue>),
+}
+
+pub(crate) struct Parameter {
+    pub(crate) r#type: Type,
+    pub(crate) marshaller: InputMarshaller,
+    pub(crate) presence: Presence,
+    pub(crate) metadata: message::Metadata,
+}
+
+pub(crate) struct Return {
+    pub(crate) r#type: Type,
+    pub(crate) marshaller: OutputMarshaller,
+    pub(crate) metadata: message::Metadata,
+}
+
+pub(crate) struct Function {
+    pub(crate) parameters: IndexMap<String, Parameter>,
+    pub(crate) returns: IndexMap<String, Return>,
+    pub(crate) callback: FunctionCallback,
+    pub(crate) metadata: message::Metadata,
 }
 
 #[allow(unused)] // TODO: once axes are implemented, remove this allow
@@ -98,79 +335,132 @@ pub(crate) struct Axis {
     pub(crate) max: f64,
     pub(crate) group: String,
     pub(crate) direction: String,
-    pub(crate) fn_ptr: unsafe extern "C" fn(
-        input: f64,
-    ),
+    pub(crate) callback: AxisCallback,
+    pub(crate) metadata: message::Metadata,
 }
 
 pub(crate) struct Sensor {
     pub(crate) output_type: Type,
-    pub(crate) min: f64,
-    pub(crate) max: f64,
-    pub(crate) fn_ptr: unsafe extern "C" fn(
-        output: *mut f64,
-    ),
+    pub(crate) output_marshaller: OutputMarshaller,
+    /// None for typed sensors, which have no range.
+    pub(crate) min: Option<f64>,
+    pub(crate) max: Option<f64>,
+    pub(crate) callback: SensorCallback,
+    pub(crate) metadata: message::Metadata,
 }
 
 pub(crate) struct Stream {
-    pub(crate) format: String,
-    pub(crate) fd: RawFd,
+    pub(crate) format: message::StreamFormat,
+    /// The sample layout, for (and only for) pcm streams.
+    pub(crate) pcm: Option<message::PcmFormat>,
+    /// The fd to read the stream's data from, or None if the application writes it with
+    /// StreamWrite or StreamWriteFrame.
+    pub(crate) fd: Option<RawFd>,
+    pub(crate) buffer_method: message::BufferMethod,
+    /// The number of frames or bytes to buffer (see message::Stream), or 0 for NoDiscard.
+    pub(crate) buffer_size: usize,
+    pub(crate) transport: message::Transport,
+    pub(crate) metadata: message::Metadata,
+    /// Called when the server starts, pauses, or stops the stream (see SetStreamControlCallback).
+    pub(crate) control_callback: Option<unsafe extern "C" fn(state: StreamState)>,
+    /// With rate control, the number of bytes that may be waiting to be sent in the stream's socket
+    /// (see SetStreamRateControl).
+    pub(crate) max_unsent: Option<usize>,
+    /// Called with a target bitrate and frame rate for the stream's producer (see SetStreamRateControl).
+    pub(crate) rate_callback: Option<unsafe extern "C" fn(bitrate: f64, frame_rate: f64)>,
 }
 
 impl Function {
     pub(crate) fn new(
-        parameters: IndexMap<String, Type>,
+        parameters: IndexMap<String, (Type, Presence)>,
         returns: IndexMap<String, Type>,
-        fn_ptr: unsafe extern "C" fn(
-            parameters: *const *const libc::c_void,
-            returns: *const *mut libc::c_void,
-        ),
+        callback: FunctionCallback,
     ) -> Result<Self, Box<dyn std::error::Error + Send + Sync + 'static>> {
         let parameters = parameters
             .into_iter()
-            .map(|(name, r#type)| -> Result<(String, (Type, InputMarshaller)), Box<dyn std::error::Error + Send + Sync + 'static>> {
-                Ok((
-                    name,
-                    (
-                        r#type,
-                        *INPUT_MARSHALLERS.get(
-                            &r#type
-                        ).ok_or(format!("unsupported input type: {:?}", r#type))?
-                    ),
-                ))
+            .map(|(name, (r#type, presence))| -> Result<(String, Parameter), Box<dyn std::error::Error + Send + Sync + 'static>> {
+                let marshaller = input_marshaller(&r#type).ok_or(format!("unsupported input type: {}", r#type))?;
+                if let Presence::Default(default) = &presence {
+                    marshaller(default).map_err(|e| format!("invalid default for parameter {:?}: {}", name, e))?;
+                }
+                Ok((name, Parameter { r#type, marshaller, presence, metadata: Default::default() }))
             }).collect::<Result<_,_>>()?;
         let returns = returns
             .into_iter()
-            .map(|(name, r#type)| -> Result<(String, (Type, OutputMarshaller)), Box<dyn std::error::Error + Send + Sync + 'static>> {
-                Ok((
-                    name,
-                    (
-                        r#type,
-                        *OUTPUT_MARSHALLERS.get(
-                            &r#type
-                        ).ok_or(format!("unsupported output type: {:?}", r#type))?,
-                    ),
-                ))
+            .map(|(name, r#type)| -> Result<(String, Return), Box<dyn std::error::Error + Send + Sync + 'static>> {
+                let marshaller = output_marshaller(&r#type).ok_or(format!("unsupported output type: {}", r#type))?;
+                Ok((name, Return { r#type, marshaller, metadata: Default::default() }))
             }).collect::<Result<_,_>>()?;
-        Ok(Self { parameters, returns, fn_ptr })
+        Ok(Self { parameters, returns, callback, metadata: Default::default() })
     }
-    pub(crate) fn call(&self, parameters: &HashMap<String, Box<RawValue>>) -> Result<HashMap<String, Box<RawValue>>, Box<dyn std::error::Error + Send + Sync + 'static>> {
-        eprintln!("TODO: check for extraneous parameters");
+    /// Marshalls the parameters, calls the callback, and marshalls the returns.
+    /// If `lenient` is true, parameters that the function did not declare are ignored
+    /// instead of being reported as errors.
+    pub(crate) fn call(&self, parameters: &HashMap<String, Box<RawValue>>, lenient: bool) -> Result<HashMap<String, Box<RawValue>>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let mut errors = vec![];
+
+        let mut unknown: Vec<&String> = parameters.keys()
+            .filter(|name| !self.parameters.contains_key(*name))
+            .collect();
+        unknown.sort();
+        for name in unknown {
+            if lenient {
+                eprintln!("Ignoring unknown parameter: {:?}", name);
+            } else {
+                errors.push(ParameterError::Unknown(name.clone()));
+            }
+        }
+
+        let mut parameterbuffer: Vec<Option<Box<dyn InputMarshall>>> = Vec::with_capacity(self.parameters.len());
+        let mut supplied: Vec<bool> = Vec::with_capacity(self.parameters.len());
+        for (name, parameter) in &self.parameters {
+            let value = parameters.get(name);
+            supplied.push(value.is_some());
+            let value = match (value, &parameter.presence) {
+                (Some(value), _) => value,
+                (None, Presence::Default(default)) => default,
+                (None, Presence::Optional) => {
+                    parameterbuffer.push(None);
+                    continue;
+                },
+                (None, Presence::Required) => {
+                    errors.push(ParameterError::Missing(name.clone()));
+                    continue;
+                },
+            };
+            match (parameter.marshaller)(value) {
+                Ok(im) => parameterbuffer.push(Some(im)),
+                Err(e) => errors.push(ParameterError::InvalidValue {
+                    name: name.clone(),
+                    r#type: parameter.r#type.clone(),
+                    reason: e.to_string(),
+                }),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(Box::new(ParameterErrors(errors)));
+        }
 
-        let parameterbuffer: Vec<Box<dyn InputMarshall>> =
-            self.parameters.iter().map(|(name, (_type, marshaller))| {
-                let value = parameters.get(name).ok_or_else(|| format!("Missing parameter: {}", name))?;
-                marshaller(value)
-            }).collect::<Result<_,_>>()?;
         let mut returnbuffer: Vec<Box<dyn OutputMarshall>> =
-            self.returns.iter().map(|(_, (_, marshaller))| marshaller()).collect();
+            self.returns.values().map(|r#return| (r#return.marshaller)()).collect();
 
-        let parameters: Vec<*const libc::c_void> = parameterbuffer.iter().map(|im| im.data()).collect();
+        let parameters: Vec<*const libc::c_void> = parameterbuffer.iter()
+            .map(|im| im.as_ref().map_or(std::ptr::null(), |im| im.data()))
+            .collect();
         let returns: Vec<*mut libc::c_void> = returnbuffer.iter_mut().map(|om| om.data()).collect();
 
-        unsafe {
-            (self.fn_ptr)(parameters.as_ptr(), returns.as_ptr());
-        }
+        PARAMETERS_SUPPLIED.with(|s| *s.borrow_mut() = supplied);
+        let result = match self.callback {
+            FunctionCallback::Void(fn_ptr) => unsafe {
+                fn_ptr(parameters.as_ptr(), returns.as_ptr());
+                Ok(())
+            },
+            FunctionCallback::Status(fn_ptr) => check_status(|| unsafe {
+                fn_ptr(parameters.as_ptr(), returns.as_ptr())
+            }),
+        };
+        PARAMETERS_SUPPLIED.with(|s| s.borrow_mut().clear());
+        result?;
 
         drop(parameters);
         drop(returns);
@@ -188,23 +478,67 @@ impl Function {
     }
 }
 
+/// A problem with one field of a FunctionCall's parameters.
+#[derive(Debug)]
+pub(crate) enum ParameterError {
+    /// The server sent a parameter that the function does not have.
+    Unknown(String),
+    /// The server did not send a parameter that the function requires.
+    Missing(String),
+    /// The parameter could not be marshalled as the declared type.
+    InvalidValue { name: String, r#type: Type, reason: String },
+}
+
+impl std::fmt::Display for ParameterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParameterError::Unknown(name) => write!(f, "unknown parameter {:?}", name),
+            ParameterError::Missing(name) => write!(f, "missing parameter {:?}", name),
+            ParameterError::InvalidValue { name, r#type, reason } =>
+                write!(f, "parameter {:?} is not a valid {}: {}", name, r#type, reason),
+        }
+    }
+}
+
+/// All of the problems found with a FunctionCall's parameters.
+#[derive(Debug)]
+pub(crate) struct ParameterErrors(pub(crate) Vec<ParameterError>);
+
+impl std::fmt::Display for ParameterErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid parameters: ")?;
+        for (i, error) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ParameterErrors {}
+
 impl Axis {
     pub(crate) fn new(
         min: f64,
         max: f64,
         group: String,
         direction: String,
-        fn_ptr: unsafe extern "C" fn(
-            input: f64,
-        ),
+        callback: AxisCallback,
     ) -> Result<Self, Box<dyn std::error::Error + Send + Sync + 'static>> {
         let input_type = Type::Prim(PrimType::Double);
-        Ok(Self { input_type, min, max, group, direction, fn_ptr })
+        Ok(Self { input_type, min, max, group, direction, callback, metadata: Default::default() })
     }
     pub(crate) fn call(&self, input: f64) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
         // let input = serde_json::from_str::<f64>(input.get())?;
-        unsafe {
-            (self.fn_ptr)(input);
+        match self.callback {
+            AxisCallback::Void(fn_ptr) => unsafe {
+                fn_ptr(input);
+            },
+            AxisCallback::Status(fn_ptr) => check_status(|| unsafe {
+                fn_ptr(input)
+            })?,
         }
         Ok(())
     }
@@ -212,33 +546,66 @@ impl Axis {
 
 impl Sensor {
     pub(crate) fn new(
-        min: f64,
-        max: f64,
-        fn_ptr: unsafe extern "C" fn(
-            input: *mut f64,
-        ),
+        output_type: Type,
+        min: Option<f64>,
+        max: Option<f64>,
+        callback: SensorCallback,
     ) -> Result<Self, Box<dyn std::error::Error + Send + Sync + 'static>> {
-        let output_type = Type::Prim(PrimType::Double);
-        // let output_marshaller = *OUTPUT_MARSHALLERS.get(&output_type).ok_or(format!("unsupported output type: {:?}", output_type))?;
-        Ok(Self { output_type, min, max, fn_ptr })
+        if !matches!(callback, SensorCallback::Typed(_)) && output_type != Type::Prim(PrimType::Double) {
+            Err(format!("unsupported output type for untyped sensor: {}", output_type))?;
+        }
+        let output_marshaller = output_marshaller(&output_type).ok_or(format!("unsupported output type: {}", output_type))?;
+        Ok(Self { output_type, output_marshaller, min, max, callback, metadata: Default::default() })
     }
     pub(crate) fn call(&self) -> Result<Box<RawValue>, Box<dyn std::error::Error + Send + Sync + 'static>> {
-        let mut output: f64 = 0.0;
-        unsafe {
-            (self.fn_ptr)(&mut output);
+        let mut output = (self.output_marshaller)();
+        match self.callback {
+            SensorCallback::Void(fn_ptr) => unsafe {
+                fn_ptr(output.data() as *mut f64);
+            },
+            SensorCallback::Status(fn_ptr) => check_status(|| unsafe {
+                fn_ptr(output.data() as *mut f64)
+            })?,
+            SensorCallback::Typed(fn_ptr) => check_status(|| unsafe {
+                fn_ptr(output.data())
+            })?,
         }
-        Ok(output.to_json()?)
+        output.to_json()
     }
 }
 
 impl Stream {
     pub(crate) fn new(
         format: &str,
-        fd: RawFd,
+        pcm: Option<message::PcmFormat>,
+        fd: Option<RawFd>,
+        buffer_method: message::BufferMethod,
+        buffer_size: u64,
     ) -> Result<Self, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let format: message::StreamFormat = format.parse()?;
+        match (format, pcm) {
+            (message::StreamFormat::Pcm, None) => Err("pcm stream must have a sample rate, channels, and sample format")?,
+            (message::StreamFormat::Pcm, Some(pcm)) if pcm.sample_rate == 0 || pcm.channels == 0 =>
+                Err("sample rate and channels must be positive")?,
+            (message::StreamFormat::Pcm, Some(_)) | (_, None) => {},
+            (_, Some(_)) => Err(format!("{} stream must not have a sample format", format))?,
+        }
+        let buffer_size = match buffer_method {
+            message::BufferMethod::NoDiscard => 0,
+            _ if buffer_size == 0 => Err(format!("buffer size must be positive for buffer method {:?}", buffer_method))?,
+            _ => buffer_size.try_into().or(Err("buffer size too large"))?,
+        };
         Ok(Self {
-            format: format.to_owned(),
+            format,
+            pcm,
             fd,
+            buffer_method,
+            buffer_size,
+            transport: Default::default(),
+            metadata: Default::default(),
+            control_callback: None,
+            max_unsent: None,
+            rate_callback: None,
         })
     }
 }
\ No newline at end of file
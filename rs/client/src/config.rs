@@ -0,0 +1,610 @@
+//! Machine config files (see LoadMachineConfig), which declare the same things as the
+//! Register* functions, in TOML or JSON.
+//!
+//! Type names and callback names are resolved while deserializing (rather than afterwards),
+//! so that errors from serde_json and toml point at the offending line.
+//! This requires the context in CONTEXT, so the Deserialize impls here
+//! only work from within `load`.
+
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::fmt;
+use std::path::Path;
+use indexmap::map::IndexMap;
+use libc::{c_char, c_void};
+use serde::Deserialize;
+use serde::de::{Deserializer, Error as _, MapAccess, Visitor};
+use crate::callbacks::*;
+use crate::marshall::input_marshaller;
+use crate::{parse_type_descriptor, RawFd};
+use crate::stream::{self, StreamState};
+use common::message;
+
+/// A callback, as returned by the application's lookup function,
+/// before being cast back to its actual type.
+pub(crate) type GenericCallback = unsafe extern "C" fn();
+
+/// A callback of any type, as returned by the lookup function passed to LoadMachineConfig.
+// The same as Option<GenericCallback>, but spelled out so that cbindgen can see that it is nullable.
+pub(crate) type NullableGenericCallback = Option<unsafe extern "C" fn()>;
+
+/// The application's lookup function, which returns the callback with the given name, or NULL.
+pub(crate) type CallbackLookup = unsafe extern "C" fn(name: *const c_char, user_data: *mut c_void) -> NullableGenericCallback;
+
+struct Context {
+    /// Named types registered before loading, and those declared so far in the file.
+    types: IndexMap<String, Type>,
+    lookup: CallbackLookup,
+    user_data: *mut c_void,
+}
+
+thread_local! {
+    /// Context for the config currently being loaded by `load`.
+    static CONTEXT: RefCell<Option<Context>> = const { RefCell::new(None) };
+}
+
+/// Everything declared in a machine config file, validated and ready to add to a handle.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct MachineConfig {
+    pub(crate) name: Option<String>,
+    pub(crate) lenient_parameters: Option<bool>,
+    /// Named types declared in the file. Must come before anything that uses them.
+    #[serde(default, deserialize_with = "named_types")]
+    pub(crate) types: IndexMap<String, Type>,
+    #[serde(default)]
+    pub(crate) functions: IndexMap<String, Function>,
+    #[serde(default)]
+    pub(crate) sensors: IndexMap<String, Sensor>,
+    #[serde(default)]
+    pub(crate) axes: IndexMap<String, Axis>,
+    #[serde(default)]
+    pub(crate) streams: IndexMap<String, StreamConfig>,
+}
+
+/// Reads and validates a machine config file, which is JSON if its extension is .json,
+/// and TOML if its extension is .toml. `types` are the named types that are already registered.
+pub(crate) fn load(
+    path: &Path,
+    types: &IndexMap<String, Type>,
+    lookup: CallbackLookup,
+    user_data: *mut c_void,
+) -> Result<MachineConfig, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    CONTEXT.with(|c| *c.borrow_mut() = Some(Context { types: types.clone(), lookup, user_data }));
+    let config = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("json") => serde_json::from_str::<MachineConfig>(&text).map_err(|e| e.to_string()),
+        Some("toml") => toml::from_str::<MachineConfig>(&text).map_err(|e| e.to_string()),
+        _ => Err("unrecognized file extension (expected .toml or .json)".to_owned()),
+    };
+    CONTEXT.with(|c| *c.borrow_mut() = None);
+    config
+}
+
+fn with_context<T>(f: impl FnOnce(&mut Context) -> T) -> T {
+    CONTEXT.with(|c| f(c.borrow_mut().as_mut().expect("config deserialized outside of config::load")))
+}
+
+/// Deserializes a type name, which may refer to named types declared earlier.
+fn type_name<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Type, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    with_context(|context| Type::parse(name.trim(), &context.types))
+        .ok_or_else(|| D::Error::custom(format!("unrecognized type {:?}", name)))
+}
+
+fn optional_type_name<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Type>, D::Error> {
+    type_name(deserializer).map(Some)
+}
+
+/// Deserializes a parameter's type, in the same syntax as a parameter descriptor's type
+/// (e.g. "int", "int optional", or "double = 1.5").
+fn type_descriptor<'de, D: Deserializer<'de>>(deserializer: D) -> Result<(Type, Presence), D::Error> {
+    let descriptor = String::deserialize(deserializer)?;
+    let (r#type, presence) = with_context(|context| parse_type_descriptor(&descriptor, &context.types))
+        .map_err(|e| D::Error::custom(format!("{}: {:?}", e, descriptor)))?;
+    // Checked here (as well as by Function::new) so that the error points at the descriptor.
+    if let Presence::Default(default) = &presence {
+        let marshaller = input_marshaller(&r#type)
+            .ok_or_else(|| D::Error::custom(format!("unsupported input type: {}", r#type)))?;
+        if let Err(e) = marshaller(default) {
+            // Drop the position within the default value, so that it is not mistaken for one in the file.
+            let reason = e.to_string();
+            let reason = reason.rsplit_once(" at line ").map_or(reason.as_str(), |(reason, _)| reason);
+            return Err(D::Error::custom(format!("invalid default value {} for {}: {}", default, r#type, reason)));
+        }
+    }
+    Ok((r#type, presence))
+}
+
+/// Deserializes a callback name, and looks it up with the application's lookup function.
+fn callback<'de, D: Deserializer<'de>>(deserializer: D) -> Result<GenericCallback, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    let c_name = CString::new(name.as_str()).map_err(D::Error::custom)?;
+    with_context(|context| unsafe { (context.lookup)(c_name.as_ptr(), context.user_data) })
+        .ok_or_else(|| D::Error::custom(format!("no callback named {:?}", name)))
+}
+
+/// Same as callback, for an optional key (with #[serde(default)]).
+fn optional_callback<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<GenericCallback>, D::Error> {
+    callback(deserializer).map(Some)
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+enum TypeConfig {
+    Struct { fields: Vec<FieldConfig> },
+    Enum { values: Vec<String> },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FieldConfig {
+    name: String,
+    #[serde(rename = "type", deserialize_with = "type_name")]
+    r#type: Type,
+}
+
+/// Deserializes the types table, adding each type to the context as it goes,
+/// so that later types (and everything else) can use it.
+fn named_types<'de, D: Deserializer<'de>>(deserializer: D) -> Result<IndexMap<String, Type>, D::Error> {
+    struct NamedTypesVisitor;
+
+    impl<'de> Visitor<'de> for NamedTypesVisitor {
+        type Value = IndexMap<String, Type>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a map of type names to type definitions")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut types = IndexMap::new();
+            while let Some(name) = map.next_key::<String>()? {
+                if with_context(|context| Type::parse(&name, &context.types)).is_some() {
+                    return Err(A::Error::custom(format!("a type named {:?} already exists", name)));
+                }
+                let r#type = match map.next_value::<TypeConfig>()? {
+                    TypeConfig::Struct { fields } => {
+                        if fields.is_empty() {
+                            return Err(A::Error::custom(format!("struct {:?} has no fields", name)));
+                        }
+                        let fields = fields.into_iter().map(|field| (field.name, field.r#type)).collect();
+                        Type::Struct(std::sync::Arc::new(StructType { name: name.clone(), fields }))
+                    },
+                    TypeConfig::Enum { values } => {
+                        if values.is_empty() {
+                            return Err(A::Error::custom(format!("enum {:?} has no values", name)));
+                        }
+                        if let Some(value) = values.iter().enumerate().find_map(|(i, v)| values[..i].contains(v).then_some(v)) {
+                            return Err(A::Error::custom(format!("enum value {:?} appears more than once", value)));
+                        }
+                        Type::Enum(std::sync::Arc::new(EnumType { name: name.clone(), values }))
+                    },
+                };
+                with_context(|context| context.types.insert(name.clone(), r#type.clone()));
+                types.insert(name, r#type);
+            }
+            Ok(types)
+        }
+    }
+
+    deserializer.deserialize_map(NamedTypesVisitor)
+}
+
+fn metadata(description: String, unit: String, tags: Vec<String>) -> message::Metadata {
+    message::Metadata { description, unit, tags }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ParameterConfig {
+    name: String,
+    #[serde(rename = "type", deserialize_with = "type_descriptor")]
+    r#type: (Type, Presence),
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    unit: String,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FunctionConfig {
+    #[serde(deserialize_with = "callback")]
+    callback: GenericCallback,
+    /// Whether the callback returns a status code, as with RegisterFunctionEx.
+    #[serde(default)]
+    status: bool,
+    #[serde(default)]
+    parameters: Vec<ParameterConfig>,
+    #[serde(default)]
+    returns: Vec<ParameterConfig>,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    unit: String,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+impl<'de> Deserialize<'de> for Function {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let config = FunctionConfig::deserialize(deserializer)?;
+        let mut parameters = IndexMap::with_capacity(config.parameters.len());
+        let mut parameter_metadata = Vec::with_capacity(config.parameters.len());
+        for parameter in config.parameters {
+            if parameters.insert(parameter.name.clone(), parameter.r#type).is_some() {
+                return Err(D::Error::custom(format!("parameter {:?} appears more than once", parameter.name)));
+            }
+            parameter_metadata.push(metadata(parameter.description, parameter.unit, parameter.tags));
+        }
+        let mut returns = IndexMap::with_capacity(config.returns.len());
+        let mut return_metadata = Vec::with_capacity(config.returns.len());
+        for r#return in config.returns {
+            let (r#type, presence) = r#return.r#type;
+            if !matches!(presence, Presence::Required) {
+                return Err(D::Error::custom(format!("return {:?} cannot be optional or have a default", r#return.name)));
+            }
+            if returns.insert(r#return.name.clone(), r#type).is_some() {
+                return Err(D::Error::custom(format!("return {:?} appears more than once", r#return.name)));
+            }
+            return_metadata.push(metadata(r#return.description, r#return.unit, r#return.tags));
+        }
+        // SAFETY: the application is responsible for returning callbacks of the right type from lookup.
+        let callback = match config.status {
+            false => FunctionCallback::Void(unsafe {
+                std::mem::transmute::<GenericCallback, unsafe extern "C" fn(*const *const c_void, *const *mut c_void)>(config.callback)
+            }),
+            true => FunctionCallback::Status(unsafe {
+                std::mem::transmute::<GenericCallback, unsafe extern "C" fn(*const *const c_void, *const *mut c_void) -> libc::c_int>(config.callback)
+            }),
+        };
+        let mut function = Function::new(parameters, returns, callback).map_err(D::Error::custom)?;
+        function.metadata = metadata(config.description, config.unit, config.tags);
+        for (parameter, metadata) in function.parameters.values_mut().zip(parameter_metadata) {
+            parameter.metadata = metadata;
+        }
+        for (r#return, metadata) in function.returns.values_mut().zip(return_metadata) {
+            r#return.metadata = metadata;
+        }
+        Ok(function)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SensorConfig {
+    #[serde(deserialize_with = "callback")]
+    callback: GenericCallback,
+    /// Whether the callback returns a status code, as with RegisterSensorEx.
+    /// Always true for sensors with a type.
+    #[serde(default)]
+    status: bool,
+    /// The sensor's type, as with RegisterSensorTyped, or None for double, as with RegisterSensor.
+    #[serde(default, rename = "type", deserialize_with = "optional_type_name")]
+    r#type: Option<Type>,
+    /// The range, as with RegisterSensor. Sensors with a type have none.
+    #[serde(default)]
+    min: Option<f64>,
+    #[serde(default)]
+    max: Option<f64>,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    unit: String,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+impl<'de> Deserialize<'de> for Sensor {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let config = SensorConfig::deserialize(deserializer)?;
+        let (min, max) = match config.r#type {
+            Some(_) if config.min.is_some() || config.max.is_some() => return Err(D::Error::custom("sensor with a type cannot have min or max")),
+            Some(_) => (None, None),
+            None => (Some(config.min.unwrap_or(0.0)), Some(config.max.unwrap_or(0.0))),
+        };
+        // SAFETY: the application is responsible for returning callbacks of the right type from lookup.
+        let (output_type, callback) = match (config.r#type, config.status) {
+            (Some(r#type), _) => (r#type, SensorCallback::Typed(unsafe {
+                std::mem::transmute::<GenericCallback, unsafe extern "C" fn(*mut c_void) -> libc::c_int>(config.callback)
+            })),
+            (None, false) => (Type::Prim(PrimType::Double), SensorCallback::Void(unsafe {
+                std::mem::transmute::<GenericCallback, unsafe extern "C" fn(*mut f64)>(config.callback)
+            })),
+            (None, true) => (Type::Prim(PrimType::Double), SensorCallback::Status(unsafe {
+                std::mem::transmute::<GenericCallback, unsafe extern "C" fn(*mut f64) -> libc::c_int>(config.callback)
+            })),
+        };
+        let mut sensor = Sensor::new(output_type, min, max, callback).map_err(D::Error::custom)?;
+        sensor.metadata = metadata(config.description, config.unit, config.tags);
+        Ok(sensor)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AxisConfig {
+    #[serde(deserialize_with = "callback")]
+    callback: GenericCallback,
+    /// Whether the callback returns a status code, as with RegisterAxisEx.
+    #[serde(default)]
+    status: bool,
+    #[serde(default)]
+    min: f64,
+    #[serde(default)]
+    max: f64,
+    #[serde(default)]
+    group: String,
+    #[serde(default)]
+    direction: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    unit: String,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+impl<'de> Deserialize<'de> for Axis {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let config = AxisConfig::deserialize(deserializer)?;
+        // SAFETY: the application is responsible for returning callbacks of the right type from lookup.
+        let callback = match config.status {
+            false => AxisCallback::Void(unsafe {
+                std::mem::transmute::<GenericCallback, unsafe extern "C" fn(f64)>(config.callback)
+            }),
+            true => AxisCallback::Status(unsafe {
+                std::mem::transmute::<GenericCallback, unsafe extern "C" fn(f64) -> libc::c_int>(config.callback)
+            }),
+        };
+        let mut axis = Axis::new(config.min, config.max, config.group, config.direction, callback).map_err(D::Error::custom)?;
+        axis.metadata = metadata(config.description, config.unit, config.tags);
+        Ok(axis)
+    }
+}
+
+/// Where a stream's data is read from.
+pub(crate) enum StreamSource {
+    Fd(RawFd),
+    /// A file (e.g. a named pipe) to be opened when the config is applied.
+    Path(String),
+    /// Neither: the application writes the data with StreamWrite or StreamWriteFrame.
+    Pushed,
+}
+
+/// A stream, whose file is not opened until the rest of the config has been validated.
+pub(crate) struct StreamConfig {
+    pub(crate) format: String,
+    pub(crate) pcm: Option<message::PcmFormat>,
+    pub(crate) source: StreamSource,
+    pub(crate) buffer_method: message::BufferMethod,
+    pub(crate) buffer_size: u64,
+    pub(crate) metadata: message::Metadata,
+    pub(crate) control_callback: Option<unsafe extern "C" fn(state: StreamState)>,
+    pub(crate) max_unsent: Option<usize>,
+    pub(crate) rate_callback: Option<unsafe extern "C" fn(bitrate: f64, frame_rate: f64)>,
+    pub(crate) transport: message::Transport,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawStreamConfig {
+    format: String,
+    fd: Option<RawFd>,
+    path: Option<String>,
+    sample_rate: Option<u32>,
+    channels: Option<u16>,
+    sample_format: Option<message::SampleFormat>,
+    #[serde(default)]
+    buffer_method: message::BufferMethod,
+    #[serde(default)]
+    buffer_size: u64,
+    #[serde(default, deserialize_with = "optional_callback")]
+    control_callback: Option<GenericCallback>,
+    #[serde(default)]
+    rate_control: bool,
+    max_unsent: Option<usize>,
+    #[serde(default, deserialize_with = "optional_callback")]
+    rate_callback: Option<GenericCallback>,
+    #[serde(default)]
+    transport: message::Transport,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    unit: String,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+impl<'de> Deserialize<'de> for StreamConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let config = RawStreamConfig::deserialize(deserializer)?;
+        // Validated here (as RegisterStream does) so that the error points at the stream.
+        let fd = match config.fd {
+            None | Some(-1) => None,
+            Some(fd @ 0..) => Some(fd),
+            Some(fd) => return Err(D::Error::custom(format!("invalid fd {} (must be nonnegative, or -1 for none)", fd))),
+        };
+        let source = match (fd, config.path) {
+            (Some(fd), None) => StreamSource::Fd(fd),
+            (None, Some(path)) => StreamSource::Path(path),
+            (None, None) => StreamSource::Pushed,
+            (Some(_), Some(_)) => return Err(D::Error::custom("stream must not have both fd and path")),
+        };
+        let pcm = match (config.sample_rate, config.channels, config.sample_format) {
+            (Some(sample_rate), Some(channels), Some(sample_format)) => Some(message::PcmFormat { sample_rate, channels, sample_format }),
+            (None, None, None) => None,
+            _ => return Err(D::Error::custom("stream must have all or none of sample_rate, channels, and sample_format")),
+        };
+        // Checked here (as well as by Stream::new) so that the error points at the stream.
+        Stream::new(&config.format, pcm, None, config.buffer_method, config.buffer_size).map_err(D::Error::custom)?;
+        let metadata = metadata(config.description, config.unit, config.tags);
+        // SAFETY: the application is responsible for returning callbacks of the right type from lookup.
+        let control_callback = config.control_callback.map(|control_callback| unsafe {
+            std::mem::transmute::<GenericCallback, unsafe extern "C" fn(StreamState)>(control_callback)
+        });
+        let max_unsent = match (config.rate_control, config.max_unsent) {
+            (true, None | Some(0)) => Some(stream::DEFAULT_MAX_UNSENT),
+            (true, max_unsent) => max_unsent,
+            (false, None) if config.rate_callback.is_none() => None,
+            (false, _) => return Err(D::Error::custom("stream must have rate_control = true to have max_unsent or rate_callback")),
+        };
+        let rate_callback = config.rate_callback.map(|rate_callback| unsafe {
+            std::mem::transmute::<GenericCallback, unsafe extern "C" fn(f64, f64)>(rate_callback)
+        });
+        let RawStreamConfig { format, buffer_method, buffer_size, transport, .. } = config;
+        Ok(StreamConfig {
+            format, pcm, source, buffer_method, buffer_size, metadata, control_callback, max_unsent, rate_callback, transport,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+    use std::path::PathBuf;
+    use crate::errors::ErrorCode;
+    use crate::{ClientHandle, stream::StreamBufferMethod};
+
+    unsafe extern "C" fn multiply(_: *const *const c_void, _: *const *mut c_void) {}
+    unsafe extern "C" fn count(_: *mut f64) {}
+
+    unsafe extern "C" fn lookup(name: *const c_char, _: *mut c_void) -> NullableGenericCallback {
+        // SAFETY: the callbacks are only cast back to their own types.
+        match unsafe { CStr::from_ptr(name) }.to_bytes() {
+            b"multiply" => Some(unsafe { std::mem::transmute::<unsafe extern "C" fn(*const *const c_void, *const *mut c_void), GenericCallback>(multiply) }),
+            b"count" => Some(unsafe { std::mem::transmute::<unsafe extern "C" fn(*mut f64), GenericCallback>(count) }),
+            _ => None,
+        }
+    }
+
+    const TOML: &str = r#"
+name = "machine"
+[functions.multiply]
+callback = "multiply"
+parameters = [{ name = "x", type = "int" }, { name = "y", type = "int = 1" }]
+returns = [{ name = "product", type = "int" }]
+[sensors.count]
+callback = "count"
+min = 0.0
+max = 100.0
+[streams.log]
+format = "ndjson"
+fd = -1
+buffer_method = "Frames"
+buffer_size = 30
+"#;
+
+    const JSON: &str = r#"{
+    "name": "machine",
+    "functions": {
+        "multiply": {
+            "callback": "multiply",
+            "parameters": [{"name": "x", "type": "int"}, {"name": "y", "type": "int = 1"}],
+            "returns": [{"name": "product", "type": "int"}]
+        }
+    },
+    "sensors": {"count": {"callback": "count", "min": 0.0, "max": 100.0}},
+    "streams": {"log": {"format": "ndjson", "buffer_method": "Frames", "buffer_size": 30}}
+}"#;
+
+    /// Writes a config file to the temporary directory, with a name of its own (since tests run in parallel).
+    fn write_config(name: &str, text: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("client-config-{}-{}", std::process::id(), name));
+        std::fs::write(&path, text).expect("Failed to write config");
+        path
+    }
+
+    fn load_config(handle: &mut ClientHandle, path: &Path) -> ErrorCode {
+        let path = CString::new(path.to_str().unwrap()).unwrap();
+        crate::LoadMachineConfig(Some(handle), path.as_ptr(), Some(lookup), std::ptr::null_mut())
+    }
+
+    /// Returns the error from loading a config, which must fail.
+    fn load_error(name: &str, text: &str) -> String {
+        let path = write_config(name, text);
+        let result = load(&path, &IndexMap::new(), lookup, std::ptr::null_mut());
+        std::fs::remove_file(path).unwrap();
+        result.err().expect("config was loaded")
+    }
+
+    /// Exports the machine description of a handle, without its message_id (which differs every time).
+    fn export(handle: &mut ClientHandle) -> serde_json::Value {
+        let mut json = std::ptr::null_mut();
+        assert_eq!(crate::ExportMachineDescription(Some(handle), Some(&mut json)), ErrorCode::NoError);
+        let mut description: serde_json::Value = serde_json::from_str(unsafe { CStr::from_ptr(json) }.to_str().unwrap()).unwrap();
+        crate::FreeString(std::ptr::NonNull::new(json));
+        description.as_object_mut().unwrap().remove("message_id");
+        description
+    }
+
+    /// Loads a config into a new handle, and exports its machine description.
+    fn load_and_export(name: &str, text: &str) -> serde_json::Value {
+        let mut handle = crate::InitializeLibrary().unwrap();
+        let path = write_config(name, text);
+        assert_eq!(load_config(&mut handle, &path), ErrorCode::NoError);
+        std::fs::remove_file(path).unwrap();
+        let description = export(&mut handle);
+        crate::ShutdownLibrary(Some(handle));
+        description
+    }
+
+    #[test]
+    fn toml_and_json_configs_export_what_they_declare() {
+        let toml = load_and_export("declare.toml", TOML);
+        assert_eq!(toml, load_and_export("declare.json", JSON));
+
+        // The same machine, registered with the Register* functions.
+        let mut handle = crate::InitializeLibrary().unwrap();
+        assert_eq!(crate::SetName(Some(&mut handle), c"machine".as_ptr()), ErrorCode::NoError);
+        let mut parameters = [[c"x".as_ptr(), c"int".as_ptr()], [c"y".as_ptr(), c"int = 1".as_ptr()], [std::ptr::null(); 2]];
+        let mut returns = [[c"product".as_ptr(), c"int".as_ptr()], [std::ptr::null(); 2]];
+        assert_eq!(
+            crate::RegisterFunction(Some(&mut handle), c"multiply".as_ptr(), parameters.as_mut_ptr(), returns.as_mut_ptr(), Some(multiply)),
+            ErrorCode::NoError,
+        );
+        assert_eq!(crate::RegisterSensor(Some(&mut handle), c"count".as_ptr(), 0.0, 100.0, Some(count)), ErrorCode::NoError);
+        assert_eq!(
+            crate::RegisterStreamEx(Some(&mut handle), c"log".as_ptr(), c"ndjson".as_ptr(), -1, StreamBufferMethod::BufferFrames, 30),
+            ErrorCode::NoError,
+        );
+        assert_eq!(toml, export(&mut handle));
+        crate::ShutdownLibrary(Some(handle));
+    }
+
+    #[test]
+    fn errors_point_at_their_line() {
+        let text = TOML.replace(r#"type = "int = 1""#, r#"type = "int = 1.5""#);
+        let error = load_error("line.toml", &text);
+        assert!(error.contains("line 5"), "{}", error);
+        let text = JSON.replace(r#""type": "int = 1""#, r#""type": "int = 1.5""#);
+        let error = load_error("line.json", &text);
+        assert!(error.contains("line 6"), "{}", error);
+    }
+
+    #[test]
+    fn invalid_stream_fds_are_rejected() {
+        let error = load_error("fd.toml", &TOML.replace("fd = -1", "fd = -2"));
+        assert!(error.contains("invalid fd -2") && error.contains("line 11"), "{}", error);
+    }
+
+    #[test]
+    fn names_already_registered_are_rejected() {
+        let mut handle = crate::InitializeLibrary().unwrap();
+        assert_eq!(crate::RegisterSensor(Some(&mut handle), c"count".as_ptr(), 0.0, 1.0, Some(count)), ErrorCode::NoError);
+        let path = write_config("duplicate.toml", TOML);
+        assert_eq!(load_config(&mut handle, &path), ErrorCode::DuplicateName);
+        // Nothing from the config was added.
+        assert!(matches!(&*handle, ClientHandle::Unconnected(client) if !client.functions.contains_key("multiply")));
+        crate::ShutdownLibrary(Some(handle));
+        // Nor can the same config be loaded twice.
+        let mut handle = crate::InitializeLibrary().unwrap();
+        assert_eq!(load_config(&mut handle, &path), ErrorCode::NoError);
+        assert_eq!(load_config(&mut handle, &path), ErrorCode::DuplicateName);
+        crate::ShutdownLibrary(Some(handle));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,6 +1,7 @@
 // SAFETY: We are only passing this to C, not getting it from C,
 // so Rust's enum valididty requirements will not be violated.
 #[repr(C)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum ErrorCode {
     /// Success
     NoError = 0,
@@ -40,4 +41,9 @@ pub enum ErrorCode {
     /// Other error (nonfatal), e.g. server sent a FunctionCall with invalid parameters,
     /// or a message that should never be sent to machine (e.g. AxisReturn)
     OtherError = 16,
+    /// A machine config file could not be read, or was invalid.
+    InvalidConfig = 17,
+    /// Data was written to a BufferNoDiscard stream while the server had paused it, so it was not
+    /// queued (StreamPaused is the name of the stream state).
+    StreamIsPaused = 18,
 }
@@ -3,25 +3,25 @@
 pub(crate) mod callbacks;
 pub(crate) mod marshall;
 pub(crate) mod errors;
+pub(crate) mod config;
+pub(crate) mod stream;
 
 use std::fs::File;
-use std::io::{Write, Read};
-use std::os::unix::prelude::FromRawFd;
+use std::os::unix::prelude::{AsRawFd, IntoRawFd};
 #[cfg(unix)]
 pub use std::os::unix::prelude::RawFd;
 #[cfg(not(unix))]
 pub type RawFd = libc::c_int;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::thread::JoinHandle;
 use std::time::Instant;
 use std::{
     ffi::CStr,
     ptr::NonNull,
     collections::HashMap, io::BufReader,
 };
-use libc::{c_char, c_void};
+use libc::{c_char, c_int, c_void};
 use indexmap::map::IndexMap; 
+use serde_json::value::RawValue;
 use callbacks::*;
 use common::message::{self, Message, MessageInner, try_read_message, try_write_message};
 use common::util::*;
@@ -31,6 +31,8 @@ use errors::ErrorCode::{self, *};
 pub struct UnconnectedClient {
     name: Option<String>,
     reset: Option<unsafe extern "C" fn()>,
+    lenient_parameters: bool,
+    types: IndexMap<String, Type>,
     streams: HashMap<String, Stream>,
     sensors: HashMap<String, Sensor>,
     axes: HashMap<String, Axis>,
@@ -40,17 +42,23 @@ pub struct UnconnectedClient {
 pub struct ConnectedClient {
     name: String,
     reset: Option<unsafe extern "C" fn()>,
+    lenient_parameters: bool,
+    types: IndexMap<String, Type>,
     streams: HashMap<String, Stream>,
     sensors: HashMap<String, Sensor>,
     axes: HashMap<String, Axis>,
     functions: HashMap<String, Function>,
     read_connection: BufReader<std::net::TcpStream>,
     write_connection: std::net::TcpStream,
-    stream_flag: Arc<AtomicBool>,
-    stream_threads: Vec<JoinHandle<()>>,
+    /// The threads sending each stream to the server, which are stopped when the client is dropped.
+    stream_workers: HashMap<String, stream::StreamWorker>,
     last_message_received_time: Option<Instant>,
+    /// The server's latest estimate of the clock offset and its uncertainty (see GetClockOffset).
+    clock_offset: Option<(i64, u64)>,
 }
 
+// Always boxed (see InitializeLibrary), so the size of the variants does not matter.
+#[allow(clippy::large_enum_variant)]
 pub enum ClientHandle {
     Unconnected(UnconnectedClient),
     Connected(ConnectedClient),
@@ -65,10 +73,16 @@ impl ClientHandle {
     fn as_connected_mut(&mut self) -> Result<&mut ConnectedClient, &mut UnconnectedClient> {
         match self { Connected(c) => Ok(c), Unconnected(c) => Err(c) }
     }
+    fn as_connected(&self) -> Result<&ConnectedClient, &UnconnectedClient> {
+        match self { Connected(c) => Ok(c), Unconnected(c) => Err(c) }
+    }
 }
 
 
 
+/// Initialize the library and return a handle that will be passed to all library functions.
+/// On success: returns a non-null handle (pointer).
+/// On failure: returns ((ClientHandle)NULL)
 #[no_mangle]
 pub extern "C" fn InitializeLibrary() -> Option<Box<ClientHandle>> {
     Some(Box::new(
@@ -78,21 +92,27 @@ pub extern "C" fn InitializeLibrary() -> Option<Box<ClientHandle>> {
     ))
 }
 
+/// Deinitialize and shut down the library.
+/// Stops sending streams to the server, and waits for the threads sending them to exit.
 #[no_mangle]
 pub extern "C" fn ShutdownLibrary(handle: Option<Box<ClientHandle>>) {
-    shadow_or_return!(mut handle, ());
+    let Some(handle) = handle else { return };
     match *handle {
         Unconnected(_) => {}, // nothing to do
         Connected(handle) => {
-            try_write_message(&handle.write_connection, &Message::new(MessageInner::Disconnect {})); // TODO: error handle
+            let _ = try_write_message(&handle.write_connection, &Message::new(MessageInner::Disconnect {})); // TODO: error handle
+            // Stops the stream threads, and waits for them to exit.
+            drop(handle.stream_workers);
         },
     };
 }
 
+/// Set the name of the client.
+/// @returns enum ErrorCode success (Was the name set successfully)
 #[no_mangle]
 pub extern "C" fn SetName(
     handle: Option<&mut ClientHandle>,
-    name: Option<NonNull<c_char>>
+    name: *const c_char
 ) -> ErrorCode {
     shadow_or_return!(mut handle, InvalidHandle, with_message "Error setting name: Invalid handle (null)");
     shadow_or_return!(name,       NullParameter, with_message "Error setting name: Invalid name (null)");
@@ -106,6 +126,12 @@ pub extern "C" fn SetName(
     NoError
 }
 
+/// Set the reset function. This will be called when the server wants to reset the client
+/// to a known safe state (e.g. if the unity client disconnects).
+/// If this function pointer is NULL, or if the client never sets the function, then
+/// the client library will do nothing upon recieving a reset message, except posibly print a
+/// diagnostic message.
+/// @returns enum ErrorCode success (Was the reset function set successfully)
 #[no_mangle]
 pub extern "C" fn SetReset(
     handle: Option<&mut ClientHandle>,
@@ -117,10 +143,64 @@ pub extern "C" fn SetReset(
     NoError
 }
 
+/// Set whether function calls from the server may include parameters that the function
+/// did not declare. By default (strict), a function call with unknown parameters, missing
+/// parameters, or parameters of the wrong type is rejected, and the server is sent an error
+/// listing every invalid parameter. When lenient, unknown parameters are ignored instead
+/// (missing parameters and parameters of the wrong type are still rejected).
+/// Must be called before connecting to the server.
+/// @param lenient    true to ignore unknown parameters, false to reject them
+/// @returns enum ErrorCode success (Was the setting changed successfully)
+#[no_mangle]
+pub extern "C" fn SetLenientParameters(
+    handle: Option<&mut ClientHandle>,
+    lenient: bool,
+) -> ErrorCode {
+    shadow_or_return!(handle, InvalidHandle, with_message "Error setting lenient parameters: Invalid handle (null)");
+    let handle = unwrap_or_return!(handle.as_unconnected_mut(), AlreadyConnected, with_message "Error setting lenient parameters: Cannot change parameter validation after connecting to server.");
+    handle.lenient_parameters = lenient;
+    NoError
+}
+
+/// Returns whether the server supplied the parameter at the given index of the currently
+/// running function callback (i.e. false if it was omitted, and NULL or its default value
+/// was passed instead). Returns false if not called from a function callback.
+/// @param index      The index of the parameter in the function's parameter descriptors
+#[no_mangle]
+pub extern "C" fn ParameterSupplied(index: c_int) -> bool {
+    parameter_supplied(index)
+}
+
+/// Set an error message describing why the currently running callback failed.
+/// Only meaningful when called from a status-returning callback (registered with
+/// RegisterFunctionEx, RegisterSensorEx, or RegisterAxisEx) that then returns a nonzero status.
+/// The message is copied, and is sent to the server along with the status.
+/// @param message    The error message
+/// @returns enum ErrorCode success (Was the message set successfully)
+#[no_mangle]
+pub extern "C" fn SetCallbackError(
+    message: *const c_char
+) -> ErrorCode {
+    shadow_or_return!(message, NullParameter, with_message "Error setting callback error: Invalid message (null)");
+    let message: &str = unwrap_or_return!(
+        unsafe { CStr::from_ptr(message.as_ptr()) }.to_str(),
+        NonUtf8String,
+        with_message "Error setting callback error: Invalid message (not UTF-8)",
+    );
+    set_callback_error(message.to_owned());
+    NoError
+}
+
+/// Updates internal library state and calls any necessary callbacks.
 #[no_mangle]
 pub extern "C" fn LibraryUpdate(handle: Option<&mut ClientHandle>) -> ErrorCode {
     shadow_or_return!(handle, InvalidHandle, with_message "Error updating: Invalid handle (null)");
     let handle = unwrap_or_return!(handle.as_connected_mut(), AlreadyConnected, with_message "Error updating: Cannot update before connecting to server.");
+    for (name, worker) in &handle.stream_workers {
+        if let (Some((bitrate, frame_rate)), Some(rate_callback)) = (worker.take_rate_target(), handle.streams[name].rate_callback) {
+            unsafe { rate_callback(bitrate, frame_rate); }
+        }
+    }
     while let Some(message) = try_read_message(&mut handle.read_connection, Some(std::time::Duration::from_secs(0))).transpose() {
         let message = match message {
             Ok(message) => message,
@@ -148,6 +228,52 @@ pub extern "C" fn LibraryUpdate(handle: Option<&mut ClientHandle>) -> ErrorCode
                     );
                 }
             }
+            ClockSync { server_time } => {
+                let reply = Message::new(
+                    ClockSyncReply {
+                        reply_to: message.message_id,
+                        server_time,
+                        client_time: stream::clock(),
+                    }
+                );
+                unwrap_or_return!(
+                    try_write_message(&handle.write_connection, &reply),
+                    MessageWriteError,
+                    with_message(e) "Error sending message: {:?}", e
+                );
+            }
+            ClockOffset { offset, uncertainty } => {
+                handle.clock_offset = Some((offset, uncertainty));
+            }
+            inner @ (StreamStart { .. } | StreamPause { .. } | StreamStop { .. }) => {
+                let (stream, stream_state) = match inner {
+                    StreamStart { stream } => (stream, stream::StreamState::StreamStarted),
+                    StreamPause { stream } => (stream, stream::StreamState::StreamPaused),
+                    StreamStop { stream } => (stream, stream::StreamState::StreamStopped),
+                    _ => unreachable!(),
+                };
+                if let Some(worker) = handle.stream_workers.get(&stream) {
+                    if worker.queue().set_stream_state(stream_state) {
+                        if let Some(control_callback) = handle.streams[&stream].control_callback {
+                            unsafe { control_callback(stream_state); }
+                        }
+                    }
+                } else {
+                    eprintln!("Error changing stream state: no stream named {:?} was registered.", stream);
+                    let reply = Message::new(
+                        UnsupportedOperation {
+                            reply_to: message.message_id,
+                            operation: stream,
+                            reason: "unrecognized stream".to_owned(),
+                        }
+                    );
+                    unwrap_or_return!(
+                        try_write_message(&handle.write_connection, &reply),
+                        MessageWriteError,
+                        with_message(e) "Error sending message: {:?}", e
+                    );
+                }
+            },
             Reset {} => {
                 // Reset to safe state, if client has a reset function
                 if let Some(reset) = handle.reset {
@@ -156,15 +282,15 @@ pub extern "C" fn LibraryUpdate(handle: Option<&mut ClientHandle>) -> ErrorCode
             },
             FunctionCall { name, parameters } => {
                 if let Some(function) = handle.functions.get(&name) {
-                    let result = match function.call(&parameters) {
+                    let result = match function.call(&parameters, handle.lenient_parameters) {
                         Ok(result) => result,
                         Err(err) => {
-                            eprintln!("Error calling function: {:?}", err);
+                            eprintln!("Error calling function: {}", err);
                             let reply = Message::new(
                                 UnsupportedOperation {
                                     reply_to: message.message_id,
                                     operation: name,
-                                    reason: format!("{:?}", err),
+                                    reason: err.to_string(),
                                 }
                             );
                             unwrap_or_return!(
@@ -204,15 +330,15 @@ pub extern "C" fn LibraryUpdate(handle: Option<&mut ClientHandle>) -> ErrorCode
             },
             AxisChange { name, value } => {
                 if let Some(axis) = handle.axes.get(&name) {
-                    let result = match axis.call(value) {
-                        Ok(result) => result,
+                    match axis.call(value) {
+                        Ok(()) => {},
                         Err(err) => {
-                            eprintln!("Error changing axis: {:?}", err);
+                            eprintln!("Error changing axis: {}", err);
                             let reply = Message::new(
                                 UnsupportedOperation {
                                     reply_to: message.message_id,
                                     operation: name,
-                                    reason: format!("{:?}", err),
+                                    reason: err.to_string(),
                                 }
                             );
                             unwrap_or_return!(
@@ -253,12 +379,12 @@ pub extern "C" fn LibraryUpdate(handle: Option<&mut ClientHandle>) -> ErrorCode
                     let result = match sensor.call() {
                         Ok(result) => result,
                         Err(err) => {
-                            eprintln!("Error reading sensor: {:?}", err);
+                            eprintln!("Error reading sensor: {}", err);
                             let reply = Message::new(
                                 UnsupportedOperation {
                                     reply_to: message.message_id,
                                     operation: name,
-                                    reason: format!("{:?}", err),
+                                    reason: err.to_string(),
                                 }
                             );
                             unwrap_or_return!(
@@ -318,7 +444,30 @@ pub extern "C" fn LibraryUpdate(handle: Option<&mut ClientHandle>) -> ErrorCode
     NoError
 }
 
-unsafe fn parse_descriptors(descriptors: *const [*const c_char; 2]) -> Result<IndexMap<String, Type>, &'static str> {
+/// Parses the type half of a parameter descriptor, which is a type name optionally followed by
+/// ` optional` (the parameter may be omitted) or `= <json>` (the parameter defaults to the given value).
+fn parse_type_descriptor(descriptor: &str, types: &IndexMap<String, Type>) -> Result<(Type, Presence), &'static str> {
+    let (r#type, presence) = if let Some((r#type, default)) = descriptor.split_once('=') {
+        let default = unwrap_or_return!(
+            serde_json::from_str::<Box<RawValue>>(default.trim()),
+            Err("invalid (non-JSON) default value"),
+        );
+        (r#type, Presence::Default(default))
+    } else if let Some(r#type) = descriptor.trim_end().strip_suffix(" optional") {
+        (r#type, Presence::Optional)
+    } else {
+        (descriptor, Presence::Required)
+    };
+    let r#type = unwrap_or_return!(
+        Type::parse(r#type.trim(), types),
+        Err("unrecognized type"),
+    );
+    Ok((r#type, presence))
+}
+
+// The Register* functions take descriptors as *mut (though they never write to them), so that in C
+// they take a const char *(*)[2], which a const char *[][2] converts to without a warning before C23.
+unsafe fn parse_descriptors(descriptors: *const [*const c_char; 2], types: &IndexMap<String, Type>) -> Result<IndexMap<String, (Type, Presence)>, &'static str> {
     let slice = if descriptors.is_null() {
         &[]
     } else {
@@ -344,23 +493,201 @@ unsafe fn parse_descriptors(descriptors: *const [*const c_char; 2]) -> Result<In
             unsafe { CStr::from_ptr(r#type.as_ptr()) }.to_str(),
             Err("invalid (non-UTF8) type"),
         );
-        let r#type = unwrap_or_return!(
-            Type::from_str(r#type),
-            Err("unrecognized type"),
-        );
-        map.insert(name.to_owned(), r#type);
+        map.insert(name.to_owned(), parse_type_descriptor(r#type, types)?);
     }
     Ok(map)
 }
 
 
+/// Registers a struct type, which can then be used by name as the type of function parameters,
+/// function returns, sensors (see RegisterSensorTyped), and fields of other structs.
+/// @param handle     The client handle
+/// @param name       The name of the type
+/// @param fields     Parameter descriptors for the fields of the struct (see RegisterFunction),
+///                   which may not be optional or have defaults.
+/// @returns enum ErrorCode success (Was the struct registered successfully)
+/// In JSON, a struct is an object with exactly the given fields (in order, when returned),
+/// except that fields of a nullable type may be missing, in which case they are null.
+/// When passed to a callback, a struct is an array of pointers, one for each field in order,
+/// the same way that parameters and returns are passed to a function callback.
+/// I.e. a struct parameter parameters[i] points to a const void *const[number of fields],
+/// and a struct return returns[i] points to a void *const[number of fields].
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)] // Called from C, which passes a raw pointer by design.
+pub extern "C" fn RegisterStruct(
+    handle: Option<&mut ClientHandle>,
+    name: *const c_char,
+    fields: *mut [*const c_char; 2],
+) -> ErrorCode {
+    shadow_or_return!(handle, InvalidHandle, with_message "Error registering struct: Invalid handle (null)");
+    shadow_or_return!(name,   NullParameter, with_message "Error registering struct: Invalid name (null)");
+    let handle = unwrap_or_return!(handle.as_unconnected_mut(), AlreadyConnected, with_message "Error registering struct: Cannot register types after connecting to server.");
+    let name: &str = unwrap_or_return!(
+        unsafe { CStr::from_ptr(name.as_ptr()) }.to_str(),
+        NonUtf8String,
+        with_message "Error registering struct: Invalid name (not UTF-8)",
+    );
+
+    if Type::parse(name, &handle.types).is_some() {
+        eprintln!("Error registering struct: attempted to register type {:?}, but a type with that name already exists.", name);
+        return DuplicateName;
+    }
+
+    let fields = unwrap_or_return!(
+        unsafe { parse_descriptors(fields, &handle.types) },
+        InvalidParameter,
+        with_message(s) "Error parsing struct fields: {}", s,
+    );
+    let fields = unwrap_or_return!(
+        fields.into_iter().map(|(name, (r#type, presence))| match presence {
+            Presence::Required => Ok((name, r#type)),
+            _ => Err(name),
+        }).collect::<Result<Vec<_>, _>>(),
+        InvalidParameter,
+        with_message(name) "Error parsing struct fields: field {:?} cannot be optional or have a default", name,
+    );
+    if fields.is_empty() {
+        eprintln!("Error registering struct: struct {:?} has no fields.", name);
+        return InvalidParameter;
+    }
+
+    let struct_type = StructType { name: name.to_owned(), fields };
+    handle.types.insert(name.to_owned(), Type::Struct(Arc::new(struct_type)));
+    NoError
+}
+
+/// Registers an enum type, which can then be used by name the same way as a struct type
+/// (see RegisterStruct).
+/// @param handle     The client handle
+/// @param name       The name of the type
+/// @param values     The allowed values of the enum, terminated by NULL.
+/// @returns enum ErrorCode success (Was the enum registered successfully)
+/// In JSON, an enum is a string, which must be one of the allowed values.
+/// When passed to a callback, an enum is an int, the index of the value in values.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)] // Called from C, which passes a raw pointer by design.
+pub extern "C" fn RegisterEnum(
+    handle: Option<&mut ClientHandle>,
+    name: *const c_char,
+    values: *const *const c_char,
+) -> ErrorCode {
+    shadow_or_return!(handle, InvalidHandle, with_message "Error registering enum: Invalid handle (null)");
+    shadow_or_return!(name,   NullParameter, with_message "Error registering enum: Invalid name (null)");
+    let handle = unwrap_or_return!(handle.as_unconnected_mut(), AlreadyConnected, with_message "Error registering enum: Cannot register types after connecting to server.");
+    let name: &str = unwrap_or_return!(
+        unsafe { CStr::from_ptr(name.as_ptr()) }.to_str(),
+        NonUtf8String,
+        with_message "Error registering enum: Invalid name (not UTF-8)",
+    );
+
+    if Type::parse(name, &handle.types).is_some() {
+        eprintln!("Error registering enum: attempted to register type {:?}, but a type with that name already exists.", name);
+        return DuplicateName;
+    }
+
+    let mut enum_values: Vec<String> = vec![];
+    if !values.is_null() {
+        loop {
+            let value = unsafe { *values.add(enum_values.len()) };
+            if value.is_null() {
+                break;
+            }
+            let value: &str = unwrap_or_return!(
+                unsafe { CStr::from_ptr(value) }.to_str(),
+                NonUtf8String,
+                with_message "Error registering enum: Invalid value (not UTF-8)",
+            );
+            if enum_values.iter().any(|v| v == value) {
+                eprintln!("Error registering enum: value {:?} appears more than once.", value);
+                return DuplicateName;
+            }
+            enum_values.push(value.to_owned());
+        }
+    }
+    if enum_values.is_empty() {
+        eprintln!("Error registering enum: enum {:?} has no values.", name);
+        return InvalidParameter;
+    }
+
+    let enum_type = EnumType { name: name.to_owned(), values: enum_values };
+    handle.types.insert(name.to_owned(), Type::Enum(Arc::new(enum_type)));
+    NoError
+}
+
+/// Registers a function.
+/// TODO: document how callback works
+/// @param handle     The client handle
+/// @param parameters Parameter descriptors for input parameters
+/// @param returns    Parameter descriptors for output parameters
+/// @param callback   The callback function to call when the server calls the function
+/// @returns enum ErrorCode success (Was the function registered successfully)
+/// Parameter descriptor: A parameter descriptor is an array of two const char*,
+/// the name and type, respectively, of the parameter.
+/// The arrays of parameter descritptors passed into this function should be
+/// terminated by {NULL, NULL}.
+/// Types: bool, byte (int8_t), short (int16_t), int (int32_t), long (int64_t),
+/// ubyte (uint8_t), ushort (uint16_t), uint (uint32_t), ulong (uint64_t), float, double, and string,
+/// or an array of any of those (e.g. "double[]"), bytes, structs registered with RegisterStruct,
+/// and enums registered with RegisterEnum.
+/// Bytes are base64-encoded in JSON, and are passed to and from the callback the same way as
+/// a ubyte[] (struct ArrayInputParameter_t and struct ArrayOutputParameter_t). The fixed-width names i8, i16, i32, i64,
+/// u8, u16, u32, and u64 may be used instead of the integer type names.
+/// A primitive type followed by one or more fixed lengths (e.g. "double[3]" or "float[4][4]")
+/// is a fixed-size array, which is a nested array of exactly those lengths in JSON, and is passed
+/// to and from the callback as a pointer to its elements, in row-major order, with no length
+/// (e.g. parameters[i] points to a const float[4][4], and returns[i] points to a float[4][4]).
+/// A fixed-size array may have at most 1048576 (2^20) elements in all.
+/// "map<string, T>", where T is bool, a numeric type, or string, is an object in JSON, and is passed
+/// to the callback as a struct MapInputParameter_t, whose keys and values are parallel arrays of length
+/// length, with values laid out as in a T[] (e.g. const double[length], or const char *const[length]).
+/// It is returned from the callback as a struct MapOutputParameter_t, whose keys are strings
+/// (each with its own release, as in a string[]) and whose values are laid out as in a T[]
+/// (e.g. double[length], or struct StringOutputParameter_t[length]). After the keys and values
+/// are released individually, release (if not NULL) is called with length, keys, and values.
+/// Any type followed by "?" (e.g. "double?" or "string[]?") is nullable: its JSON value may also be null.
+/// It is passed to the callback as a struct NullableInputParameter_t, whose value points to what a
+/// parameter of the type without the "?" would point to, or is NULL if the JSON value was null.
+/// It is returned from the callback as a struct NullableOutputParameter_t, whose present is false
+/// when the callback is called, and whose value points to where the callback should write the value;
+/// the JSON value is null unless the callback sets present to true.
+/// Integer parameters whose JSON value does not fit in the type are rejected.
+/// The type of an input parameter may be followed by " optional" (e.g. "int optional"),
+/// in which case the server may omit it, and the callback will receive NULL for it,
+/// or by "= " and a JSON default value (e.g. "double = 1.5" or "string = \"hi\""),
+/// in which case the default value will be used if the server omits it.
+/// See ParameterSupplied to check whether the server supplied a parameter.
 #[no_mangle]
 pub extern "C" fn RegisterFunction(
     handle: Option<&mut ClientHandle>,
-    name: Option<NonNull<c_char>>,
+    name: *const c_char,
+    parameters: *mut [*const c_char; 2],
+    returns: *mut [*const c_char; 2],
+    callback: Option<unsafe extern "C" fn (*const *const c_void, *const *mut c_void)>,
+) -> ErrorCode {
+    register_function(handle, name, parameters, returns, callback.map(FunctionCallback::Void))
+}
+
+/// Registers a function whose callback can fail.
+/// Same as RegisterFunction, except that the callback returns a status code:
+/// 0 on success, or nonzero on failure. On failure, the returns are not sent to the server;
+/// an error reply is sent instead (see SetCallbackError).
+#[no_mangle]
+pub extern "C" fn RegisterFunctionEx(
+    handle: Option<&mut ClientHandle>,
+    name: *const c_char,
+    parameters: *mut [*const c_char; 2],
+    returns: *mut [*const c_char; 2],
+    callback: Option<unsafe extern "C" fn (*const *const c_void, *const *mut c_void) -> c_int>,
+) -> ErrorCode {
+    register_function(handle, name, parameters, returns, callback.map(FunctionCallback::Status))
+}
+
+fn register_function(
+    handle: Option<&mut ClientHandle>,
+    name: *const c_char,
     parameters: *const [*const c_char; 2],
     returns: *const [*const c_char; 2],
-    callback: Option<extern "C" fn (*const *const c_void, *const *mut c_void)>,
+    callback: Option<FunctionCallback>,
 ) -> ErrorCode {
     shadow_or_return!(handle,   InvalidHandle, with_message "Error registering function: Invalid handle (null)");
     shadow_or_return!(callback, NullParameter, with_message "Error registering function: Invalid callback (null)");
@@ -378,18 +705,23 @@ pub extern "C" fn RegisterFunction(
     }
 
     let parameters = unwrap_or_return!(
-        unsafe { parse_descriptors(parameters) },
+        unsafe { parse_descriptors(parameters, &handle.types) },
         InvalidParameter,
         with_message(s) "Error parsing function parameters: {}", s,
     );
     let returns = unwrap_or_return!(
-        unsafe { parse_descriptors(returns) },
+        unsafe { parse_descriptors(returns, &handle.types) },
         InvalidParameter,
         with_message(s) "Error parsing function returns: {}", s,
     );
-
-    dbg!(&parameters);
-    dbg!(&returns);
+    let returns = unwrap_or_return!(
+        returns.into_iter().map(|(name, (r#type, presence))| match presence {
+            Presence::Required => Ok((name, r#type)),
+            _ => Err(name),
+        }).collect::<Result<IndexMap<_, _>, _>>(),
+        InvalidParameter,
+        with_message(name) "Error parsing function returns: return {:?} cannot be optional or have a default", name,
+    );
 
     let function = unwrap_or_return!(
         Function::new(parameters, returns, callback),
@@ -401,13 +733,73 @@ pub extern "C" fn RegisterFunction(
     NoError
 }
 
+/// Registers a sensor.
+/// TODO: document how callback works
+/// @param handle     The client handle
+/// @param min        The minimum value that this sensor can have (not enforced)
+/// @param max        The maximum value that this sensor can have (not enforced)
+/// @param callback   The callback function to call when the server reads the sensor
+/// @returns enum ErrorCode success (Was the sensor registered successfully)
+/// Type descriptor: A type descriptor is const char*, the type of the parameter.
 #[no_mangle]
 pub extern "C" fn RegisterSensor(
     handle: Option<&mut ClientHandle>,
-    name: Option<NonNull<c_char>>,
+    name: *const c_char,
+    min: f64,
+    max: f64,
+    callback: Option<unsafe extern "C" fn (*mut f64)>,
+) -> ErrorCode {
+    register_sensor(handle, name, None, Some(min), Some(max), callback.map(SensorCallback::Void))
+}
+
+/// Registers a sensor whose callback can fail.
+/// Same as RegisterSensor, except that the callback returns a status code:
+/// 0 on success, or nonzero on failure. On failure, the value is not sent to the server;
+/// an error reply is sent instead (see SetCallbackError).
+#[no_mangle]
+pub extern "C" fn RegisterSensorEx(
+    handle: Option<&mut ClientHandle>,
+    name: *const c_char,
     min: f64,
     max: f64,
-    callback: Option<extern "C" fn (*mut f64)>,
+    callback: Option<unsafe extern "C" fn (*mut f64) -> c_int>,
+) -> ErrorCode {
+    register_sensor(handle, name, None, Some(min), Some(max), callback.map(SensorCallback::Status))
+}
+
+/// Registers a sensor of any type that a function can return (see RegisterFunction).
+/// @param handle     The client handle
+/// @param name       The name of the sensor
+/// @param type       The type of the sensor
+/// @param callback   The callback function to call when the server reads the sensor
+/// @returns enum ErrorCode success (Was the sensor registered successfully)
+/// The callback's parameter points to the same thing that a function return of the sensor's type
+/// would (e.g. a double for "double", or a struct ArrayOutputParameter_t for "bytes").
+/// The callback returns 0 on success, or nonzero on failure (see RegisterSensorEx).
+#[no_mangle]
+pub extern "C" fn RegisterSensorTyped(
+    handle: Option<&mut ClientHandle>,
+    name: *const c_char,
+    r#type: *const c_char,
+    callback: Option<unsafe extern "C" fn (*mut c_void) -> c_int>,
+) -> ErrorCode {
+    shadow_or_return!(r#type, NullParameter, with_message "Error registering sensor: Invalid type (null)");
+    let r#type: &str = unwrap_or_return!(
+        unsafe { CStr::from_ptr(r#type.as_ptr()) }.to_str(),
+        NonUtf8String,
+        with_message "Error registering sensor: Invalid type (not UTF-8)",
+    );
+    register_sensor(handle, name, Some(r#type), None, None, callback.map(SensorCallback::Typed))
+}
+
+/// output_type is the name of the sensor's type, or None for double.
+fn register_sensor(
+    handle: Option<&mut ClientHandle>,
+    name: *const c_char,
+    output_type: Option<&str>,
+    min: Option<f64>,
+    max: Option<f64>,
+    callback: Option<SensorCallback>,
 ) -> ErrorCode {
     shadow_or_return!(handle,       InvalidHandle, with_message "Error registering sensor: Invalid handle (null)");
     shadow_or_return!(callback,     NullParameter, with_message "Error registering sensor: Invalid callback (null)");
@@ -424,10 +816,17 @@ pub extern "C" fn RegisterSensor(
         return DuplicateName;
     }
 
-    let output_type = Type::Prim(PrimType::Double);
+    let output_type = match output_type {
+        Some(output_type) => unwrap_or_return!(
+            Type::parse(output_type, &handle.types),
+            InvalidParameter,
+            with_message "Error registering sensor: Unrecognized type {:?}", output_type,
+        ),
+        None => Type::Prim(PrimType::Double),
+    };
 
     let sensor = unwrap_or_return!(
-        Sensor::new(min, max, callback),
+        Sensor::new(output_type, min, max, callback),
         InvalidParameter,
         with_message(e) "Error registering sensor: {:?}", e
     );
@@ -437,12 +836,93 @@ pub extern "C" fn RegisterSensor(
 }
 
 
+/// Registers a stream.
+/// @param handle     The client handle
+/// @param name       The name of the stream
+/// @param format     The format of the stream: "mjpeg", "h264" (Annex B), or "ndjson"
+///                   (for pcm, see RegisterPcmStream)
+/// @param fd         The file descriptor to read stream data from, or -1 to write the data
+///                   with StreamWrite or StreamWriteFrame instead.
+/// @returns enum ErrorCode success (Was the stream registered successfully)
+/// Same as RegisterStreamEx with BufferNoDiscard.
 #[no_mangle]
 pub extern "C" fn RegisterStream(
     handle: Option<&mut ClientHandle>,
-    name: Option<NonNull<c_char>>,
-    format: Option<NonNull<c_char>>,
+    name: *const c_char,
+    format: *const c_char,
+    fd: RawFd,
+) -> ErrorCode {
+    register_stream(handle, name, format, None, fd, message::BufferMethod::NoDiscard, 0)
+}
+
+/// Registers a stream, with what to do with its data when the server falls behind.
+/// @param handle        The client handle
+/// @param name          The name of the stream
+/// @param format        The format of the stream: "mjpeg", "h264" (Annex B), or "ndjson"
+///                      (for pcm, see RegisterPcmStream)
+/// @param fd            The file descriptor to read stream data from, or -1 to write the data
+///                      with StreamWrite or StreamWriteFrame instead.
+/// @param buffer_method What to do when the server falls behind (see enum StreamBufferMethod)
+/// @param buffer_size   The number of frames (BufferFrames) or bytes (BufferBytes) to buffer
+///                      before discarding the oldest, which must be positive. Ignored for BufferNoDiscard.
+/// @returns enum ErrorCode success (Was the stream registered successfully)
+/// The buffer method and size are sent to the server, and are also applied to the data waiting
+/// to be sent to the server. Data read from fd (or written with StreamWrite) is split into frames
+/// according to the format, and only whole frames are discarded. For mjpeg, each frame is one JPEG
+/// image, and anything between images (such as multipart boundaries) is discarded. For h264, each
+/// frame is one access unit (all of its NAL units, with their start codes). For ndjson, each frame
+/// is one record, with its newline.
+/// Each frame is sent to the server with its length, a sequence number, the time it was captured
+/// (when it was read from fd or written, see MonotonicMicroseconds), and whether it is a keyframe
+/// (for h264, whether it contains an IDR picture).
+#[no_mangle]
+pub extern "C" fn RegisterStreamEx(
+    handle: Option<&mut ClientHandle>,
+    name: *const c_char,
+    format: *const c_char,
+    fd: RawFd,
+    buffer_method: stream::StreamBufferMethod,
+    buffer_size: u64,
+) -> ErrorCode {
+    register_stream(handle, name, format, None, fd, buffer_method.into(), buffer_size)
+}
+
+/// Registers a stream of raw PCM audio, with the layout of its samples.
+/// @param handle        The client handle
+/// @param name          The name of the stream
+/// @param fd            The file descriptor to read stream data from, or -1 to write the data
+///                      with StreamWrite or StreamWriteFrame instead.
+/// @param buffer_method What to do when the server falls behind (see RegisterStreamEx)
+/// @param buffer_size   The number of frames or bytes to buffer (see RegisterStreamEx)
+/// @param sample_rate   Samples per second, per channel, which must be positive
+/// @param channels      The number of channels, whose samples are interleaved, which must be positive
+/// @param sample_format The layout of each sample (see enum PcmSampleFormat)
+/// @returns enum ErrorCode success (Was the stream registered successfully)
+/// The layout is sent to the server with the stream's format, "pcm". Each frame is the whole samples
+/// (for every channel) of a read from fd (or StreamWrite), and the rest is kept for the next frame.
+#[no_mangle]
+pub extern "C" fn RegisterPcmStream(
+    handle: Option<&mut ClientHandle>,
+    name: *const c_char,
+    fd: RawFd,
+    buffer_method: stream::StreamBufferMethod,
+    buffer_size: u64,
+    sample_rate: u32,
+    channels: u16,
+    sample_format: stream::PcmSampleFormat,
+) -> ErrorCode {
+    let pcm = message::PcmFormat { sample_rate, channels, sample_format: sample_format.into() };
+    register_stream(handle, name, c_str!("pcm").as_ptr(), Some(pcm), fd, buffer_method.into(), buffer_size)
+}
+
+fn register_stream(
+    handle: Option<&mut ClientHandle>,
+    name: *const c_char,
+    format: *const c_char,
+    pcm: Option<message::PcmFormat>,
     fd: RawFd,
+    buffer_method: message::BufferMethod,
+    buffer_size: u64,
 ) -> ErrorCode {
     #[cfg(not(unix))] {
         eprintln!("Error registering stream: Library does not support streams on non-unix platforms.");
@@ -469,10 +949,18 @@ pub extern "C" fn RegisterStream(
         return DuplicateName;
     }
 
+    let fd = match fd {
+        -1 => None,
+        0.. => Some(fd),
+        _ => {
+            eprintln!("Error registering stream: Invalid fd {} (must be nonnegative, or -1 for none)", fd);
+            return InvalidParameter;
+        }
+    };
     let stream = unwrap_or_return!(
-        Stream::new(format, fd),
+        Stream::new(format, pcm, fd, buffer_method, buffer_size),
         InvalidParameter,
-        with_message(e) "Error registering stream: {:?}", e
+        with_message(e) "Error registering stream: {}", e
     );
 
     handle.streams.insert(name.to_owned(), stream);
@@ -480,15 +968,54 @@ pub extern "C" fn RegisterStream(
 }
 
 
+/// Registers an axis.
+/// TODO: document how callback works
+/// @param handle     The client handle
+/// @param min        The minimum value that this axis can have (not enforced)
+/// @param max        The maximum value that this axis can have (not enforced)
+/// @param group      The group that this axis is a member of
+/// @param direction  The direction that this axis is in
+/// @param callback   The callback function to call when the server moves the axis
+/// @returns enum ErrorCode success (Was the axis registered successfully)
+/// The callback must take a single `double` parameter
 #[no_mangle]
 pub extern "C" fn RegisterAxis(
     handle: Option<&mut ClientHandle>,
-    name: Option<NonNull<c_char>>,
+    name: *const c_char,
+    min: f64,
+    max: f64,
+    group: *const c_char,
+    direction: *const c_char,
+    callback: Option<unsafe extern "C" fn (f64)>,
+) -> ErrorCode {
+    register_axis(handle, name, min, max, group, direction, callback.map(AxisCallback::Void))
+}
+
+/// Registers an axis whose callback can fail.
+/// Same as RegisterAxis, except that the callback returns a status code:
+/// 0 on success, or nonzero on failure. On failure, an error reply is sent to the
+/// server (see SetCallbackError).
+#[no_mangle]
+pub extern "C" fn RegisterAxisEx(
+    handle: Option<&mut ClientHandle>,
+    name: *const c_char,
+    min: f64,
+    max: f64,
+    group: *const c_char,
+    direction: *const c_char,
+    callback: Option<unsafe extern "C" fn (f64) -> c_int>,
+) -> ErrorCode {
+    register_axis(handle, name, min, max, group, direction, callback.map(AxisCallback::Status))
+}
+
+fn register_axis(
+    handle: Option<&mut ClientHandle>,
+    name: *const c_char,
     min: f64,
     max: f64,
-    group: Option<NonNull<c_char>>,
-    direction: Option<NonNull<c_char>>,
-    callback: Option<extern "C" fn (f64)>,
+    group: *const c_char,
+    direction: *const c_char,
+    callback: Option<AxisCallback>,
 ) -> ErrorCode {
     shadow_or_return!(handle,     InvalidHandle, with_message "Error registering axis: Invalid handle (null)");
     shadow_or_return!(callback,   NullParameter, with_message "Error registering axis: Invalid callback (null)");
@@ -500,7 +1027,7 @@ pub extern "C" fn RegisterAxis(
         with_message "Error registering axis: Invalid name (not UTF-8)",
     );
 
-    let group = match group {
+    let group = match NonNull::new(group.cast_mut()) {
         Some(group) => {
             unwrap_or_return!(
                 unsafe { CStr::from_ptr(group.as_ptr()) }.to_str(),
@@ -511,7 +1038,7 @@ pub extern "C" fn RegisterAxis(
         None => "",
     };
 
-    let direction = match direction {
+    let direction = match NonNull::new(direction.cast_mut()) {
         Some(direction) => {
             unwrap_or_return!(
                 unsafe { CStr::from_ptr(direction.as_ptr()) }.to_str(),
@@ -527,8 +1054,6 @@ pub extern "C" fn RegisterAxis(
         return DuplicateName;
     }
 
-    let input_type = Type::Prim(PrimType::Double);
-
     let axis = unwrap_or_return!(
         Axis::new(min, max, group.to_owned(), direction.to_owned(), callback),
         InvalidParameter,
@@ -539,10 +1064,543 @@ pub extern "C" fn RegisterAxis(
     NoError
 }
 
+/// Parses the description, unit, and (NULL-terminated) tags passed to the Set*Metadata functions,
+/// any of which may be NULL.
+unsafe fn parse_metadata(
+    description: *const c_char,
+    unit: *const c_char,
+    tags: *const *const c_char,
+) -> Result<message::Metadata, &'static str> {
+    let to_string = |s: *const c_char, error| match s.is_null() {
+        false => unsafe { CStr::from_ptr(s) }.to_str().map(str::to_owned).or(Err(error)),
+        true => Ok(String::new()),
+    };
+    let description = to_string(description, "invalid (non-UTF8) description")?;
+    let unit = to_string(unit, "invalid (non-UTF8) unit")?;
+    let mut metadata_tags: Vec<String> = vec![];
+    if !tags.is_null() {
+        loop {
+            let tag = unsafe { *tags.add(metadata_tags.len()) };
+            if tag.is_null() {
+                break;
+            }
+            metadata_tags.push(to_string(tag, "invalid (non-UTF8) tag")?);
+        }
+    }
+    Ok(message::Metadata { description, unit, tags: metadata_tags })
+}
+
+/// Shared implementation of the Set*Metadata functions.
+/// `names` are the name(s) identifying the item, and `set` stores the metadata on it,
+/// returning false if there is no such item.
+fn set_metadata(
+    handle: Option<&mut ClientHandle>,
+    item: &str,
+    names: &[*const c_char],
+    description: *const c_char,
+    unit: *const c_char,
+    tags: *const *const c_char,
+    set: impl FnOnce(&mut UnconnectedClient, &[&str], message::Metadata) -> bool,
+) -> ErrorCode {
+    shadow_or_return!(handle, InvalidHandle, with_message "Error setting {} metadata: Invalid handle (null)", item);
+    let handle = unwrap_or_return!(handle.as_unconnected_mut(), AlreadyConnected, with_message "Error setting {} metadata: Cannot set metadata after connecting to server.", item);
+    let mut name_strs: Vec<&str> = Vec::with_capacity(names.len());
+    for &name in names {
+        shadow_or_return!(name, NullParameter, with_message "Error setting {} metadata: Invalid name (null)", item);
+        name_strs.push(unwrap_or_return!(
+            unsafe { CStr::from_ptr(name.as_ptr()) }.to_str(),
+            NonUtf8String,
+            with_message "Error setting {} metadata: Invalid name (not UTF-8)", item,
+        ));
+    }
+    let metadata = unwrap_or_return!(
+        unsafe { parse_metadata(description, unit, tags) },
+        NonUtf8String,
+        with_message(s) "Error setting {} metadata: {}", item, s,
+    );
+    if !set(handle, &name_strs, metadata) {
+        eprintln!("Error setting {} metadata: no {} named {:?} was registered.", item, item, name_strs.join("."));
+        return InvalidParameter;
+    }
+    NoError
+}
+
+/// Sets the human-readable metadata of a registered function, which is sent to the server.
+/// @param handle      The client handle
+/// @param function    The name of the function
+/// @param description A description of the function, or NULL for none
+/// @param unit        The unit of the function's result (e.g. "m/s", "degrees", or "A"), or NULL for none
+/// @param tags        Free-form tags, terminated by NULL, or NULL for none
+/// @returns enum ErrorCode success (Was the metadata set successfully)
+/// The function must already be registered, and the metadata replaces any previously set.
+/// Returns InvalidParameter if no function with that name is registered.
+#[no_mangle]
+pub extern "C" fn SetFunctionMetadata(
+    handle: Option<&mut ClientHandle>,
+    function: *const c_char,
+    description: *const c_char,
+    unit: *const c_char,
+    tags: *const *const c_char,
+) -> ErrorCode {
+    set_metadata(handle, "function", &[function], description, unit, tags, |handle, names, metadata| {
+        handle.functions.get_mut(names[0]).map(|f| f.metadata = metadata).is_some()
+    })
+}
+
+/// Sets the human-readable metadata of a parameter or return of a registered function.
+/// @param handle      The client handle
+/// @param function    The name of the function
+/// @param parameter   The name of the parameter or return
+/// @param description A description of the parameter, or NULL for none
+/// @param unit        The unit of the parameter (e.g. "m/s", "degrees", or "A"), or NULL for none
+/// @param tags        Free-form tags, terminated by NULL, or NULL for none
+/// @returns enum ErrorCode success (Was the metadata set successfully)
+/// See SetFunctionMetadata.
+#[no_mangle]
+pub extern "C" fn SetParameterMetadata(
+    handle: Option<&mut ClientHandle>,
+    function: *const c_char,
+    parameter: *const c_char,
+    description: *const c_char,
+    unit: *const c_char,
+    tags: *const *const c_char,
+) -> ErrorCode {
+    set_metadata(handle, "parameter", &[function, parameter], description, unit, tags, |handle, names, metadata| {
+        let Some(function) = handle.functions.get_mut(names[0]) else { return false };
+        if let Some(parameter) = function.parameters.get_mut(names[1]) {
+            parameter.metadata = metadata;
+        } else if let Some(r#return) = function.returns.get_mut(names[1]) {
+            r#return.metadata = metadata;
+        } else {
+            return false;
+        }
+        true
+    })
+}
+
+/// Sets the human-readable metadata of a registered sensor.
+/// See SetFunctionMetadata.
+#[no_mangle]
+pub extern "C" fn SetSensorMetadata(
+    handle: Option<&mut ClientHandle>,
+    sensor: *const c_char,
+    description: *const c_char,
+    unit: *const c_char,
+    tags: *const *const c_char,
+) -> ErrorCode {
+    set_metadata(handle, "sensor", &[sensor], description, unit, tags, |handle, names, metadata| {
+        handle.sensors.get_mut(names[0]).map(|s| s.metadata = metadata).is_some()
+    })
+}
+
+/// Sets the human-readable metadata of a registered axis.
+/// See SetFunctionMetadata.
+#[no_mangle]
+pub extern "C" fn SetAxisMetadata(
+    handle: Option<&mut ClientHandle>,
+    axis: *const c_char,
+    description: *const c_char,
+    unit: *const c_char,
+    tags: *const *const c_char,
+) -> ErrorCode {
+    set_metadata(handle, "axis", &[axis], description, unit, tags, |handle, names, metadata| {
+        handle.axes.get_mut(names[0]).map(|a| a.metadata = metadata).is_some()
+    })
+}
+
+/// Sets the human-readable metadata of a registered stream.
+/// See SetFunctionMetadata.
+#[no_mangle]
+pub extern "C" fn SetStreamMetadata(
+    handle: Option<&mut ClientHandle>,
+    stream: *const c_char,
+    description: *const c_char,
+    unit: *const c_char,
+    tags: *const *const c_char,
+) -> ErrorCode {
+    set_metadata(handle, "stream", &[stream], description, unit, tags, |handle, names, metadata| {
+        handle.streams.get_mut(names[0]).map(|s| s.metadata = metadata).is_some()
+    })
+}
+
+/// Sets the function called when the server starts, pauses, or stops a registered stream
+/// (e.g. to shut down a camera pipeline while nobody is watching it).
+/// @param handle     The client handle
+/// @param stream     The name of the stream
+/// @param callback   Called (by LibraryUpdate) with the stream's new state (see enum StreamState),
+///                   or NULL for none
+/// @returns enum ErrorCode success (Was the callback set successfully)
+/// Streams are started when the client connects, and the callback is only called when the
+/// state changes.
+#[no_mangle]
+pub extern "C" fn SetStreamControlCallback(
+    handle: Option<&mut ClientHandle>,
+    stream: *const c_char,
+    callback: Option<unsafe extern "C" fn(state: stream::StreamState)>,
+) -> ErrorCode {
+    shadow_or_return!(handle, InvalidHandle, with_message "Error setting stream control callback: Invalid handle (null)");
+    shadow_or_return!(stream, NullParameter, with_message "Error setting stream control callback: Invalid stream (null)");
+    let handle = unwrap_or_return!(handle.as_unconnected_mut(), AlreadyConnected, with_message "Error setting stream control callback: Cannot set callback after connecting to server.");
+    let stream: &str = unwrap_or_return!(
+        unsafe { CStr::from_ptr(stream.as_ptr()) }.to_str(),
+        NonUtf8String,
+        with_message "Error setting stream control callback: Invalid stream (not UTF-8)",
+    );
+    let stream = unwrap_or_return!(
+        handle.streams.get_mut(stream),
+        InvalidParameter,
+        with_message "Error setting stream control callback: no stream named {:?} was registered.", stream
+    );
+    stream.control_callback = callback;
+    NoError
+}
+
+/// Sets how a registered stream's data is sent to the server (see enum StreamTransport).
+/// @param handle     The client handle
+/// @param stream     The name of the stream
+/// @param transport  TransportTcp (the default) or TransportUdp
+/// @returns enum ErrorCode success (Was the transport set successfully)
+/// With TransportUdp, a frame is lost if any of its datagrams is lost, but a lost datagram does
+/// not delay the frames after it, which suits e.g. live video. Must be called before connecting.
+#[no_mangle]
+pub extern "C" fn SetStreamTransport(
+    handle: Option<&mut ClientHandle>,
+    stream: *const c_char,
+    transport: stream::StreamTransport,
+) -> ErrorCode {
+    shadow_or_return!(handle, InvalidHandle, with_message "Error setting stream transport: Invalid handle (null)");
+    shadow_or_return!(stream, NullParameter, with_message "Error setting stream transport: Invalid stream (null)");
+    let handle = unwrap_or_return!(handle.as_unconnected_mut(), AlreadyConnected, with_message "Error setting stream transport: Cannot set transport after connecting to server.");
+    let stream: &str = unwrap_or_return!(
+        unsafe { CStr::from_ptr(stream.as_ptr()) }.to_str(),
+        NonUtf8String,
+        with_message "Error setting stream transport: Invalid stream (not UTF-8)",
+    );
+    let stream = unwrap_or_return!(
+        handle.streams.get_mut(stream),
+        InvalidParameter,
+        with_message "Error setting stream transport: no stream named {:?} was registered.", stream
+    );
+    stream.transport = transport.into();
+    NoError
+}
+
+/// Enables rate control for a registered stream, so that when the connection to the server is slower
+/// than the stream's producer, frames are discarded according to the stream's buffer method (or with
+/// BufferNoDiscard, the producer waits) instead of piling up in the socket, and the producer is told
+/// what rate the connection can take.
+/// @param handle     The client handle
+/// @param stream     The name of the stream
+/// @param max_unsent The number of bytes that may be waiting in the stream's socket before frames are
+///                   held back in the stream's buffer, or 0 for the default (64 KiB). Only measured
+///                   on Linux; elsewhere, frames are held back only while writing to the socket blocks.
+/// @param callback   Called (by LibraryUpdate) with a target bitrate (in bits per second) and frame
+///                   rate (in frames per second) for the producer, when they change, or with 0 and 0
+///                   when the producer is no longer limited; or NULL for none
+/// @returns enum ErrorCode success (Was rate control enabled successfully)
+/// The targets are measured about once per second, from the rate at which the server received the
+/// stream while it was congested, and are raised when it is not, until the producer no longer uses them.
+/// Must be called before connecting.
+#[no_mangle]
+pub extern "C" fn SetStreamRateControl(
+    handle: Option<&mut ClientHandle>,
+    stream: *const c_char,
+    max_unsent: u64,
+    callback: Option<unsafe extern "C" fn(bitrate: f64, frame_rate: f64)>,
+) -> ErrorCode {
+    shadow_or_return!(handle, InvalidHandle, with_message "Error setting stream rate control: Invalid handle (null)");
+    shadow_or_return!(stream, NullParameter, with_message "Error setting stream rate control: Invalid stream (null)");
+    let handle = unwrap_or_return!(handle.as_unconnected_mut(), AlreadyConnected, with_message "Error setting stream rate control: Cannot enable rate control after connecting to server.");
+    let stream: &str = unwrap_or_return!(
+        unsafe { CStr::from_ptr(stream.as_ptr()) }.to_str(),
+        NonUtf8String,
+        with_message "Error setting stream rate control: Invalid stream (not UTF-8)",
+    );
+    let max_unsent = match max_unsent {
+        0 => stream::DEFAULT_MAX_UNSENT,
+        _ => unwrap_or_return!(
+            usize::try_from(max_unsent).ok(),
+            InvalidParameter,
+            with_message "Error setting stream rate control: max_unsent too large"
+        ),
+    };
+    let stream = unwrap_or_return!(
+        handle.streams.get_mut(stream),
+        InvalidParameter,
+        with_message "Error setting stream rate control: no stream named {:?} was registered.", stream
+    );
+    stream.max_unsent = Some(max_unsent);
+    stream.rate_callback = callback;
+    NoError
+}
+
+/// Loads a machine config file, which declares the name and any of the types, functions, sensors,
+/// axes, and streams that could otherwise be registered with the functions above, with their metadata.
+/// @param handle     The client handle
+/// @param path       The path of the config file, which must end in .toml (TOML) or .json (JSON)
+/// @param lookup     Function that returns the callback with the given name (as named in the config file),
+///                   cast to GenericCallback_t, or NULL if there is none. user_data is passed to it.
+/// @param user_data  Passed to lookup
+/// @returns enum ErrorCode success (Was the config loaded successfully)
+/// The callback returned by lookup must have the type that the corresponding Register* function takes.
+/// If the file is invalid (including referring to unknown types or callbacks), nothing is registered,
+/// InvalidConfig is returned, and the error (with the line number, where possible) is printed to stderr.
+/// If anything in the file was already registered, nothing is registered, and DuplicateName is returned.
+///
+/// The file has the following keys, all of which are optional (shown in TOML; JSON is the same shape):
+///   name = "machine name"                   (see SetName)
+///   lenient_parameters = true               (see SetLenientParameters)
+///   [types.pose]                            (see RegisterStruct; must come before anything using it)
+///   kind = "struct"
+///   fields = [{ name = "x", type = "double" }, { name = "y", type = "double" }]
+///   [types.speed_level]                     (see RegisterEnum)
+///   kind = "enum"
+///   values = ["low", "medium", "high"]
+///   [functions.multiply]                    (see RegisterFunction)
+///   callback = "multiply"                   (the name passed to lookup)
+///   status = false                          (true for a callback as in RegisterFunctionEx)
+///   parameters = [{ name = "x", type = "int" }, { name = "y", type = "int = 1" }]
+///   returns = [{ name = "product", type = "int" }]
+///   [sensors.count]                         (see RegisterSensor)
+///   callback = "count"
+///   status = false                          (true for a callback as in RegisterSensorEx)
+///   type = "double?"                        (for a callback as in RegisterSensorTyped)
+///   min = 0.0                               (only without type)
+///   max = 100.0
+///   [axes.example]                          (see RegisterAxis)
+///   callback = "example"
+///   status = false                          (true for a callback as in RegisterAxisEx)
+///   min = -1.0
+///   max = 1.0
+///   group = "example_group"
+///   direction = "x"
+///   [streams.webcam]                        (see RegisterStream)
+///   format = "mjpeg"                        ("mjpeg", "h264", "pcm", or "ndjson")
+///   fd = 0                                  (or path = "/path/to/fifo", which is opened for reading,
+///                                            or neither, to write the data with StreamWrite)
+///   buffer_method = "Frames"                (see RegisterStreamEx; "Frames", "Bytes", or "NoDiscard")
+///   buffer_size = 30
+///   control_callback = "webcam_control"     (see SetStreamControlCallback)
+///   rate_control = true                     (see SetStreamRateControl)
+///   max_unsent = 65536                      (with rate_control, optional)
+///   rate_callback = "webcam_rate"           (with rate_control, optional)
+///   transport = "udp"                       (see SetStreamTransport; "tcp" or "udp")
+///   [streams.microphone]                    (see RegisterPcmStream)
+///   format = "pcm"
+///   sample_rate = 48000
+///   channels = 2
+///   sample_format = "s16le"                 ("u8", "s16le", "s16be", "s32le", "s32be", "f32le", or "f32be")
+/// Functions, parameters and returns, sensors, axes, and streams may also have description, unit,
+/// and tags keys (see SetFunctionMetadata), e.g. { name = "x", type = "int", unit = "mm" }.
+#[no_mangle]
+pub extern "C" fn LoadMachineConfig(
+    handle: Option<&mut ClientHandle>,
+    path: *const c_char,
+    lookup: Option<unsafe extern "C" fn(name: *const c_char, user_data: *mut c_void) -> config::NullableGenericCallback>,
+    user_data: *mut c_void,
+) -> ErrorCode {
+    shadow_or_return!(handle, InvalidHandle, with_message "Error loading machine config: Invalid handle (null)");
+    shadow_or_return!(path,   NullParameter, with_message "Error loading machine config: Invalid path (null)");
+    shadow_or_return!(lookup, NullParameter, with_message "Error loading machine config: Invalid lookup (null)");
+    let handle = unwrap_or_return!(handle.as_unconnected_mut(), AlreadyConnected, with_message "Error loading machine config: Cannot load config after connecting to server.");
+    let path: &str = unwrap_or_return!(
+        unsafe { CStr::from_ptr(path.as_ptr()) }.to_str(),
+        NonUtf8String,
+        with_message "Error loading machine config: Invalid path (not UTF-8)",
+    );
+
+    let config = unwrap_or_return!(
+        config::load(std::path::Path::new(path), &handle.types, lookup, user_data),
+        InvalidConfig,
+        with_message(e) "Error loading machine config {:?}: {}", path, e,
+    );
+
+    let duplicate = None
+        .or_else(|| config.functions.keys().find(|name| handle.functions.contains_key(*name)).map(|name| ("function", name)))
+        .or_else(|| config.sensors.keys().find(|name| handle.sensors.contains_key(*name)).map(|name| ("sensor", name)))
+        .or_else(|| config.axes.keys().find(|name| handle.axes.contains_key(*name)).map(|name| ("axis", name)))
+        .or_else(|| config.streams.keys().find(|name| handle.streams.contains_key(*name)).map(|name| ("stream", name)));
+    if let Some((kind, name)) = duplicate {
+        eprintln!("Error loading machine config {:?}: {} {:?} was already registered.", path, kind, name);
+        return DuplicateName;
+    }
+
+    // Open streams' files last, so that nothing needs to be closed if validation fails.
+    // Until every stream has been created, the files are owned here (and closed on any error).
+    let mut opened = Vec::new();
+    let mut streams = Vec::with_capacity(config.streams.len());
+    for (name, stream) in config.streams {
+        let fd = match stream.source {
+            config::StreamSource::Fd(fd) => Some(fd),
+            config::StreamSource::Path(stream_path) => {
+                let file = unwrap_or_return!(
+                    File::open(&stream_path),
+                    InvalidConfig,
+                    with_message(e) "Error loading machine config {:?}: could not open {:?} for stream {:?}: {}", path, stream_path, name, e,
+                );
+                let fd = file.as_raw_fd();
+                opened.push(file);
+                Some(fd)
+            },
+            config::StreamSource::Pushed => None,
+        };
+        let mut new_stream = unwrap_or_return!(
+            Stream::new(&stream.format, stream.pcm, fd, stream.buffer_method, stream.buffer_size),
+            InvalidConfig,
+            with_message(e) "Error loading machine config {:?}: stream {:?}: {}", path, name, e,
+        );
+        new_stream.metadata = stream.metadata;
+        new_stream.control_callback = stream.control_callback;
+        new_stream.max_unsent = stream.max_unsent;
+        new_stream.rate_callback = stream.rate_callback;
+        new_stream.transport = stream.transport;
+        streams.push((name, new_stream));
+    }
+
+    if let Some(name) = config.name {
+        handle.name = Some(name);
+    }
+    if let Some(lenient) = config.lenient_parameters {
+        handle.lenient_parameters = lenient;
+    }
+    handle.types.extend(config.types);
+    handle.functions.extend(config.functions);
+    handle.sensors.extend(config.sensors);
+    handle.axes.extend(config.axes);
+    handle.streams.extend(streams);
+    // The streams read (and close) their files from now on.
+    for file in opened {
+        let _ = file.into_raw_fd();
+    }
+    NoError
+}
+
+/// The MachineDescription message that ConnectToServer sends (and ExportMachineDescription exports).
+fn machine_description(
+    name: &str,
+    types: &IndexMap<String, Type>,
+    functions: &HashMap<String, Function>,
+    sensors: &HashMap<String, Sensor>,
+    axes: &HashMap<String, Axis>,
+    streams: &HashMap<String, Stream>,
+) -> MessageInner {
+    MessageInner::MachineDescription {
+        name: name.to_owned(),
+
+        functions: functions.iter().map(|(name, f)| {
+            let parameters = f.parameters.iter().map(|(n, p)| {
+                (n.clone(), p.r#type.to_string())
+            }).collect();
+            let returns = f.returns.iter().map(|(n, r)| {
+                (n.clone(), r.r#type.to_string())
+            }).collect();
+            let optional = f.parameters.iter()
+                .filter(|(_, p)| !matches!(p.presence, Presence::Required))
+                .map(|(n, _)| n.clone())
+                .collect();
+            let defaults = f.parameters.iter().filter_map(|(n, p)| match &p.presence {
+                Presence::Default(default) => Some((n.clone(), default.clone())),
+                _ => None,
+            }).collect();
+            let metadata = f.metadata.clone();
+            let parameter_metadata = f.parameters.iter()
+                .filter(|(_, p)| !p.metadata.is_empty())
+                .map(|(n, p)| (n.clone(), p.metadata.clone()))
+                .collect();
+            let return_metadata = f.returns.iter()
+                .filter(|(_, r)| !r.metadata.is_empty())
+                .map(|(n, r)| (n.clone(), r.metadata.clone()))
+                .collect();
+            (name.clone(), message::Function {
+                parameters, returns, optional, defaults, metadata, parameter_metadata, return_metadata,
+            })
+        }).collect(),
+
+        sensors: sensors.iter().map(|(name, s)| {
+            let output_type = s.output_type.to_string();
+            let metadata = s.metadata.clone();
+            (name.clone(), message::Sensor { output_type, min: s.min, max: s.max, metadata })
+        }).collect(),
+
+        axes: axes.iter().map(|(name, a)| {
+            let input_type = a.input_type.to_string();
+            let direction = a.direction.clone();
+            let group = a.group.clone();
+            let metadata = a.metadata.clone();
+            (name.clone(), message::Axis { input_type, min: a.min, max: a.max, group, direction, metadata })
+        }).collect(),
+
+        streams: streams.iter().map(|(name, s)| {
+            let Stream { format, pcm, buffer_method, buffer_size, transport, metadata, .. } = s;
+            let (format, pcm, transport) = (*format, *pcm, *transport);
+            let metadata = metadata.clone();
+            let buffer_size = *buffer_size as u64;
+            (name.clone(), message::Stream { format, pcm, buffer_method: *buffer_method, buffer_size, transport, metadata })
+        }).collect(),
+
+        types: types.iter().filter_map(|(name, t)| {
+            Some((name.clone(), t.definition()?))
+        }).collect(),
+    }
+}
+
+/// Exports the machine description that ConnectToServer would send to the server, without connecting.
+/// @param handle     The client handle (which must not be connected yet)
+/// @param json_out   Where to store the machine description message, as a JSON string,
+///                   which must be freed with FreeString
+/// @returns enum ErrorCode success (Was the machine description exported successfully)
+/// The message is the same as the one ConnectToServer would send at that point, except for its message_id.
+/// Like ConnectToServer, requires a name to be set (see SetName).
+#[no_mangle]
+pub extern "C" fn ExportMachineDescription(
+    handle: Option<&mut ClientHandle>,
+    json_out: Option<&mut *mut c_char>,
+) -> ErrorCode {
+    shadow_or_return!(handle,   InvalidHandle, with_message "Error exporting machine description: Invalid handle (null)");
+    shadow_or_return!(json_out, NullParameter, with_message "Error exporting machine description: Invalid output pointer (null)");
+    let handle = unwrap_or_return!(
+        handle.as_unconnected_mut(),
+        AlreadyConnected,
+        with_message "Error exporting machine description: already connected",
+    );
+    let name = unwrap_or_return!(
+        handle.name.as_deref(),
+        MissingRequiredValue,
+        with_message "Error exporting machine description: no name set",
+    );
+    let machine_description = Message::new(machine_description(
+        name, &handle.types, &handle.functions, &handle.sensors, &handle.axes, &handle.streams,
+    ));
+    let json = unwrap_or_return!(
+        serde_json::to_string(&machine_description),
+        OtherError,
+        with_message(e) "Error exporting machine description: {}", e,
+    );
+    let json = unwrap_or_return!(
+        std::ffi::CString::new(json),
+        OtherError,
+        with_message(e) "Error exporting machine description: {}", e,
+    );
+    *json_out = json.into_raw();
+    NoError
+}
+
+/// Frees a string returned by the library (e.g. by ExportMachineDescription). Does nothing if string is NULL.
+#[no_mangle]
+pub extern "C" fn FreeString(string: Option<NonNull<c_char>>) {
+    if let Some(string) = string {
+        drop(unsafe { std::ffi::CString::from_raw(string.as_ptr()) });
+    }
+}
+
+/// Connects to a server
+/// @param server     String that is the domain name or IP address (v4 or v6) of the server.
+/// @param port       uint16_t that is the port to connect to on the server.
+/// @returns enum ErrorCode success (Did the client connect successfully)
 #[no_mangle]
 pub extern "C" fn ConnectToServer(
     handle: Option<&mut ClientHandle>,
-    server: Option<NonNull<c_char>>,
+    server: *const c_char,
     port: u16,
     stream_port: u16,
 ) -> ErrorCode {
@@ -573,59 +1631,25 @@ pub extern "C" fn ConnectToServer(
     let write_connection = connection;
 
     let UnconnectedClient {
-        name, reset, sensors, axes, functions, streams
+        name, reset, lenient_parameters, types, sensors, axes, functions, streams
     } = std::mem::take(handle);
 
-    let stream_flag = Arc::new(AtomicBool::new(true));
-
     *handle_ = ClientHandle::Connected(ConnectedClient {
         name: name.unwrap(),
         reset,
-        sensors, axes, functions, streams,
+        lenient_parameters,
+        types, sensors, axes, functions, streams,
         write_connection,
         read_connection,
-        stream_flag,
-        stream_threads: vec![], // Will be set later
+        stream_workers: HashMap::new(), // Will be set later
         last_message_received_time: None,
+        clock_offset: None,
     });
     let handle = match handle_ { Connected(c) => c, _ => unreachable!() };
 
-    let machine_description = Message::new(
-        MessageInner::MachineDescription {
-            name: handle.name.clone().into(),
-
-            functions: handle.functions.iter().map(|(name, f)| {
-                let parameters = f.parameters.iter().map(|(n, (t, _))| {
-                    (n.clone(), t.to_str().to_owned())
-                }).collect();
-                let returns = f.returns.iter().map(|(n, (t, _))| {
-                    (n.clone(), t.to_str().to_owned())
-                }).collect();
-                (name.clone(), message::Function { parameters, returns })
-            }).collect(),
-
-            sensors: handle.sensors.iter().map(|(name, s)| {
-                eprintln!("TODO: sensor min/max");
-                let output_type = s.output_type.to_str().to_owned();
-                (name.clone(), message::Sensor { output_type, min: s.min, max: s.max })
-            }).collect(),
-
-            axes: handle.axes.iter().map(|(name, a)| {
-                eprintln!("TODO: axis min/max");
-                let input_type = a.input_type.to_str().to_owned();
-                let direction = a.direction.clone();
-                let group = a.group.clone();
-                (name.clone(), message::Axis { input_type, min: a.min, max: a.max, group, direction })
-            }).collect(),
-
-            streams: handle.streams.iter().map(|(name, s)| {
-                let Stream { format, fd } = s;
-                let format = format.clone();
-                eprint!("TODO: buffer_method in C API");
-                (name.clone(), message::Stream { format, buffer_method: message::BufferMethod::NoDiscard })
-            }).collect(),
-        }
-    );
+    let machine_description = Message::new(machine_description(
+        &handle.name, &handle.types, &handle.functions, &handle.sensors, &handle.axes, &handle.streams,
+    ));
 
     unwrap_or_return!(
         try_write_message(&handle.write_connection, &machine_description),
@@ -635,16 +1659,36 @@ pub extern "C" fn ConnectToServer(
 
     #[cfg(unix)]
     {
-        let stream_threads = handle.streams.iter().map(
+        let stream_workers = handle.streams.iter().map(
             |(stream_name, stream)| {
-                let mut stream_socket = unwrap_or_return!(
+                let stream_socket = unwrap_or_return!(
                     std::net::TcpStream::connect((server, stream_port)),
                     None,
                     with_message(e) "Error connecting to server stream port: {:?}", e
                 );
 
+                // Sent from the same address as the stream connection, to the same address as it.
+                let udp_socket = match stream.transport {
+                    message::Transport::Tcp => None,
+                    message::Transport::Udp => Some(unwrap_or_return!(
+                        stream_socket.local_addr()
+                            .and_then(|local_addr| std::net::UdpSocket::bind((local_addr.ip(), 0)))
+                            .and_then(|udp_socket| udp_socket.connect(stream_socket.peer_addr()?).map(|()| udp_socket)),
+                        None,
+                        with_message(e) "Error opening UDP socket for stream {:?}: {:?}", stream_name, e
+                    )),
+                };
+                let udp_port = match &udp_socket {
+                    Some(udp_socket) => Some(unwrap_or_return!(
+                        udp_socket.local_addr(),
+                        None,
+                        with_message(e) "Error opening UDP socket for stream {:?}: {:?}", stream_name, e
+                    ).port()),
+                    None => None,
+                };
+
                 let stream_descriptor = Message::new(
-                    MessageInner::StreamDescription { machine: handle.name.clone(), stream: stream_name.clone() }
+                    MessageInner::StreamDescription { machine: handle.name.clone(), stream: stream_name.clone(), udp_port }
                 );
                 unwrap_or_return!(
                     try_write_message(&stream_socket, &stream_descriptor),
@@ -652,44 +1696,328 @@ pub extern "C" fn ConnectToServer(
                     with_message(e) "Error writing to server stream port: {:?}", e
                 );
 
-                let stream_flag = Arc::clone(&handle.stream_flag);
-                let fd = stream.fd;
-
-                let thread = std::thread::spawn(move || {
-                    let mut buf = vec![0; 65536];
-                    let mut file = unsafe { File::from_raw_fd(fd) };
-                    while stream_flag.load(Ordering::Relaxed) {
-                        match file.read(&mut buf[..]) {
-                            Ok(0) => break,
-                            Ok(len) => {
-//                                println!("Read {len} bytes from file");
-                                stream_socket.write_all(&buf[..len]).expect("failed to write data to server");
-                            }
-                            Err(e) => panic!("{:?}", e),
-                        };
-                    }
-                });
-                Some(thread)
-            }
-        ).collect::<Option<Vec<_>>>();
-        let stream_threads = match stream_threads {
-            Some(ts) => ts,
-            None => {
-                handle.stream_flag.store(false, Ordering::SeqCst);
-                unwrap_or_return!(
+                let worker = unwrap_or_return!(
+                    stream::StreamWorker::start(stream_name, stream, stream_socket, udp_socket),
                     None,
-                    ConnectionError,
-                    with_message "Error connecting to server: Failed to start stream thread(s). There may be up to (number of streams registered - 1) threads running"
-                )
+                    with_message(e) "Error starting stream {:?}: {:?}", stream_name, e
+                );
+                Some((stream_name.clone(), worker))
             }
-        };
+        ).collect::<Option<HashMap<_, _>>>();
+        // If a stream failed to start, the ones that did are stopped when stream_workers is dropped.
+        handle.stream_workers = unwrap_or_return!(
+            stream_workers,
+            ConnectionError,
+            with_message "Error connecting to server: Failed to start stream thread(s)"
+        );
+    }
+
+    NoError
+}
+
+/// Writes data to a stream that was registered without an fd (see RegisterStreamEx).
+/// @param handle     The client handle (which must be connected)
+/// @param stream     The name of the stream
+/// @param data       The data, which is split into frames according to the stream's format,
+///                   the same way as data read from an fd (for mjpeg, one JPEG image per frame)
+/// @param len        The length of data in bytes
+/// @returns enum ErrorCode success (Was the data queued successfully)
+/// The data is copied, and queued to be sent to the server according to the stream's buffer method.
+/// With BufferNoDiscard, waits until the data previously written has been sent, and returns
+/// StreamIsPaused (without queueing the data) while the server has paused the stream.
+/// Returns ServerDisconnected if the stream can no longer be sent to the server (see GetStreamStatus).
+/// StreamWrite, StreamWriteFrame, and StreamWriteFrameAt may be called from any thread, including
+/// concurrently with each other, but not concurrently with other functions taking the same handle.
+/// To write from a thread while the handle is in use (e.g. by LibraryUpdate), use a stream writer
+/// (see GetStreamWriter) instead.
+#[no_mangle]
+pub extern "C" fn StreamWrite(
+    handle: Option<&ClientHandle>,
+    stream: *const c_char,
+    data: *const c_void,
+    len: usize,
+) -> ErrorCode {
+    handle_stream_write(handle, stream, data, len, "writing to stream", stream::PushSource::write)
+}
+
+/// Writes one frame to a stream that was registered without an fd (see RegisterStreamEx).
+/// Same as StreamWrite, except that data is sent as one frame, regardless of the stream's format,
+/// so that the buffer method keeps or discards it as a whole.
+#[no_mangle]
+pub extern "C" fn StreamWriteFrame(
+    handle: Option<&ClientHandle>,
+    stream: *const c_char,
+    data: *const c_void,
+    len: usize,
+) -> ErrorCode {
+    handle_stream_write(handle, stream, data, len, "writing frame to stream", |source, data| source.write_frame(data, stream::clock()))
+}
+
+/// Same as StreamWriteFrame, with the time the frame was captured (by default, when it is written).
+/// @param timestamp  The capture time, on the clock returned by MonotonicMicroseconds (CLOCK_MONOTONIC,
+///                   which e.g. V4L2 buffer timestamps also use), in microseconds
+#[no_mangle]
+pub extern "C" fn StreamWriteFrameAt(
+    handle: Option<&ClientHandle>,
+    stream: *const c_char,
+    data: *const c_void,
+    len: usize,
+    timestamp: u64,
+) -> ErrorCode {
+    handle_stream_write(handle, stream, data, len, "writing frame to stream", |source, data| source.write_frame(data, timestamp))
+}
+
+/// Returns (in *writer_ptr) a writer for a stream that was registered without an fd (see RegisterStreamEx),
+/// through which the application writes the stream's data (see StreamWriterWrite).
+/// @param handle     The client handle (which must be connected)
+/// @param stream     The name of the stream
+/// @returns enum ErrorCode success (Was the writer returned successfully)
+/// The writer must be freed with FreeStreamWriter. It does not depend on the handle, so it may be
+/// used from any thread (e.g. a camera's capture callback), concurrently with any function taking the
+/// handle, including LibraryUpdate. After ShutdownLibrary, writing to it returns ServerDisconnected.
+#[no_mangle]
+pub extern "C" fn GetStreamWriter(
+    handle: Option<&ClientHandle>,
+    stream: *const c_char,
+    writer_ptr: Option<&mut Option<Box<stream::StreamWriter>>>,
+) -> ErrorCode {
+    shadow_or_return!(handle,     InvalidHandle, with_message "Error getting stream writer: Invalid handle (null)");
+    shadow_or_return!(stream,     NullParameter, with_message "Error getting stream writer: Invalid stream (null)");
+    shadow_or_return!(writer_ptr, NullParameter, with_message "Error getting stream writer: Invalid writer pointer (null)");
+    let handle = unwrap_or_return!(handle.as_connected(), NotConnected, with_message "Error getting stream writer: not yet connected");
+    let stream: &str = unwrap_or_return!(
+        unsafe { CStr::from_ptr(stream.as_ptr()) }.to_str(),
+        NonUtf8String,
+        with_message "Error getting stream writer: Invalid stream (not UTF-8)",
+    );
+    let source = match push_source(handle, stream, "getting writer for stream") {
+        Ok(source) => Arc::clone(source),
+        Err(error) => return error,
+    };
+    *writer_ptr = Some(Box::new(stream::StreamWriter { name: stream.to_owned(), source }));
+    NoError
+}
+
+/// Frees a writer returned by GetStreamWriter. Does nothing if writer is NULL.
+#[no_mangle]
+pub extern "C" fn FreeStreamWriter(writer: Option<Box<stream::StreamWriter>>) {
+    drop(writer);
+}
+
+/// Same as StreamWrite, through a stream writer (see GetStreamWriter).
+/// StreamWriterWrite, StreamWriterWriteFrame, and StreamWriterWriteFrameAt may be called from any
+/// thread, including concurrently with each other and with functions taking the client handle.
+#[no_mangle]
+pub extern "C" fn StreamWriterWrite(
+    writer: Option<&stream::StreamWriter>,
+    data: *const c_void,
+    len: usize,
+) -> ErrorCode {
+    writer_stream_write(writer, data, len, "writing to stream", stream::PushSource::write)
+}
+
+/// Same as StreamWriteFrame, through a stream writer (see GetStreamWriter).
+#[no_mangle]
+pub extern "C" fn StreamWriterWriteFrame(
+    writer: Option<&stream::StreamWriter>,
+    data: *const c_void,
+    len: usize,
+) -> ErrorCode {
+    writer_stream_write(writer, data, len, "writing frame to stream", |source, data| source.write_frame(data, stream::clock()))
+}
 
-        handle.stream_threads = stream_threads;
+/// Same as StreamWriteFrameAt, through a stream writer (see GetStreamWriter).
+#[no_mangle]
+pub extern "C" fn StreamWriterWriteFrameAt(
+    writer: Option<&stream::StreamWriter>,
+    data: *const c_void,
+    len: usize,
+    timestamp: u64,
+) -> ErrorCode {
+    writer_stream_write(writer, data, len, "writing frame to stream", |source, data| source.write_frame(data, timestamp))
+}
+
+/// Returns the push source of a stream that was registered without an fd, or (after reporting
+/// why) the error to return.
+fn push_source<'a>(handle: &'a ConnectedClient, stream: &str, action: &str) -> Result<&'a Arc<stream::PushSource>, ErrorCode> {
+    match handle.stream_workers.get(stream).map(stream::StreamWorker::push_source) {
+        Some(Some(source)) => Ok(source),
+        Some(None) => {
+            eprintln!("Error {} {:?}: the stream reads its data from an fd.", action, stream);
+            Err(InvalidParameter)
+        }
+        None => {
+            eprintln!("Error {} {:?}: no stream with that name was registered.", action, stream);
+            Err(InvalidParameter)
+        }
+    }
+}
+
+/// Shared implementation of StreamWrite, StreamWriteFrame, and StreamWriteFrameAt.
+fn handle_stream_write(
+    handle: Option<&ClientHandle>,
+    stream: *const c_char,
+    data: *const c_void,
+    len: usize,
+    action: &str,
+    write: impl FnOnce(&stream::PushSource, &[u8]) -> stream::Pushed,
+) -> ErrorCode {
+    shadow_or_return!(handle, InvalidHandle, with_message "Error {}: Invalid handle (null)", action);
+    shadow_or_return!(stream, NullParameter, with_message "Error {}: Invalid stream (null)", action);
+    let handle = unwrap_or_return!(handle.as_connected(), NotConnected, with_message "Error {}: not yet connected", action);
+    let stream: &str = unwrap_or_return!(
+        unsafe { CStr::from_ptr(stream.as_ptr()) }.to_str(),
+        NonUtf8String,
+        with_message "Error {}: Invalid stream (not UTF-8)", action,
+    );
+    match push_source(handle, stream, action) {
+        Ok(source) => stream_write(source, stream, data, len, action, write),
+        Err(error) => error,
+    }
+}
+
+/// Shared implementation of StreamWriterWrite, StreamWriterWriteFrame, and StreamWriterWriteFrameAt.
+fn writer_stream_write(
+    writer: Option<&stream::StreamWriter>,
+    data: *const c_void,
+    len: usize,
+    action: &str,
+    write: impl FnOnce(&stream::PushSource, &[u8]) -> stream::Pushed,
+) -> ErrorCode {
+    shadow_or_return!(writer, NullParameter, with_message "Error {}: Invalid writer (null)", action);
+    stream_write(&writer.source, &writer.name, data, len, action, write)
+}
+
+/// Writes data to the push source of the stream named stream.
+/// `write` queues the data (see stream::Pushed).
+fn stream_write(
+    source: &stream::PushSource,
+    stream: &str,
+    data: *const c_void,
+    len: usize,
+    action: &str,
+    write: impl FnOnce(&stream::PushSource, &[u8]) -> stream::Pushed,
+) -> ErrorCode {
+    if len == 0 {
+        return NoError;
+    }
+    shadow_or_return!(data, NullParameter, with_message "Error {} {:?}: Invalid data (null)", action, stream);
+    let data = unsafe { std::slice::from_raw_parts(data.as_ptr() as *const u8, len) };
+    match write(source, data) {
+        stream::Pushed::Queued => NoError,
+        // Not reported, since it is expected until the server resumes the stream.
+        stream::Pushed::Paused => StreamIsPaused,
+        stream::Pushed::Closed => {
+            eprintln!("Error {} {:?}: the stream is no longer being sent to the server.", action, stream);
+            ServerDisconnected
+        }
     }
+}
+
+/// Returns (in *status_ptr) whether a stream is still being sent to the server (see enum StreamStatus),
+/// and (in *error_ptr, if error_ptr is not NULL) why it failed, or NULL if it has not failed.
+/// @param handle     The client handle (which must be connected)
+/// @param stream     The name of the stream
+/// @returns enum ErrorCode success (Was the status returned successfully)
+/// The error string must be freed with FreeString.
+/// A stream that fails (e.g. because its fd could not be read, or the server closed the stream's
+/// connection) stops being sent, but the client stays connected.
+#[no_mangle]
+pub extern "C" fn GetStreamStatus(
+    handle: Option<&ClientHandle>,
+    stream: *const c_char,
+    status_ptr: Option<&mut stream::StreamStatus>,
+    error_ptr: Option<&mut *mut c_char>,
+) -> ErrorCode {
+    shadow_or_return!(handle,     InvalidHandle, with_message "Error getting stream status: Invalid handle (null)");
+    shadow_or_return!(stream,     NullParameter, with_message "Error getting stream status: Invalid stream (null)");
+    shadow_or_return!(status_ptr, NullParameter, with_message "Error getting stream status: Invalid status pointer (null)");
+    let handle = unwrap_or_return!(handle.as_connected(), NotConnected, with_message "Error getting stream status: not yet connected");
+    let stream: &str = unwrap_or_return!(
+        unsafe { CStr::from_ptr(stream.as_ptr()) }.to_str(),
+        NonUtf8String,
+        with_message "Error getting stream status: Invalid stream (not UTF-8)",
+    );
+    let worker = unwrap_or_return!(
+        handle.stream_workers.get(stream),
+        InvalidParameter,
+        with_message "Error getting stream status: no stream named {:?} was registered.", stream
+    );
+    let (status, error) = worker.status();
+    if let Some(error_ptr) = error_ptr {
+        *error_ptr = match error {
+            // Error messages never contain NUL, but drop them rather than fail if one does.
+            Some(error) => std::ffi::CString::new(error.replace('\0', "")).unwrap().into_raw(),
+            None => std::ptr::null_mut(),
+        };
+    }
+    *status_ptr = status;
+    NoError
+}
+
+/// Returns (in *stats_ptr) statistics of a stream being sent to the server (see struct StreamStats),
+/// e.g. to find out why a stream is stuttering.
+/// @param handle     The client handle (which must be connected)
+/// @param stream     The name of the stream
+/// @returns enum ErrorCode success (Were the statistics returned successfully)
+#[no_mangle]
+pub extern "C" fn GetStreamStats(
+    handle: Option<&ClientHandle>,
+    stream: *const c_char,
+    stats_ptr: Option<&mut stream::StreamStats>,
+) -> ErrorCode {
+    shadow_or_return!(handle,    InvalidHandle, with_message "Error getting stream statistics: Invalid handle (null)");
+    shadow_or_return!(stream,    NullParameter, with_message "Error getting stream statistics: Invalid stream (null)");
+    shadow_or_return!(stats_ptr, NullParameter, with_message "Error getting stream statistics: Invalid statistics pointer (null)");
+    let handle = unwrap_or_return!(handle.as_connected(), NotConnected, with_message "Error getting stream statistics: not yet connected");
+    let stream: &str = unwrap_or_return!(
+        unsafe { CStr::from_ptr(stream.as_ptr()) }.to_str(),
+        NonUtf8String,
+        with_message "Error getting stream statistics: Invalid stream (not UTF-8)",
+    );
+    let worker = unwrap_or_return!(
+        handle.stream_workers.get(stream),
+        InvalidParameter,
+        with_message "Error getting stream statistics: no stream named {:?} was registered.", stream
+    );
+    *stats_ptr = worker.stats();
+    NoError
+}
+
+/// Returns the client's monotonic clock (CLOCK_MONOTONIC), in microseconds.
+/// Stream frames are sent with their capture time on this clock, and the server can estimate its
+/// offset from the server's clock (see GetClockOffset).
+#[no_mangle]
+pub extern "C" fn MonotonicMicroseconds() -> u64 {
+    stream::clock()
+}
+
+/// Returns (in *offset_ptr) the server's latest estimate of the client's clock (see MonotonicMicroseconds)
+/// minus the server's clock, in microseconds, and (in *uncertainty_ptr) its maximum error.
+/// If the server has not sent an estimate, returns 0 in *offset_ptr and UINT64_MAX in *uncertainty_ptr.
+/// Estimates are received by LibraryUpdate.
+#[no_mangle]
+pub extern "C" fn GetClockOffset(
+    handle: Option<&ClientHandle>,
+    offset_ptr: Option<&mut i64>,
+    uncertainty_ptr: Option<&mut u64>,
+) -> ErrorCode {
+    shadow_or_return!(handle,          InvalidHandle, with_message "Error getting clock offset: Invalid handle (null)");
+    shadow_or_return!(offset_ptr,      NullParameter, with_message "Error getting clock offset: Invalid offset pointer (null)");
+    shadow_or_return!(uncertainty_ptr, NullParameter, with_message "Error getting clock offset: Invalid uncertainty pointer (null)");
+    let handle = unwrap_or_return!(
+        handle.as_connected(),
+        NotConnected,
+        with_message "Error getting clock offset: not yet connected",
+    );
 
+    (*offset_ptr, *uncertainty_ptr) = handle.clock_offset.unwrap_or((0, u64::MAX));
     NoError
 }
 
+/// Returns (in *result_ptr) the number of milliseconds since the last message from the server was handled.
+/// If no messages have yet been received, returns -1 in *result_ptr.
+/// May also return -1 spuriously if the platforms monotonic clock is not actually monotonic.
 #[no_mangle]
 pub extern "C" fn MillisecondsSinceLastMessage(
     handle: Option<&mut ClientHandle>,
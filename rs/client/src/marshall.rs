@@ -2,42 +2,59 @@ use std::ffi::{CString, CStr};
 use std::error::Error;
 use std::marker::PhantomData;
 use std::collections::HashMap;
+use std::sync::Arc;
+use indexmap::map::IndexMap;
 use libc::{c_void, c_char};
 use serde_json::value::RawValue;
 use common::util::*;
 
 
 pub(crate) trait InputMarshall {
-    fn from_json(json: &RawValue) -> Result<Box<Self>, Box<dyn Error + Send + Sync + 'static>> where Self: Sized;
     fn data(&self) -> *const c_void;
 }
 
 pub(crate) trait OutputMarshall {
-    fn empty() -> Box<Self> where Self: Sized;
     fn data(&mut self) -> *mut c_void;
     fn to_json(&self) -> Result<Box<RawValue>, Box<dyn Error + Send + Sync + 'static>>;
 }
 
+/// An InputMarshall that needs nothing but the JSON value to be created
+/// (i.e. not a struct, whose marshall also needs its fields' marshallers).
+pub(crate) trait SimpleInputMarshall: InputMarshall {
+    fn from_json(json: &RawValue) -> Result<Box<Self>, Box<dyn Error + Send + Sync + 'static>> where Self: Sized;
+}
+
+/// An OutputMarshall that needs nothing to be created.
+pub(crate) trait SimpleOutputMarshall: OutputMarshall {
+    fn empty() -> Box<Self> where Self: Sized;
+}
+
 macro_rules! impl_primitive_marshall {
     ($ty:ty, $empty:expr) => {
         impl_primitive_marshall!(@input $ty);
         impl_primitive_marshall!(@output $ty, $empty);
     };
     (@input $ty:ty) => {
-        impl InputMarshall for $ty {
+        impl SimpleInputMarshall for $ty {
             fn from_json(json: &RawValue) -> Result<Box<Self>, Box<dyn Error + Send + Sync + 'static>> {
                 Ok(Box::new(serde_json::from_str::<$ty>(json.get())?))
             }
+        }
+
+        impl InputMarshall for $ty {
             fn data(&self) -> *const c_void {
                 self as *const Self as *const c_void
             }
         }
     };
     (@output $ty:ty, $empty:expr) => {
-        impl OutputMarshall for $ty {
+        impl SimpleOutputMarshall for $ty {
             fn empty() -> Box<Self> {
                 Box::new($empty)
             }
+        }
+
+        impl OutputMarshall for $ty {
             fn data(&mut self) -> *mut c_void {
                 self as *mut Self as *mut c_void
             }
@@ -50,13 +67,14 @@ macro_rules! impl_primitive_marshall {
 
 
 #[repr(transparent)]
+#[derive(Default)]
 struct OutputBoolMarshall(u8); // To ensure we don't have invalid bools floating around
 
 impl serde::ser::Serialize for OutputBoolMarshall {
     fn serialize<S: serde::ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
         match self.0 {
             0 => false.serialize(serializer),
-            _ => false.serialize(serializer),
+            _ => true.serialize(serializer),
         }
     }
 }
@@ -67,31 +85,48 @@ impl_primitive_marshall!(i8, 0);
 impl_primitive_marshall!(i16, 0);
 impl_primitive_marshall!(i32, 0);
 impl_primitive_marshall!(i64, 0);
+impl_primitive_marshall!(u8, 0);
+impl_primitive_marshall!(u16, 0);
+impl_primitive_marshall!(u32, 0);
+impl_primitive_marshall!(u64, 0);
 impl_primitive_marshall!(f32, 0.0);
 impl_primitive_marshall!(f64, 0.0);
 
+/// A string parameter, as passed to a callback.
+#[repr(C)]
+struct InputStringMarshallInner {
+    /// The NUL-terminated, UTF-8 string.
+    string: *const c_char,
+}
+
 struct InputStringMarshall {
-    ptr: *const c_char, // points into data
+    inner: InputStringMarshallInner, // points into data
     #[allow(unused)]
-    /// Owns the buffer that ptr points to
+    /// Owns the buffer that inner.string points to
     data: CString,
 }
-    
-impl InputMarshall for InputStringMarshall {
+
+impl SimpleInputMarshall for InputStringMarshall {
     fn from_json(json: &RawValue) -> Result<Box<Self>, Box<dyn Error + Send + Sync + 'static>> {
         eprintln!("TODO: check for memory leaks with strings");
         let data = serde_json::from_str::<CString>(json.get())?;
-        let ptr = data.as_ptr();
-        Ok(Box::new(Self { ptr, data }))
+        let inner = InputStringMarshallInner { string: data.as_ptr() };
+        Ok(Box::new(Self { inner, data }))
     }
+}
+
+impl InputMarshall for InputStringMarshall {
     fn data(&self) -> *const c_void {
-        &self.ptr as *const *const c_char as *const c_void
+        &self.inner as *const InputStringMarshallInner as *const c_void
     }
 }
 
+/// An array parameter (or bytes), as passed to a callback.
 #[repr(C)]
 struct InputArrayMarshallInner {
+    /// The number of elements.
     length: i32,
+    /// The elements (e.g. const double[length], or const char *const[length] for a string[]).
     data: *const c_void,
 }
 
@@ -102,13 +137,41 @@ struct InputArrayMarshall<T> {
     data: Vec<T>,
 }
 
-impl<T: for<'a> serde::de::Deserialize<'a>> InputMarshall for InputArrayMarshall<T> {
+impl<T: for<'a> serde::de::Deserialize<'a>> SimpleInputMarshall for InputArrayMarshall<T> {
     fn from_json(json: &RawValue) -> Result<Box<Self>, Box<dyn Error + Send + Sync + 'static>> {
         let data = serde_json::from_str::<Vec<T>>(json.get())?;
         let length: i32 = data.len().try_into().or(Err("input array was too long"))?;
         let inner = InputArrayMarshallInner { length, data: data.as_ptr() as *const c_void };
         Ok(Box::new(Self { inner, data }))
     }
+}
+
+impl<T: for<'a> serde::de::Deserialize<'a>> InputMarshall for InputArrayMarshall<T> {
+    fn data(&self) -> *const c_void {
+        &self.inner as *const InputArrayMarshallInner as *const c_void
+    }
+}
+
+/// Bytes are base64-encoded in JSON, and passed to C the same way as a byte array.
+struct InputBytesMarshall {
+    inner: InputArrayMarshallInner,
+    #[allow(unused)]
+    // Owns the buffer that inner.data points to
+    data: Vec<u8>,
+}
+
+impl SimpleInputMarshall for InputBytesMarshall {
+    fn from_json(json: &RawValue) -> Result<Box<Self>, Box<dyn Error + Send + Sync + 'static>> {
+        // Not &str, which cannot borrow a string with escapes (e.g. \/, which some encoders emit for /).
+        let encoded = serde_json::from_str::<String>(json.get())?;
+        let data = base64::decode(encoded)?;
+        let length: i32 = data.len().try_into().or(Err("input bytes were too long"))?;
+        let inner = InputArrayMarshallInner { length, data: data.as_ptr() as *const c_void };
+        Ok(Box::new(Self { inner, data }))
+    }
+}
+
+impl InputMarshall for InputBytesMarshall {
     fn data(&self) -> *const c_void {
         &self.inner as *const InputArrayMarshallInner as *const c_void
     }
@@ -130,7 +193,7 @@ struct InputStringArrayMarshall {
     data: Vec<CString>,
 }
 
-impl InputMarshall for InputStringArrayMarshall {
+impl SimpleInputMarshall for InputStringArrayMarshall {
     fn from_json(json: &RawValue) -> Result<Box<Self>, Box<dyn Error + Send + Sync + 'static>> {
         eprintln!("TODO: check for memory leaks with strings");
         let data = serde_json::from_str::<Vec<CString>>(json.get())?;
@@ -140,6 +203,9 @@ impl InputMarshall for InputStringArrayMarshall {
         let inner = InputStringArrayMarshallInner { length, data: ptrs.as_ptr() };
         Ok(Box::new(Self { inner, ptrs, data }))
     }
+}
+
+impl InputMarshall for InputStringArrayMarshall {
     fn data(&self) -> *const c_void {
         &self.inner as *const InputStringArrayMarshallInner as *const c_void
     }
@@ -147,84 +213,126 @@ impl InputMarshall for InputStringArrayMarshall {
 
 
 
+/// A string return, as written by a callback.
 #[repr(C)]
 struct OutputStringMarshall {
-    data: *const c_char,
+    /// The NUL-terminated, UTF-8 string, initially NULL.
+    string: *const c_char,
+    /// If not NULL, called with string after it has been sent to the server.
     release: Option<unsafe extern "C" fn(*const c_char)>,
 }
 
 impl std::ops::Drop for OutputStringMarshall {
     fn drop(&mut self) {
         if let Some(release) = self.release {
-            unsafe { release(self.data) }
+            unsafe { release(self.string) }
         }
     }
 }
 
-impl OutputMarshall for OutputStringMarshall {
+impl SimpleOutputMarshall for OutputStringMarshall {
     fn empty() -> Box<Self> {
         eprintln!("TODO: check for memory leaks with strings");
         Box::new(Self {
-            data: std::ptr::null(),
+            string: std::ptr::null(),
             release: None,
         })
     }
+}
+
+impl OutputMarshall for OutputStringMarshall {
     fn data(&mut self) -> *mut c_void {
         self as *mut Self as *mut c_void
     }
     fn to_json(&self) -> Result<Box<RawValue>, Box<dyn Error + Send + Sync + 'static>> {
-        if self.data.is_null() {
+        if self.string.is_null() {
             Err("output string was null pointer")?;
         }
-        let cstr = unsafe { CStr::from_ptr(self.data) };
+        let cstr = unsafe { CStr::from_ptr(self.string) };
         Ok(RawValue::from_string(serde_json::to_string(cstr)?)?)
     }
 }
 
+/// An array return (or bytes), as written by a callback.
 #[repr(C)]
-struct OutputArrayMarshall<T: Sized> {
+struct OutputArrayMarshallInner {
+    /// The number of elements, initially 0.
     length: i32,
-    data: *mut T,
-    release: Option<unsafe extern "C" fn(i32, *mut T)>,
+    /// The elements (e.g. double[length]), initially NULL.
+    data: *mut c_void,
+    /// If not NULL, called with length and data after they have been sent to the server.
+    release: Option<unsafe extern "C" fn(i32, *mut c_void)>,
+}
+
+#[repr(transparent)]
+struct OutputArrayMarshall<T: Sized> {
+    inner: OutputArrayMarshallInner,
     phantom: PhantomData<T>,
 }
 
 impl<T: Sized> std::ops::Drop for OutputArrayMarshall<T> {
     fn drop(&mut self) {
-        if let Some(release) = self.release {
-            unsafe { release(self.length, self.data) }
+        if let Some(release) = self.inner.release {
+            unsafe { release(self.inner.length, self.inner.data) }
         }
     }
 }
 
-impl<T: serde::ser::Serialize> OutputMarshall for OutputArrayMarshall<T> {
+impl<T: serde::ser::Serialize> SimpleOutputMarshall for OutputArrayMarshall<T> {
     fn empty() -> Box<Self> {
         Box::new(Self {
-            length: 0,
-            data: std::ptr::null_mut(),
-            release: None,
+            inner: OutputArrayMarshallInner {
+                length: 0,
+                data: std::ptr::null_mut(),
+                release: None,
+            },
             phantom: PhantomData,
         })
     }
+}
+
+impl<T: serde::ser::Serialize> OutputMarshall for OutputArrayMarshall<T> {
     fn data(&mut self) -> *mut c_void {
         self as *mut Self as *mut c_void
     }
     fn to_json(&self) -> Result<Box<RawValue>, Box<dyn Error + Send + Sync + 'static>> {
-        let length: usize = match self.length {
-            0 => { // empty array, return early
-                let slice: &[T] = &[];
-                return Ok(RawValue::from_string(serde_json::to_string(slice)?)?)
-            },
-            1.. => self.length.try_into().or(Err("output array too long"))?,
+        Ok(RawValue::from_string(serde_json::to_string(self.as_slice()?)?)?)
+    }
+}
+
+impl<T: Sized> OutputArrayMarshall<T> {
+    fn as_slice(&self) -> Result<&[T], Box<dyn Error + Send + Sync + 'static>> {
+        let length: usize = match self.inner.length {
+            0 => return Ok(&[]), // empty array, return early
+            1.. => self.inner.length.try_into().or(Err("output array too long"))?,
             _ => Err("output array had invalid (negative) length")?,
         };
-        if self.data.is_null() {
+        if self.inner.data.is_null() {
             Err("output array was null pointer")?;
         }
-        let slice: &[T] = unsafe {
-            std::slice::from_raw_parts(self.data, length)
-        };
-        Ok(RawValue::from_string(serde_json::to_string(slice)?)?)
+        Ok(unsafe {
+            std::slice::from_raw_parts(self.inner.data as *const T, length)
+        })
+    }
+}
+
+/// Bytes are passed from C the same way as a byte array, and base64-encoded in JSON.
+#[repr(transparent)]
+struct OutputBytesMarshall(OutputArrayMarshall<u8>);
+
+impl SimpleOutputMarshall for OutputBytesMarshall {
+    fn empty() -> Box<Self> {
+        Box::new(Self(*OutputArrayMarshall::empty()))
+    }
+}
+
+impl OutputMarshall for OutputBytesMarshall {
+    fn data(&mut self) -> *mut c_void {
+        self as *mut Self as *mut c_void
+    }
+    fn to_json(&self) -> Result<Box<RawValue>, Box<dyn Error + Send + Sync + 'static>> {
+        let encoded = base64::encode(self.0.as_slice()?);
+        Ok(RawValue::from_string(serde_json::to_string(&encoded)?)?)
     }
 }
 
@@ -257,8 +365,7 @@ impl std::ops::Drop for OutputStringArrayMarshall {
     }
 }
 
-impl OutputMarshall for OutputStringArrayMarshall {
-//OutputArrayMarshall<T> {
+impl SimpleOutputMarshall for OutputStringArrayMarshall {
     fn empty() -> Box<Self> {
         eprintln!("TODO: check for memory leaks with strings");
         Box::new(Self {
@@ -268,6 +375,9 @@ impl OutputMarshall for OutputStringArrayMarshall {
 //            phantom: PhantomData,
         })
     }
+}
+
+impl OutputMarshall for OutputStringArrayMarshall {
     fn data(&mut self) -> *mut c_void {
         self as *mut Self as *mut c_void
     }
@@ -293,6 +403,396 @@ impl OutputMarshall for OutputStringArrayMarshall {
     }
 }
 
+/// A map parameter, as passed to a callback.
+#[repr(C)]
+struct InputMapMarshallInner {
+    /// The number of entries.
+    length: i32,
+    /// The keys (const char *const[length]).
+    keys: *const *const c_char,
+    /// The values, laid out as in an array of the value type (e.g. const double[length]).
+    values: *const c_void,
+}
+
+/// Maps are objects in JSON, and are passed to C as parallel arrays of keys and values,
+/// laid out like a string array and an array of the value type.
+struct InputMapMarshall {
+    inner: InputMapMarshallInner,
+    #[allow(unused)]
+    /// This owns the buffer that inner.keys points to
+    keys: Box<InputStringArrayMarshall>,
+    #[allow(unused)]
+    /// This owns the buffer that inner.values points to
+    values: Box<dyn InputMarshall>,
+}
+
+impl InputMapMarshall {
+    fn from_json(json: &RawValue, values_marshaller: SimpleInputMarshaller) -> Result<Box<Self>, Box<dyn Error + Send + Sync + 'static>> {
+        let map = serde_json::from_str::<IndexMap<std::string::String, &RawValue>>(json.get())?;
+        let keys = InputStringArrayMarshall::from_json(&serde_json::value::to_raw_value(&map.keys().collect::<Vec<_>>())?)?;
+        let values = values_marshaller(&serde_json::value::to_raw_value(&map.values().collect::<Vec<_>>())?)?;
+        // Both array marshalls point to a length followed by a pointer to their elements.
+        let values_ptr = unsafe { (*(values.data() as *const InputArrayMarshallInner)).data };
+        let inner = InputMapMarshallInner { length: keys.inner.length, keys: keys.inner.data, values: values_ptr };
+        Ok(Box::new(Self { inner, keys, values }))
+    }
+}
+
+impl InputMarshall for InputMapMarshall {
+    fn data(&self) -> *const c_void {
+        &self.inner as *const InputMapMarshallInner as *const c_void
+    }
+}
+
+/// A map return, as written by a callback.
+#[repr(C)]
+struct OutputMapMarshallInner {
+    /// The number of entries, initially 0.
+    length: i32,
+    /// The keys (each with its own release, as in a string[]), initially NULL.
+    keys: *mut OutputStringMarshall,
+    /// The values, laid out as in an array of the value type (e.g. double[length]), initially NULL.
+    values: *mut c_void,
+    /// If not NULL, called with length, keys, and values after the keys and values
+    /// have been released individually.
+    release: Option<unsafe extern "C" fn(i32, *mut OutputStringMarshall, *mut c_void)>,
+}
+
+/// Maps are passed from C as parallel arrays of keys (as strings) and values (as the value type),
+/// which are released with one call to release, and are objects in JSON.
+#[repr(transparent)]
+struct OutputMapMarshall<V: OutputMarshall> {
+    inner: OutputMapMarshallInner,
+    phantom: PhantomData<V>,
+}
+
+impl<V: OutputMarshall> std::ops::Drop for OutputMapMarshall<V> {
+    fn drop(&mut self) {
+        let OutputMapMarshallInner { length, keys, values, release } = self.inner;
+        let values = values as *mut V;
+        let length: usize = match length {
+            0.. => unwrap_or_return!(length.try_into(), eprintln!("output map too long")),
+            _ => return eprintln!("output map had invalid (negative) length"),
+        };
+        if length > 0 && (keys.is_null() || values.is_null()) {
+            return eprintln!("output map was null pointer");
+        }
+        if length > 0 {
+            unsafe {
+                std::ptr::drop_in_place(std::ptr::slice_from_raw_parts_mut(keys, length));
+                std::ptr::drop_in_place(std::ptr::slice_from_raw_parts_mut(values, length));
+            }
+        }
+        if let Some(release) = release {
+            unsafe { release(self.inner.length, keys, values as *mut c_void) }
+        }
+    }
+}
+
+impl<V: OutputMarshall> SimpleOutputMarshall for OutputMapMarshall<V> {
+    fn empty() -> Box<Self> {
+        Box::new(Self {
+            inner: OutputMapMarshallInner {
+                length: 0,
+                keys: std::ptr::null_mut(),
+                values: std::ptr::null_mut(),
+                release: None,
+            },
+            phantom: PhantomData,
+        })
+    }
+}
+
+impl<V: OutputMarshall> OutputMarshall for OutputMapMarshall<V> {
+    fn data(&mut self) -> *mut c_void {
+        self as *mut Self as *mut c_void
+    }
+    fn to_json(&self) -> Result<Box<RawValue>, Box<dyn Error + Send + Sync + 'static>> {
+        let length: usize = match self.inner.length {
+            0 => return Ok(RawValue::from_string("{}".to_owned())?), // empty map, return early
+            1.. => self.inner.length.try_into().or(Err("output map too long"))?,
+            _ => Err("output map had invalid (negative) length")?,
+        };
+        if self.inner.keys.is_null() || self.inner.values.is_null() {
+            Err("output map was null pointer")?;
+        }
+        let (keys, values) = unsafe {(
+            std::slice::from_raw_parts(self.inner.keys, length),
+            std::slice::from_raw_parts(self.inner.values as *const V, length),
+        )};
+        let mut map = IndexMap::with_capacity(length);
+        for (key, value) in keys.iter().zip(values) {
+            if key.string.is_null() {
+                Err("output map key was null pointer")?;
+            }
+            let key = unsafe { CStr::from_ptr(key.string) }.to_str()?;
+            if map.insert(key, value.to_json()?).is_some() {
+                Err(format!("output map had duplicate key {:?}", key))?;
+            }
+        }
+        Ok(RawValue::from_string(serde_json::to_string(&map)?)?)
+    }
+}
+
+/// A nullable parameter, as passed to a callback.
+#[repr(C)]
+struct InputNullableMarshallInner {
+    /// false if the value is null.
+    present: bool,
+    /// What a parameter of the type without the "?" would point to, or NULL if the value is null.
+    value: *const c_void,
+}
+
+/// Nullable values are passed to C as a presence flag and a pointer to the value
+/// (as it would be passed if it were not nullable), which is NULL if the value is null.
+struct InputNullableMarshall {
+    inner: InputNullableMarshallInner,
+    #[allow(unused)]
+    /// This owns what inner.value points to
+    value: Option<Box<dyn InputMarshall>>,
+}
+
+impl InputNullableMarshall {
+    fn from_json(json: &RawValue, marshaller: &InputMarshaller) -> Result<Box<Self>, Box<dyn Error + Send + Sync + 'static>> {
+        let value = match json.get().trim() {
+            "null" => None,
+            _ => Some(marshaller(json)?),
+        };
+        let inner = InputNullableMarshallInner {
+            present: value.is_some(),
+            value: value.as_ref().map_or(std::ptr::null(), |value| value.data()),
+        };
+        Ok(Box::new(Self { inner, value }))
+    }
+}
+
+impl InputMarshall for InputNullableMarshall {
+    fn data(&self) -> *const c_void {
+        &self.inner as *const InputNullableMarshallInner as *const c_void
+    }
+}
+
+/// A nullable return, as written by a callback.
+#[repr(C)]
+struct OutputNullableMarshallInner {
+    /// Initially false. The value is null unless the callback sets this to true.
+    present: OutputBoolMarshall,
+    /// Where the callback should write the value, as it would for a return of the type without the "?".
+    value: *mut c_void,
+}
+
+/// Nullable values are passed from C as a presence flag, initially false, and a pointer to
+/// where the value should be written (as it would be if it were not nullable),
+/// and are null in JSON if the flag is not set.
+struct OutputNullableMarshall {
+    inner: OutputNullableMarshallInner,
+    /// This owns what inner.value points to
+    value: Box<dyn OutputMarshall>,
+}
+
+impl OutputNullableMarshall {
+    fn empty(marshaller: &OutputMarshaller) -> Box<Self> {
+        let mut value = marshaller();
+        let inner = OutputNullableMarshallInner { present: OutputBoolMarshall(0), value: value.data() };
+        Box::new(Self { inner, value })
+    }
+}
+
+impl OutputMarshall for OutputNullableMarshall {
+    fn data(&mut self) -> *mut c_void {
+        &mut self.inner as *mut OutputNullableMarshallInner as *mut c_void
+    }
+    fn to_json(&self) -> Result<Box<RawValue>, Box<dyn Error + Send + Sync + 'static>> {
+        match self.inner.present.0 {
+            0 => Ok(RawValue::from_string("null".to_owned())?),
+            _ => self.value.to_json(),
+        }
+    }
+}
+
+/// Fixed-size arrays are nested arrays in JSON, and are passed to C as a pointer to
+/// the (flattened, row-major) elements.
+struct InputFixedArrayMarshall<T> {
+    data: Vec<T>,
+}
+
+impl<T: for<'a> serde::de::Deserialize<'a>> InputFixedArrayMarshall<T> {
+    fn from_json(json: &RawValue, dimensions: &[usize]) -> Result<Box<Self>, Box<dyn Error + Send + Sync + 'static>> {
+        let mut data = Vec::with_capacity(dimensions.iter().product());
+        Self::flatten(json, dimensions, &mut data)?;
+        Ok(Box::new(Self { data }))
+    }
+    fn flatten(json: &RawValue, dimensions: &[usize], data: &mut Vec<T>) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+        match dimensions {
+            [length] => {
+                let elements = serde_json::from_str::<Vec<T>>(json.get())?;
+                if elements.len() != *length {
+                    Err(format!("expected an array of length {}, got length {}", length, elements.len()))?;
+                }
+                data.extend(elements);
+            },
+            [length, inner @ ..] => {
+                let elements = serde_json::from_str::<Vec<&RawValue>>(json.get())?;
+                if elements.len() != *length {
+                    Err(format!("expected an array of length {}, got length {}", length, elements.len()))?;
+                }
+                for element in elements {
+                    Self::flatten(element, inner, data)?;
+                }
+            },
+            [] => unreachable!("fixed arrays have at least one dimension"),
+        }
+        Ok(())
+    }
+}
+
+impl<T> InputMarshall for InputFixedArrayMarshall<T> {
+    fn data(&self) -> *const c_void {
+        self.data.as_ptr() as *const c_void
+    }
+}
+
+/// Fixed-size arrays are passed from C as a pointer to a (flattened, row-major) buffer
+/// for the callback to fill, and are nested arrays in JSON.
+struct OutputFixedArrayMarshall<T> {
+    data: Vec<T>,
+    dimensions: Vec<usize>,
+}
+
+impl<T: Default> OutputFixedArrayMarshall<T> {
+    fn empty(dimensions: &[usize]) -> Box<Self> {
+        let data = std::iter::repeat_with(T::default).take(dimensions.iter().product()).collect();
+        Box::new(Self { data, dimensions: dimensions.to_vec() })
+    }
+}
+
+impl<T: serde::ser::Serialize> OutputFixedArrayMarshall<T> {
+    fn nest(data: &[T], dimensions: &[usize]) -> Result<Box<RawValue>, Box<dyn Error + Send + Sync + 'static>> {
+        match dimensions {
+            [_] => Ok(RawValue::from_string(serde_json::to_string(data)?)?),
+            [_, inner @ ..] => {
+                let rows = data.chunks(inner.iter().product())
+                    .map(|row| Self::nest(row, inner))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(RawValue::from_string(serde_json::to_string(&rows)?)?)
+            },
+            [] => unreachable!("fixed arrays have at least one dimension"),
+        }
+    }
+}
+
+impl<T: serde::ser::Serialize> OutputMarshall for OutputFixedArrayMarshall<T> {
+    fn data(&mut self) -> *mut c_void {
+        self.data.as_mut_ptr() as *mut c_void
+    }
+    fn to_json(&self) -> Result<Box<RawValue>, Box<dyn Error + Send + Sync + 'static>> {
+        Self::nest(&self.data, &self.dimensions)
+    }
+}
+
+/// Structs are passed to C as an array of pointers to their fields' values, in order
+/// (the same way as the parameters of a function).
+struct InputStructMarshall {
+    ptrs: Vec<*const c_void>,
+    #[allow(unused)]
+    /// Owns the values that ptrs[..] point to
+    fields: Vec<Box<dyn InputMarshall>>,
+}
+
+impl InputStructMarshall {
+    /// `fields` are each field's name and marshaller, and whether it is nullable (in which case
+    /// it may be missing, and is then null).
+    fn from_json(json: &RawValue, fields: &[(String, InputMarshaller, bool)]) -> Result<Box<Self>, Box<dyn Error + Send + Sync + 'static>> {
+        let mut values = serde_json::from_str::<HashMap<String, &RawValue>>(json.get())?;
+        let null = serde_json::from_str::<&RawValue>("null")?;
+        let fields = fields.iter().map(|(name, marshaller, nullable)| {
+            let value = match values.remove(name) {
+                Some(value) => value,
+                None if *nullable => null,
+                None => Err(format!("missing field {:?}", name))?,
+            };
+            marshaller(value).map_err(|e| format!("field {:?}: {}", name, e).into())
+        }).collect::<Result<Vec<_>, Box<dyn Error + Send + Sync + 'static>>>()?;
+        if let Some(name) = values.keys().next() {
+            Err(format!("unknown field {:?}", name))?;
+        }
+        let ptrs = fields.iter().map(|im| im.data()).collect();
+        Ok(Box::new(Self { ptrs, fields }))
+    }
+}
+
+impl InputMarshall for InputStructMarshall {
+    fn data(&self) -> *const c_void {
+        self.ptrs.as_ptr() as *const c_void
+    }
+}
+
+/// Structs are passed to C as an array of pointers to where their fields' values should be written,
+/// in order (the same way as the returns of a function).
+struct OutputStructMarshall {
+    ptrs: Vec<*mut c_void>,
+    /// Owns the values that ptrs[..] point to
+    fields: Vec<(String, Box<dyn OutputMarshall>)>,
+}
+
+impl OutputStructMarshall {
+    fn empty(fields: &[(String, OutputMarshaller)]) -> Box<Self> {
+        let mut fields: Vec<(String, Box<dyn OutputMarshall>)> = fields.iter()
+            .map(|(name, marshaller)| (name.clone(), marshaller()))
+            .collect();
+        let ptrs = fields.iter_mut().map(|(_, om)| om.data()).collect();
+        Box::new(Self { ptrs, fields })
+    }
+}
+
+impl OutputMarshall for OutputStructMarshall {
+    fn data(&mut self) -> *mut c_void {
+        self.ptrs.as_mut_ptr() as *mut c_void
+    }
+    fn to_json(&self) -> Result<Box<RawValue>, Box<dyn Error + Send + Sync + 'static>> {
+        let values = self.fields.iter()
+            .map(|(name, om)| Ok((name.as_str(), om.to_json().map_err(|e| format!("field {:?}: {}", name, e))?)))
+            .collect::<Result<IndexMap<&str, Box<RawValue>>, Box<dyn Error + Send + Sync + 'static>>>()?;
+        Ok(RawValue::from_string(serde_json::to_string(&values)?)?)
+    }
+}
+
+/// Enums are strings in JSON, and are passed to C as the (int) index of the value.
+struct InputEnumMarshall(i32);
+
+impl InputEnumMarshall {
+    fn from_json(json: &RawValue, enum_type: &EnumType) -> Result<Box<Self>, Box<dyn Error + Send + Sync + 'static>> {
+        let value = serde_json::from_str::<String>(json.get())?;
+        let index = enum_type.values.iter().position(|v| *v == value)
+            .ok_or_else(|| format!("{:?} is not one of {:?}", value, enum_type.values))?;
+        Ok(Box::new(Self(index.try_into()?)))
+    }
+}
+
+impl InputMarshall for InputEnumMarshall {
+    fn data(&self) -> *const c_void {
+        &self.0 as *const i32 as *const c_void
+    }
+}
+
+/// Enums are passed from C as the (int) index of the value, and are strings in JSON.
+struct OutputEnumMarshall {
+    index: i32,
+    enum_type: Arc<EnumType>,
+}
+
+impl OutputMarshall for OutputEnumMarshall {
+    fn data(&mut self) -> *mut c_void {
+        &mut self.index as *mut i32 as *mut c_void
+    }
+    fn to_json(&self) -> Result<Box<RawValue>, Box<dyn Error + Send + Sync + 'static>> {
+        let value = usize::try_from(self.index).ok()
+            .and_then(|index| self.enum_type.values.get(index))
+            .ok_or_else(|| format!("output enum index {} out of range for {}", self.index, self.enum_type.name))?;
+        Ok(RawValue::from_string(serde_json::to_string(value)?)?)
+    }
+}
+
 macro_rules! make_input_marshallers {
     ($map:ident, $( $typeval:expr, $ty:ty ),* $(,)?) => {
         $(
@@ -313,13 +813,148 @@ macro_rules! make_output_marshallers {
 
 use crate::callbacks::PrimType;
 use crate::callbacks::Type;
+use crate::callbacks::EnumType;
 
-pub(crate) type InputMarshaller = fn(&RawValue) -> Result<Box<dyn InputMarshall>, Box<dyn std::error::Error + Send + Sync + 'static>>;
-pub(crate) type OutputMarshaller = fn() -> Box<dyn OutputMarshall>;
+pub(crate) type InputMarshaller = Arc<dyn Fn(&RawValue) -> Result<Box<dyn InputMarshall>, Box<dyn std::error::Error + Send + Sync + 'static>> + Send + Sync>;
+pub(crate) type OutputMarshaller = Arc<dyn Fn() -> Box<dyn OutputMarshall> + Send + Sync>;
+
+type SimpleInputMarshaller = fn(&RawValue) -> Result<Box<dyn InputMarshall>, Box<dyn std::error::Error + Send + Sync + 'static>>;
+type SimpleOutputMarshaller = fn() -> Box<dyn OutputMarshall>;
+
+/// Returns the input marshaller for the given type, or None if the type cannot be an input.
+pub(crate) fn input_marshaller(r#type: &Type) -> Option<InputMarshaller> {
+    match r#type {
+        Type::Struct(struct_type) => {
+            let fields = struct_type.fields.iter()
+                .map(|(name, r#type)| Some((name.clone(), input_marshaller(r#type)?, matches!(r#type, Type::Nullable(_)))))
+                .collect::<Option<Vec<_>>>()?;
+            Some(Arc::new(move |json: &RawValue| -> Result<Box<dyn InputMarshall>, _> {
+                Ok(InputStructMarshall::from_json(json, &fields)?)
+            }))
+        },
+        Type::Enum(enum_type) => {
+            let enum_type = Arc::clone(enum_type);
+            Some(Arc::new(move |json: &RawValue| -> Result<Box<dyn InputMarshall>, _> {
+                Ok(InputEnumMarshall::from_json(json, &enum_type)?)
+            }))
+        },
+        Type::FixedArray(prim, dimensions) => {
+            let dimensions = dimensions.clone();
+            macro_rules! marshaller {
+                ($ty:ty) => {
+                    Arc::new(move |json: &RawValue| -> Result<Box<dyn InputMarshall>, _> {
+                        Ok(InputFixedArrayMarshall::<$ty>::from_json(json, &dimensions)?)
+                    })
+                };
+            }
+            use PrimType::*;
+            Some(match prim {
+                Bool => marshaller!(bool),
+                Byte => marshaller!(i8),
+                Short => marshaller!(i16),
+                Int => marshaller!(i32),
+                Long => marshaller!(i64),
+                UByte => marshaller!(u8),
+                UShort => marshaller!(u16),
+                UInt => marshaller!(u32),
+                ULong => marshaller!(u64),
+                Float => marshaller!(f32),
+                Double => marshaller!(f64),
+            })
+        },
+        Type::Map(value_type) => {
+            let values_marshaller = *INPUT_MARSHALLERS.get(&match **value_type {
+                Type::Prim(prim) => Type::PrimArray(prim),
+                Type::String => Type::StringArray,
+                _ => return None,
+            })?;
+            Some(Arc::new(move |json: &RawValue| -> Result<Box<dyn InputMarshall>, _> {
+                Ok(InputMapMarshall::from_json(json, values_marshaller)?)
+            }))
+        },
+        Type::Nullable(r#type) => {
+            let marshaller = input_marshaller(r#type)?;
+            Some(Arc::new(move |json: &RawValue| -> Result<Box<dyn InputMarshall>, _> {
+                Ok(InputNullableMarshall::from_json(json, &marshaller)?)
+            }))
+        },
+        r#type => INPUT_MARSHALLERS.get(r#type).map(|&marshaller| Arc::new(marshaller) as InputMarshaller),
+    }
+}
+
+/// Returns the output marshaller for the given type, or None if the type cannot be an output.
+pub(crate) fn output_marshaller(r#type: &Type) -> Option<OutputMarshaller> {
+    match r#type {
+        Type::Struct(struct_type) => {
+            let fields = struct_type.fields.iter()
+                .map(|(name, r#type)| Some((name.clone(), output_marshaller(r#type)?)))
+                .collect::<Option<Vec<_>>>()?;
+            Some(Arc::new(move || -> Box<dyn OutputMarshall> {
+                OutputStructMarshall::empty(&fields)
+            }))
+        },
+        Type::Enum(enum_type) => {
+            let enum_type = Arc::clone(enum_type);
+            Some(Arc::new(move || -> Box<dyn OutputMarshall> {
+                Box::new(OutputEnumMarshall { index: -1, enum_type: Arc::clone(&enum_type) })
+            }))
+        },
+        Type::FixedArray(prim, dimensions) => {
+            let dimensions = dimensions.clone();
+            macro_rules! marshaller {
+                ($ty:ty) => {
+                    Arc::new(move || -> Box<dyn OutputMarshall> {
+                        OutputFixedArrayMarshall::<$ty>::empty(&dimensions)
+                    })
+                };
+            }
+            use PrimType::*;
+            Some(match prim {
+                Bool => marshaller!(OutputBoolMarshall),
+                Byte => marshaller!(i8),
+                Short => marshaller!(i16),
+                Int => marshaller!(i32),
+                Long => marshaller!(i64),
+                UByte => marshaller!(u8),
+                UShort => marshaller!(u16),
+                UInt => marshaller!(u32),
+                ULong => marshaller!(u64),
+                Float => marshaller!(f32),
+                Double => marshaller!(f64),
+            })
+        },
+        Type::Map(value_type) => {
+            use PrimType::*;
+            let marshaller: SimpleOutputMarshaller = match **value_type {
+                Type::Prim(Bool) => || OutputMapMarshall::<OutputBoolMarshall>::empty(),
+                Type::Prim(Byte) => || OutputMapMarshall::<i8>::empty(),
+                Type::Prim(Short) => || OutputMapMarshall::<i16>::empty(),
+                Type::Prim(Int) => || OutputMapMarshall::<i32>::empty(),
+                Type::Prim(Long) => || OutputMapMarshall::<i64>::empty(),
+                Type::Prim(UByte) => || OutputMapMarshall::<u8>::empty(),
+                Type::Prim(UShort) => || OutputMapMarshall::<u16>::empty(),
+                Type::Prim(UInt) => || OutputMapMarshall::<u32>::empty(),
+                Type::Prim(ULong) => || OutputMapMarshall::<u64>::empty(),
+                Type::Prim(Float) => || OutputMapMarshall::<f32>::empty(),
+                Type::Prim(Double) => || OutputMapMarshall::<f64>::empty(),
+                Type::String => || OutputMapMarshall::<OutputStringMarshall>::empty(),
+                _ => return None,
+            };
+            Some(Arc::new(marshaller))
+        },
+        Type::Nullable(r#type) => {
+            let marshaller = output_marshaller(r#type)?;
+            Some(Arc::new(move || -> Box<dyn OutputMarshall> {
+                OutputNullableMarshall::empty(&marshaller)
+            }))
+        },
+        r#type => OUTPUT_MARSHALLERS.get(r#type).map(|&marshaller| Arc::new(marshaller) as OutputMarshaller),
+    }
+}
 
 lazy_static::lazy_static! {
-    pub(crate) static ref INPUT_MARSHALLERS: HashMap<Type, InputMarshaller> = {
-        let mut map = HashMap::<Type, InputMarshaller>::with_capacity(16);
+    static ref INPUT_MARSHALLERS: HashMap<Type, SimpleInputMarshaller> = {
+        let mut map = HashMap::<Type, SimpleInputMarshaller>::with_capacity(24);
         use PrimType::*;
         use Type::*;
         make_input_marshallers!(map, 
@@ -328,6 +963,10 @@ lazy_static::lazy_static! {
             Prim(Short), i16,
             Prim(Int), i32,
             Prim(Long), i64,
+            Prim(UByte), u8,
+            Prim(UShort), u16,
+            Prim(UInt), u32,
+            Prim(ULong), u64,
             Prim(Float), f32,
             Prim(Double), f64,
             PrimArray(Bool), InputArrayMarshall<bool>,
@@ -335,15 +974,20 @@ lazy_static::lazy_static! {
             PrimArray(Short), InputArrayMarshall<i16>,
             PrimArray(Int), InputArrayMarshall<i32>,
             PrimArray(Long), InputArrayMarshall<i64>,
+            PrimArray(UByte), InputArrayMarshall<u8>,
+            PrimArray(UShort), InputArrayMarshall<u16>,
+            PrimArray(UInt), InputArrayMarshall<u32>,
+            PrimArray(ULong), InputArrayMarshall<u64>,
             PrimArray(Float), InputArrayMarshall<f32>,
             PrimArray(Double), InputArrayMarshall<f64>,
             String, InputStringMarshall,
             StringArray, InputStringArrayMarshall,
+            Bytes, InputBytesMarshall,
         );
         map
     };
-    pub(crate) static ref OUTPUT_MARSHALLERS: HashMap<Type, OutputMarshaller> = {
-        let mut map = HashMap::<Type, OutputMarshaller>::with_capacity(16);
+    static ref OUTPUT_MARSHALLERS: HashMap<Type, SimpleOutputMarshaller> = {
+        let mut map = HashMap::<Type, SimpleOutputMarshaller>::with_capacity(24);
         use PrimType::*;
         use Type::*;
         make_output_marshallers!(map, 
@@ -352,6 +996,10 @@ lazy_static::lazy_static! {
             Prim(Short), i16,
             Prim(Int), i32,
             Prim(Long), i64,
+            Prim(UByte), u8,
+            Prim(UShort), u16,
+            Prim(UInt), u32,
+            Prim(ULong), u64,
             Prim(Float), f32,
             Prim(Double), f64,
             PrimArray(Bool), OutputArrayMarshall<OutputBoolMarshall>,
@@ -359,11 +1007,335 @@ lazy_static::lazy_static! {
             PrimArray(Short), OutputArrayMarshall<i16>,
             PrimArray(Int), OutputArrayMarshall<i32>,
             PrimArray(Long), OutputArrayMarshall<i64>,
+            PrimArray(UByte), OutputArrayMarshall<u8>,
+            PrimArray(UShort), OutputArrayMarshall<u16>,
+            PrimArray(UInt), OutputArrayMarshall<u32>,
+            PrimArray(ULong), OutputArrayMarshall<u64>,
             PrimArray(Float), OutputArrayMarshall<f32>,
             PrimArray(Double), OutputArrayMarshall<f64>,
             String, OutputStringMarshall,
             StringArray, OutputStringArrayMarshall,
+            Bytes, OutputBytesMarshall,
         );
         map
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(r#type: &str) -> Type {
+        parse_with(r#type, &IndexMap::new())
+    }
+
+    /// Parses a type name, which may refer to the given named types.
+    fn parse_with(r#type: &str, named: &IndexMap<String, Type>) -> Type {
+        Type::parse(r#type, named).unwrap_or_else(|| panic!("unrecognized type {:?}", r#type))
+    }
+
+    /// Marshalls a JSON value as an input of the given type, or returns why it was rejected.
+    fn input(r#type: &Type, json: &str) -> Result<Box<dyn InputMarshall>, String> {
+        let marshaller = input_marshaller(r#type).expect("unsupported input type");
+        marshaller(&RawValue::from_string(json.to_owned()).unwrap()).map_err(|e| e.to_string())
+    }
+
+    /// Creates an output of the given type, has `write` fill it in as a callback would
+    /// (given what returns[i] would point to), and returns its JSON, or why it was rejected.
+    fn output(r#type: &Type, write: impl FnOnce(*mut c_void)) -> Result<String, String> {
+        let mut om = output_marshaller(r#type).expect("unsupported output type")();
+        write(om.data());
+        om.to_json().map(|json| json.get().to_owned()).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn bools_round_trip() {
+        let r#type = parse("bool");
+        for (json, value) in [("true", true), ("false", false)] {
+            let im = input(&r#type, json).unwrap();
+            assert_eq!(unsafe { *(im.data() as *const bool) }, value);
+        }
+        // Any nonzero byte written by the callback is true.
+        for (byte, json) in [(0u8, "false"), (1, "true"), (2, "true")] {
+            assert_eq!(output(&r#type, |data| unsafe { *(data as *mut u8) = byte }).as_deref(), Ok(json));
+        }
+        assert!(input(&r#type, "1").is_err());
+    }
+
+    /// Returns a struct type with the given fields, as registered with RegisterStruct.
+    fn struct_type(name: &str, fields: &[(&str, &str)], named: &IndexMap<String, Type>) -> Type {
+        let fields = fields.iter().map(|(field, r#type)| (field.to_string(), parse_with(r#type, named))).collect();
+        Type::Struct(Arc::new(crate::callbacks::StructType { name: name.to_owned(), fields }))
+    }
+
+    #[test]
+    fn structs_round_trip_in_declared_order() {
+        let mut named = IndexMap::new();
+        named.insert("point".to_owned(), struct_type("point", &[("y", "double"), ("x", "double")], &named));
+        let r#type = struct_type("pose", &[("z", "int"), ("position", "point"), ("a", "long")], &named);
+
+        let im = input(&r#type, r#"{"a": 3, "position": {"x": 1.5, "y": -2}, "z": 7}"#).unwrap();
+        unsafe {
+            let fields = im.data() as *const *const c_void;
+            assert_eq!(*(*fields as *const i32), 7);
+            let position = *fields.add(1) as *const *const c_void;
+            assert_eq!((*(*position as *const f64), *(*position.add(1) as *const f64)), (-2.0, 1.5));
+            assert_eq!(*(*fields.add(2) as *const i64), 3);
+        }
+
+        let json = output(&r#type, |data| unsafe {
+            let fields = data as *const *mut c_void;
+            *(*fields as *mut i32) = 7;
+            let position = *fields.add(1) as *const *mut c_void;
+            *(*position as *mut f64) = -2.0;
+            *(*position.add(1) as *mut f64) = 1.5;
+            *(*fields.add(2) as *mut i64) = 3;
+        });
+        assert_eq!(json.as_deref(), Ok(r#"{"z":7,"position":{"y":-2.0,"x":1.5},"a":3}"#));
+    }
+
+    #[test]
+    fn struct_fields_must_match() {
+        let r#type = struct_type("reading", &[("id", "int"), ("value", "double?")], &IndexMap::new());
+        let error = input(&r#type, r#"{"value": 1.0}"#).err().unwrap();
+        assert!(error.contains(r#"missing field "id""#), "{}", error);
+        let error = input(&r#type, r#"{"id": 1, "value": 1.0, "extra": 2}"#).err().unwrap();
+        assert!(error.contains(r#"unknown field "extra""#), "{}", error);
+        let error = input(&r#type, r#"{"id": "1", "value": 1.0}"#).err().unwrap();
+        assert!(error.contains(r#"field "id""#), "{}", error);
+        assert!(input(&r#type, "[1, 1.0]").is_err());
+
+        // A nullable field may be missing, and is then null.
+        for json in [r#"{"id": 1}"#, r#"{"id": 1, "value": null}"#] {
+            let im = input(&r#type, json).unwrap();
+            let value = unsafe { *(im.data() as *const *const InputNullableMarshallInner).add(1) };
+            assert!(unsafe { !(*value).present && (*value).value.is_null() });
+        }
+    }
+
+    #[test]
+    fn fixed_arrays_round_trip() {
+        let r#type = parse("float[2][3]");
+        let im = input(&r#type, "[[1, 2, 3], [4, 5, 6]]").unwrap();
+        let elements = unsafe { std::slice::from_raw_parts(im.data() as *const f32, 6) };
+        assert_eq!(elements, [1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        let json = output(&r#type, |data| unsafe {
+            std::slice::from_raw_parts_mut(data as *mut f32, 6).copy_from_slice(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.5]);
+        });
+        assert_eq!(json.as_deref(), Ok("[[1.0,2.0,3.0],[4.0,5.0,6.5]]"));
+    }
+
+    #[test]
+    fn fixed_arrays_must_have_their_shape() {
+        let r#type = parse("int[2][2]");
+        for json in ["[[1, 2], [3]]", "[[1, 2], [3, 4], [5, 6]]", "[1, 2, 3, 4]", "[[1, 2], [3, [4]]]", "[[1, 2], 3]"] {
+            assert!(input(&r#type, json).is_err(), "{}", json);
+        }
+        assert!(input(&parse("ubyte[2]"), "[1, 256]").is_err());
+    }
+
+    #[test]
+    fn fixed_arrays_are_limited_in_size() {
+        assert_eq!(Type::parse("double[1024][1024]", &IndexMap::new()), Some(Type::FixedArray(PrimType::Double, vec![1024, 1024])));
+        for r#type in ["double[1024][1025]", "u8[1048577]", "bool[65536][65536][65536][65536]", "int[0]"] {
+            assert_eq!(Type::parse(r#type, &IndexMap::new()), None, "{}", r#type);
+        }
+    }
+
+    /// A function callback taking three ints (each of which may be NULL, and is then 0), and
+    /// returning their sum, and whether the second was supplied (see ParameterSupplied).
+    unsafe extern "C" fn sum(parameters: *const *const c_void, returns: *const *mut c_void) {
+        let parameter = |i| unsafe { (*parameters.add(i) as *const i32).as_ref().copied().unwrap_or(0) };
+        unsafe {
+            *(*returns as *mut i32) = parameter(0) + parameter(1) + parameter(2);
+            *(*returns.add(1) as *mut u8) = crate::callbacks::parameter_supplied(1) as u8;
+        }
+    }
+
+    /// Returns a function with the given parameter descriptors (see RegisterFunction),
+    /// which must be three ints, whose callback is sum.
+    fn sum_function(parameters: [(&str, &str); 3]) -> crate::callbacks::Function {
+        let parameters = parameters.iter()
+            .map(|(name, descriptor)| (name.to_string(), crate::parse_type_descriptor(descriptor, &IndexMap::new()).unwrap()))
+            .collect();
+        let returns = [("sum", "int"), ("y_supplied", "bool")].iter().map(|(name, r#type)| (name.to_string(), parse(r#type))).collect();
+        crate::callbacks::Function::new(parameters, returns, crate::callbacks::FunctionCallback::Void(sum)).unwrap()
+    }
+
+    /// Calls a function with the given parameters (a JSON object), and returns its returns as JSON,
+    /// in order, or why the call failed.
+    fn call(function: &crate::callbacks::Function, parameters: &str, lenient: bool) -> Result<String, String> {
+        let parameters = serde_json::from_str::<HashMap<String, Box<RawValue>>>(parameters).unwrap();
+        let returns = function.call(&parameters, lenient).map_err(|e| e.to_string())?;
+        Ok(function.returns.keys().map(|name| returns[name].get()).collect::<Vec<_>>().join(", "))
+    }
+
+    #[test]
+    fn parameters_are_checked_strictly_unless_lenient() {
+        let function = sum_function([("x", "int"), ("y", "int"), ("z", "int")]);
+        assert_eq!(call(&function, r#"{"x": 1, "y": 2, "z": 3}"#, false).as_deref(), Ok("6, true"));
+        let error = call(&function, r#"{"x": 1, "y": 2, "z": 3, "w": 4, "v": 5}"#, false).unwrap_err();
+        assert_eq!(error, r#"invalid parameters: unknown parameter "v"; unknown parameter "w""#);
+        assert_eq!(call(&function, r#"{"x": 1, "y": 2, "z": 3, "w": 4}"#, true).as_deref(), Ok("6, true"));
+        // Missing and invalid parameters are errors even when lenient, and are all reported.
+        let error = call(&function, r#"{"x": 1.5, "w": 4}"#, true).unwrap_err();
+        assert!(error.starts_with(r#"invalid parameters: parameter "x" is not a valid int: "#), "{}", error);
+        assert!(error.ends_with(r#"; missing parameter "y"; missing parameter "z""#), "{}", error);
+    }
+
+    #[test]
+    fn optional_and_default_parameters_may_be_omitted() {
+        let function = sum_function([("x", "int"), ("y", "int = 5"), ("z", "int optional")]);
+        assert_eq!(call(&function, r#"{"x": 1, "y": 2, "z": 3}"#, false).as_deref(), Ok("6, true"));
+        // The default is used for y, and the callback receives NULL for z.
+        assert_eq!(call(&function, r#"{"x": 1}"#, false).as_deref(), Ok("6, false"));
+        // A supplied value is checked even if it is the default.
+        assert_eq!(call(&function, r#"{"x": 1, "y": 5}"#, false).as_deref(), Ok("6, true"));
+        assert!(call(&function, r#"{"x": 1, "z": "3"}"#, false).is_err());
+        assert!(call(&function, r#"{"y": 2}"#, false).unwrap_err().contains(r#"missing parameter "x""#));
+        // Defaults must be valid for their type.
+        let parameters = [("x".to_owned(), crate::parse_type_descriptor("int = 1.5", &IndexMap::new()).unwrap())].into_iter().collect();
+        assert!(crate::callbacks::Function::new(parameters, IndexMap::new(), crate::callbacks::FunctionCallback::Void(sum)).is_err());
+        assert!(crate::parse_type_descriptor("int = nope", &IndexMap::new()).is_err());
+    }
+
+    #[test]
+    fn integers_must_fit_their_type() {
+        for (r#type, min, max, below, above) in [
+            ("ubyte", "0", "255", "-1", "256"),
+            ("u16", "0", "65535", "-1", "65536"),
+            ("uint", "0", "4294967295", "-1", "4294967296"),
+            ("ulong", "0", "18446744073709551615", "-1", "18446744073709551616"),
+            ("i8", "-128", "127", "-129", "128"),
+            ("long", "-9223372036854775808", "9223372036854775807", "-9223372036854775809", "9223372036854775808"),
+        ] {
+            let r#type = parse(r#type);
+            assert!(input(&r#type, min).is_ok() && input(&r#type, max).is_ok(), "{}", r#type);
+            assert!(input(&r#type, below).is_err() && input(&r#type, above).is_err(), "{}", r#type);
+            assert!(input(&r#type, "1.5").is_err() && input(&r#type, "\"1\"").is_err(), "{}", r#type);
+        }
+        let im = input(&parse("ulong"), "18446744073709551615").unwrap();
+        assert_eq!(unsafe { *(im.data() as *const u64) }, u64::MAX);
+        let json = output(&parse("u32"), |data| unsafe { *(data as *mut u32) = u32::MAX });
+        assert_eq!(json.as_deref(), Ok("4294967295"));
+    }
+
+    #[test]
+    fn integer_arrays_must_fit_their_type() {
+        let r#type = parse("u16[]");
+        let im = input(&r#type, "[0, 65535]").unwrap();
+        let inner = unsafe { &*(im.data() as *const InputArrayMarshallInner) };
+        assert_eq!(unsafe { std::slice::from_raw_parts(inner.data as *const u16, inner.length as usize) }, [0, 65535]);
+        assert!(input(&r#type, "[0, 65536]").is_err());
+        assert!(input(&r#type, "[0, -1]").is_err());
+        assert!(input(&r#type, "{}").is_err());
+
+        let values = [1u16, 65535];
+        let json = output(&r#type, |data| unsafe {
+            let inner = &mut *(data as *mut OutputArrayMarshallInner);
+            inner.length = 2;
+            inner.data = values.as_ptr() as *mut c_void;
+        });
+        assert_eq!(json.as_deref(), Ok("[1,65535]"));
+        let json = output(&r#type, |data| unsafe { (*(data as *mut OutputArrayMarshallInner)).length = -1 });
+        assert!(json.is_err());
+    }
+
+    #[test]
+    fn bytes_are_base64() {
+        let r#type = parse("bytes");
+        // With an escaped /, as some encoders emit.
+        for json in [r#""AP8/""#, r#""AP8\/""#] {
+            let im = input(&r#type, json).unwrap();
+            let inner = unsafe { &*(im.data() as *const InputArrayMarshallInner) };
+            assert_eq!(unsafe { std::slice::from_raw_parts(inner.data as *const u8, inner.length as usize) }, [0x00, 0xFF, 0x3F]);
+        }
+        for json in [r#""A""#, r#""A*8/""#, "[0, 255, 63]", "null"] {
+            assert!(input(&r#type, json).is_err(), "{}", json);
+        }
+
+        let values = [0x00u8, 0xFF, 0x3F];
+        let json = output(&r#type, |data| unsafe {
+            let inner = &mut *(data as *mut OutputArrayMarshallInner);
+            inner.length = 3;
+            inner.data = values.as_ptr() as *mut c_void;
+        });
+        assert_eq!(json.as_deref(), Ok(r#""AP8/""#));
+        assert_eq!(output(&r#type, |_| ()).as_deref(), Ok(r#""""#));
+    }
+
+    #[test]
+    fn enums_are_their_values_names() {
+        let r#type = Type::Enum(Arc::new(EnumType { name: "level".to_owned(), values: vec!["low".to_owned(), "high".to_owned()] }));
+        let im = input(&r#type, r#""high""#).unwrap();
+        assert_eq!(unsafe { *(im.data() as *const i32) }, 1);
+        for json in [r#""medium""#, r#""HIGH""#, "1", "null"] {
+            assert!(input(&r#type, json).is_err(), "{}", json);
+        }
+
+        assert_eq!(output(&r#type, |data| unsafe { *(data as *mut i32) = 0 }).as_deref(), Ok(r#""low""#));
+        // The callback must write a valid index (and the initial one is not).
+        assert!(output(&r#type, |_| ()).is_err());
+        assert!(output(&r#type, |data| unsafe { *(data as *mut i32) = 2 }).is_err());
+    }
+
+    #[test]
+    fn maps_round_trip() {
+        let r#type = parse("map<string, double>");
+        let im = input(&r#type, r#"{"kp": 1.5, "ki": 0}"#).unwrap();
+        let inner = unsafe { &*(im.data() as *const InputMapMarshallInner) };
+        let keys = unsafe { std::slice::from_raw_parts(inner.keys, inner.length as usize) };
+        let keys: Vec<_> = keys.iter().map(|&key| unsafe { CStr::from_ptr(key) }.to_str().unwrap()).collect();
+        assert_eq!(keys, ["kp", "ki"]);
+        assert_eq!(unsafe { std::slice::from_raw_parts(inner.values as *const f64, 2) }, [1.5, 0.0]);
+        for json in [r#"{"kp": "1.5"}"#, r#"[1.5]"#, "null"] {
+            assert!(input(&r#type, json).is_err(), "{}", json);
+        }
+        assert!(input(&parse("map<string, ubyte>"), r#"{"a": 256}"#).is_err());
+
+        /// Has the callback return the given keys, with 1.0, 2.0, and so on.
+        fn returned(r#type: &Type, keys: &[&CStr]) -> Result<String, String> {
+            let mut keys: Vec<_> = keys.iter().map(|key| OutputStringMarshall { string: key.as_ptr(), release: None }).collect();
+            let mut values: Vec<f64> = (1..=keys.len()).map(|i| i as f64).collect();
+            let json = output(r#type, |data| unsafe {
+                let inner = &mut *(data as *mut OutputMapMarshallInner);
+                inner.length = keys.len() as i32;
+                inner.keys = keys.as_mut_ptr();
+                inner.values = values.as_mut_ptr() as *mut c_void;
+            });
+            // The marshall dropped the keys and values in place, and they are not dropped again.
+            unsafe { keys.set_len(0) };
+            json
+        }
+        assert_eq!(returned(&r#type, &[c"kp", c"ki"]).as_deref(), Ok(r#"{"kp":1.0,"ki":2.0}"#));
+        assert_eq!(returned(&r#type, &[]).as_deref(), Ok("{}"));
+        assert!(returned(&r#type, &[c"kp", c"kp"]).unwrap_err().contains("duplicate key"));
+    }
+
+    #[test]
+    fn nullables_are_null_or_present() {
+        let r#type = parse("double?");
+        let im = input(&r#type, "null").unwrap();
+        let inner = unsafe { &*(im.data() as *const InputNullableMarshallInner) };
+        assert!(!inner.present && inner.value.is_null());
+        let im = input(&r#type, "2.5").unwrap();
+        let inner = unsafe { &*(im.data() as *const InputNullableMarshallInner) };
+        assert!(inner.present && unsafe { *(inner.value as *const f64) } == 2.5);
+        assert!(input(&r#type, r#""2.5""#).is_err());
+        // Only nullable types accept null.
+        assert!(input(&parse("double"), "null").is_err());
+        assert!(input(&parse("string"), "null").is_err());
+
+        // The value is null unless the callback sets present.
+        assert_eq!(output(&r#type, |_| ()).as_deref(), Ok("null"));
+        let json = output(&r#type, |data| unsafe {
+            let inner = &mut *(data as *mut OutputNullableMarshallInner);
+            *(inner.value as *mut f64) = 2.5;
+            inner.present = OutputBoolMarshall(1);
+        });
+        assert_eq!(json.as_deref(), Ok("2.5"));
+        // Even for a type whose value could not be serialized without being set.
+        assert_eq!(output(&parse("string?"), |_| ()).as_deref(), Ok("null"));
+        assert!(output(&parse("string"), |_| ()).is_err());
+    }
+}
@@ -0,0 +1,1239 @@
+//! Sending stream data to the server.
+//!
+//! Each stream has a queue of frames, which is filled by a producer (a thread reading the
+//! stream's fd, or the application through StreamWrite and StreamWriteFrame) and drained by
+//! a thread writing to the server's stream port.
+//! If the server falls behind, the queue applies the stream's buffer method.
+//! Each frame is sent with a FrameHeader (see common::message).
+//! A StreamWorker owns a stream's threads: it records why the stream failed, if it did, and
+//! interrupts and joins the threads when it is dropped.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{ErrorKind, PipeWriter, Read, Write};
+use std::net::{Shutdown, TcpStream, UdpSocket};
+use std::os::unix::prelude::{AsRawFd, FromRawFd};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use libc::c_char;
+use common::message::{BufferMethod, FrameHeader, SampleFormat, StreamFormat, Transport, fragment_frame, write_frame};
+use crate::callbacks::Stream;
+
+/// A frame that has not ended after this many bytes is queued anyway, so that a stream that
+/// is not in the format it claims cannot use unbounded memory.
+const MAX_FRAME_LENGTH: usize = 16 * 1024 * 1024;
+
+/// What to do with a stream's data when the server falls behind (see RegisterStreamEx).
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+#[allow(clippy::enum_variant_names)] // C enumerators share a namespace with everything else.
+pub enum StreamBufferMethod {
+    /// Wait for the server. No data is discarded.
+    BufferNoDiscard = 0,
+    /// Buffer up to buffer_size frames, then discard the oldest.
+    BufferFrames = 1,
+    /// Buffer up to buffer_size bytes (but at least one frame), then discard the oldest frames.
+    BufferBytes = 2,
+}
+
+impl From<StreamBufferMethod> for BufferMethod {
+    fn from(buffer_method: StreamBufferMethod) -> Self {
+        match buffer_method {
+            StreamBufferMethod::BufferNoDiscard => BufferMethod::NoDiscard,
+            StreamBufferMethod::BufferFrames => BufferMethod::Frames,
+            StreamBufferMethod::BufferBytes => BufferMethod::Bytes,
+        }
+    }
+}
+
+/// How a stream's data is sent to the server (see SetStreamTransport).
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+#[allow(clippy::enum_variant_names)] // C enumerators share a namespace with everything else.
+pub enum StreamTransport {
+    /// Over a TCP connection. No data is lost, but a lost packet delays the rest of the stream.
+    TransportTcp = 0,
+    /// As UDP datagrams. A lost datagram loses its frame, but does not delay the rest of the stream.
+    TransportUdp = 1,
+}
+
+impl From<StreamTransport> for Transport {
+    fn from(transport: StreamTransport) -> Self {
+        match transport {
+            StreamTransport::TransportTcp => Transport::Tcp,
+            StreamTransport::TransportUdp => Transport::Udp,
+        }
+    }
+}
+
+/// A frame waiting to be sent to the server.
+struct Frame {
+    header: FrameHeader,
+    data: Vec<u8>,
+}
+
+/// Whether a stream is being sent to the server, as controlled by the server (see SetStreamControlCallback).
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[allow(clippy::enum_variant_names)] // C enumerators share a namespace with everything else.
+pub enum StreamState {
+    /// The stream's data is being sent. Streams start in this state.
+    #[default]
+    StreamStarted = 0,
+    /// The stream's data is not being sent, and what has been buffered is kept for when it resumes.
+    /// With BufferNoDiscard, the stream's fd is not read (and StreamWrite returns StreamIsPaused)
+    /// until it resumes; otherwise, the stream's data is discarded until it resumes.
+    StreamPaused = 1,
+    /// The stream's data (including what has been buffered) is discarded, e.g. because nobody is
+    /// watching it, until it is started again, from its next keyframe.
+    StreamStopped = 2,
+}
+
+/// Returns the monotonic clock (CLOCK_MONOTONIC) in microseconds, which frame timestamps use.
+pub(crate) fn clock() -> u64 {
+    let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // Cannot fail with a valid clock and pointer.
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time) };
+    time.tv_sec as u64 * 1_000_000 + time.tv_nsec as u64 / 1_000
+}
+
+/// The layout of a sample of a pcm stream (see RegisterPcmStream).
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+#[allow(clippy::enum_variant_names)] // C enumerators share a namespace with everything else.
+pub enum PcmSampleFormat {
+    /// Unsigned 8-bit
+    SampleU8 = 0,
+    /// Signed 16-bit little-endian
+    SampleS16LE = 1,
+    /// Signed 16-bit big-endian
+    SampleS16BE = 2,
+    /// Signed 32-bit little-endian
+    SampleS32LE = 3,
+    /// Signed 32-bit big-endian
+    SampleS32BE = 4,
+    /// 32-bit float little-endian
+    SampleF32LE = 5,
+    /// 32-bit float big-endian
+    SampleF32BE = 6,
+}
+
+impl From<PcmSampleFormat> for SampleFormat {
+    fn from(sample_format: PcmSampleFormat) -> Self {
+        match sample_format {
+            PcmSampleFormat::SampleU8 => SampleFormat::U8,
+            PcmSampleFormat::SampleS16LE => SampleFormat::S16le,
+            PcmSampleFormat::SampleS16BE => SampleFormat::S16be,
+            PcmSampleFormat::SampleS32LE => SampleFormat::S32le,
+            PcmSampleFormat::SampleS32BE => SampleFormat::S32be,
+            PcmSampleFormat::SampleF32LE => SampleFormat::F32le,
+            PcmSampleFormat::SampleF32BE => SampleFormat::F32be,
+        }
+    }
+}
+
+#[derive(Default)]
+struct QueueState {
+    frames: VecDeque<Frame>,
+    /// The total length of the queued frames.
+    bytes: usize,
+    /// The sequence number of the next frame pushed.
+    next_sequence: u64,
+    stream_state: StreamState,
+    /// Set when the stream is stopped, so that it starts again from a keyframe.
+    awaiting_keyframe: bool,
+    /// The number of frames discarded by the buffer method, or because the stream was not started,
+    /// or that could not be sent (see record_dropped).
+    frames_dropped: u64,
+    closed: bool,
+}
+
+/// What StreamQueue::push did with a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Pushed {
+    /// The frame was queued, or discarded by the buffer method or because the stream is not started.
+    Queued,
+    /// The frame was not queued, because the stream is paused (only with NoDiscard, without waiting).
+    Paused,
+    /// The frame was not queued, because the queue is closed.
+    Closed,
+}
+
+/// The frames of a stream that have not yet been sent to the server.
+pub(crate) struct StreamQueue {
+    buffer_method: BufferMethod,
+    buffer_size: usize,
+    state: Mutex<QueueState>,
+    /// Notified when a frame is queued, or the queue is closed.
+    queued: Condvar,
+    /// Notified when a frame is taken from the queue, or the queue is closed.
+    taken: Condvar,
+}
+
+impl StreamQueue {
+    pub(crate) fn new(buffer_method: BufferMethod, buffer_size: usize) -> Self {
+        Self {
+            buffer_method,
+            buffer_size,
+            state: Default::default(),
+            queued: Condvar::new(),
+            taken: Condvar::new(),
+        }
+    }
+
+    /// Queues a frame. With NoDiscard, first waits until the frames already queued have been
+    /// taken, and (if wait_while_paused) the stream is not paused; otherwise, a paused stream's frame
+    /// is not queued, so that an application writing the stream's data is not blocked until the
+    /// server resumes it. With other buffer methods, discards the oldest frames until there are
+    /// at most buffer_size frames (Frames) or bytes (Bytes) queued, except that the newest frame
+    /// is never discarded. Discards the frame instead if the stream is not started (see StreamState).
+    pub(crate) fn push(&self, data: Vec<u8>, flags: u64, timestamp: u64, wait_while_paused: bool) -> Pushed {
+        let mut state = self.state.lock().unwrap();
+        if self.buffer_method == BufferMethod::NoDiscard {
+            state = self.taken.wait_while(state, |state| {
+                let paused = state.stream_state == StreamState::StreamPaused;
+                !state.closed && (paused && wait_while_paused || !paused && !state.frames.is_empty())
+            }).unwrap();
+            if !state.closed && state.stream_state == StreamState::StreamPaused {
+                return Pushed::Paused;
+            }
+        }
+        if state.closed {
+            return Pushed::Closed;
+        }
+        let header = FrameHeader { length: data.len() as u64, sequence: state.next_sequence, timestamp, flags };
+        state.next_sequence += 1;
+        if state.stream_state != StreamState::StreamStarted {
+            state.frames_dropped += 1;
+            return Pushed::Queued;
+        }
+        if state.awaiting_keyframe {
+            if flags & FrameHeader::FLAG_KEYFRAME == 0 {
+                state.frames_dropped += 1;
+                return Pushed::Queued;
+            }
+            state.awaiting_keyframe = false;
+        }
+        state.bytes += data.len();
+        state.frames.push_back(Frame { header, data });
+        while state.frames.len() > 1 && match self.buffer_method {
+            BufferMethod::Frames => state.frames.len() > self.buffer_size,
+            BufferMethod::Bytes => state.bytes > self.buffer_size,
+            BufferMethod::NoDiscard => false,
+        } {
+            let discarded = state.frames.pop_front().unwrap();
+            state.bytes -= discarded.data.len();
+            state.frames_dropped += 1;
+        }
+        self.queued.notify_one();
+        Pushed::Queued
+    }
+
+    /// Takes the oldest frame, waiting until there is one, and the stream is started.
+    /// Returns None once the queue is closed and empty. Frames queued before it was closed are kept
+    /// while the stream is paused, until it is resumed (or stopped, which discards them).
+    fn pop(&self) -> Option<Frame> {
+        let state = self.state.lock().unwrap();
+        let mut state = self.queued.wait_while(state, |state| match state.frames.is_empty() {
+            true => !state.closed,
+            false => state.stream_state != StreamState::StreamStarted,
+        }).unwrap();
+        let frame = state.frames.pop_front()?;
+        state.bytes -= frame.data.len();
+        self.taken.notify_one();
+        Some(frame)
+    }
+
+    /// Changes the stream's state, discarding the queued frames if it is stopped.
+    /// Returns whether the state changed.
+    pub(crate) fn set_stream_state(&self, stream_state: StreamState) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.stream_state == stream_state {
+            return false;
+        }
+        if stream_state == StreamState::StreamStopped {
+            state.frames_dropped += state.frames.len() as u64;
+            state.frames.clear();
+            state.bytes = 0;
+            state.awaiting_keyframe = true;
+        }
+        state.stream_state = stream_state;
+        self.queued.notify_all();
+        self.taken.notify_all();
+        true
+    }
+
+    /// Counts a frame that was taken from the queue, but could not be sent.
+    pub(crate) fn record_dropped(&self) {
+        self.state.lock().unwrap().frames_dropped += 1;
+    }
+
+    /// Closes the queue. Frames that are already queued can still be taken.
+    pub(crate) fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.queued.notify_all();
+        self.taken.notify_all();
+    }
+
+    fn counts(&self) -> QueueCounts {
+        let state = self.state.lock().unwrap();
+        QueueCounts {
+            queued: state.frames.len() as u64,
+            pushed: state.next_sequence,
+            dropped: state.frames_dropped,
+            state: state.stream_state,
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    /// Closes the queue, and discards the frames that are already queued.
+    fn abort(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.frames.clear();
+        state.bytes = 0;
+        self.queued.notify_all();
+        self.taken.notify_all();
+    }
+}
+
+/// A snapshot of a StreamQueue's counters.
+struct QueueCounts {
+    /// The number of frames queued.
+    queued: u64,
+    /// The number of frames pushed, including those that were dropped.
+    pushed: u64,
+    /// The number of frames dropped (see QueueState::frames_dropped).
+    dropped: u64,
+    state: StreamState,
+}
+
+/// Where a Framer is in the frame it is parsing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ParseState {
+    /// Looking for the start of the next frame.
+    Start,
+    /// (mjpeg) At a marker, before the image's entropy-coded data or between its scans.
+    Segments,
+    /// (mjpeg) In entropy-coded data, which ends at the next marker other than RSTn.
+    EntropyCoded,
+    /// (h264) In an access unit, which ends at the next NAL unit that begins another one, once it has
+    /// a slice of its picture (has_slice).
+    AccessUnit { has_slice: bool },
+}
+
+/// Splits a stream's data into frames according to its format, so that buffer methods discard
+/// whole frames.
+///
+/// For mjpeg, each frame is one JPEG image, from its SOI marker to its EOI marker. The marker
+/// segments are skipped by their length, so markers inside them (e.g. of an EXIF thumbnail) do
+/// not end the image. Data between images, such as multipart boundaries and part headers, is
+/// discarded, as is an image that turns out not to be valid.
+///
+/// For h264, each frame is one access unit (a picture, with the parameter sets and SEI before it),
+/// from its first start code to the start code of the next one, so a frame is complete once the
+/// next one starts. Data before the first start code is discarded.
+///
+/// For pcm, each frame is the whole samples of a read (or StreamWrite), and the rest of the
+/// data is kept for the next one.
+///
+/// For ndjson, each frame is one record, with its newline. Blank lines are discarded.
+pub(crate) struct Framer {
+    format: StreamFormat,
+    /// For pcm, the length of one sample for every channel.
+    sample_frame_length: usize,
+    /// Data that has been read, but does not yet end a frame.
+    pending: Vec<u8>,
+    /// How much of pending has already been parsed.
+    position: usize,
+    state: ParseState,
+}
+
+impl Framer {
+    pub(crate) fn new(stream: &Stream) -> Self {
+        Self {
+            format: stream.format,
+            sample_frame_length: stream.pcm.map_or(1, |pcm| pcm.frame_length()),
+            pending: vec![],
+            position: 0,
+            state: ParseState::Start,
+        }
+    }
+
+    /// Adds data read from the stream, and returns the frames that it completed.
+    pub(crate) fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        self.pending.extend_from_slice(data);
+        let mut frames = vec![];
+        loop {
+            let end = match self.format {
+                StreamFormat::Mjpeg => self.jpeg_end(),
+                StreamFormat::H264 => self.access_unit_end(),
+                StreamFormat::Pcm => Some(self.pending.len() - self.pending.len() % self.sample_frame_length)
+                    .filter(|&end| end > 0),
+                StreamFormat::Ndjson => self.record_end(),
+            };
+            let Some(end) = end else { break };
+            let rest = self.pending.split_off(end);
+            let frame = std::mem::replace(&mut self.pending, rest);
+            self.position = 0;
+            self.state = ParseState::Start;
+            if self.format != StreamFormat::Ndjson || !frame.trim_ascii().is_empty() {
+                frames.push(frame);
+            }
+        }
+        if self.pending.len() >= MAX_FRAME_LENGTH {
+            frames.extend(self.finish());
+        }
+        frames
+    }
+
+    /// Returns the flags for a frame's header.
+    pub(crate) fn flags(&self, frame: &[u8]) -> u64 {
+        let keyframe = match self.format {
+            // An IDR picture's slices are NAL units of type 5, and its access unit has the parameter sets.
+            StreamFormat::H264 => start_codes(frame).any(|start| frame.get(start).is_some_and(|header| header & 0x1F == 5)),
+            StreamFormat::Mjpeg | StreamFormat::Pcm | StreamFormat::Ndjson => true,
+        };
+        if keyframe { FrameHeader::FLAG_KEYFRAME } else { 0 }
+    }
+
+    /// Parses pending from position, and returns where the image in it ends, if it does.
+    /// Discards the data before the image.
+    fn jpeg_end(&mut self) -> Option<usize> {
+        loop {
+            let rest = &self.pending[self.position..];
+            match self.state {
+                ParseState::Start => match rest.windows(2).position(|marker| marker == [0xFF, 0xD8]) {
+                    Some(start) => {
+                        self.pending.drain(..self.position + start);
+                        self.position = 2;
+                        self.state = ParseState::Segments;
+                    }
+                    None => {
+                        // Keeps a final 0xFF, which may begin an SOI marker.
+                        let discard = self.pending.len() - usize::from(self.pending.last() == Some(&0xFF));
+                        self.pending.drain(..discard);
+                        self.position = 0;
+                        return None;
+                    }
+                },
+                ParseState::Segments => {
+                    // A marker is 0xFF (possibly repeated as fill) and a nonzero code.
+                    let fill = rest.iter().take_while(|&&byte| byte == 0xFF).count();
+                    let &code = rest.get(fill)?;
+                    if fill == 0 || code == 0x00 || code == 0xD8 {
+                        // Not a valid image, so look for the next one.
+                        self.state = ParseState::Start;
+                        continue;
+                    }
+                    let after = self.position + fill + 1;
+                    match code {
+                        // EOI
+                        0xD9 => return Some(after),
+                        // TEM and RSTn have no segment.
+                        0x01 | 0xD0..=0xD7 => self.position = after,
+                        _ => {
+                            let length = self.pending.get(after..after + 2)?;
+                            // The length includes itself, but not the marker.
+                            let length = usize::from(u16::from_be_bytes([length[0], length[1]]));
+                            if length < 2 {
+                                self.state = ParseState::Start;
+                                continue;
+                            }
+                            if self.pending.len() < after + length {
+                                return None;
+                            }
+                            self.position = after + length;
+                            // SOS
+                            if code == 0xDA {
+                                self.state = ParseState::EntropyCoded;
+                            }
+                        }
+                    }
+                }
+                ParseState::EntropyCoded => {
+                    // 0xFF in entropy-coded data is followed by 0x00, or RSTn.
+                    match rest.windows(2).position(|bytes| {
+                        bytes[0] == 0xFF && !matches!(bytes[1], 0x00 | 0xD0..=0xD7 | 0xFF)
+                    }) {
+                        Some(marker) => {
+                            self.position += marker;
+                            self.state = ParseState::Segments;
+                        }
+                        None => {
+                            // Keeps a final 0xFF, which may begin a marker.
+                            self.position = self.position.max(self.pending.len().saturating_sub(1));
+                            return None;
+                        }
+                    }
+                }
+                ParseState::AccessUnit { .. } => unreachable!("h264 state in mjpeg stream"),
+            }
+        }
+    }
+
+    /// Parses pending from position, and returns where the access unit at its start ends, if it does.
+    /// Discards the data before the first start code.
+    fn access_unit_end(&mut self) -> Option<usize> {
+        if self.state == ParseState::Start {
+            match self.pending.windows(3).position(|start_code| start_code == [0, 0, 1]) {
+                Some(start) => {
+                    // Keeps the zeros before the start code (as in a 4-byte start code).
+                    let zeros = self.pending[..start].iter().rev().take_while(|&&byte| byte == 0).count();
+                    self.pending.drain(..start - zeros);
+                    self.position = zeros;
+                    self.state = ParseState::AccessUnit { has_slice: false };
+                }
+                None => {
+                    // Keeps final zeros, which may begin a start code.
+                    let zeros = self.pending.iter().rev().take_while(|&&byte| byte == 0).count().min(3);
+                    self.pending.drain(..self.pending.len() - zeros);
+                    return None;
+                }
+            }
+        }
+        let ParseState::AccessUnit { mut has_slice } = self.state else { unreachable!("non-h264 state in h264 stream") };
+        loop {
+            let Some(next) = self.pending[self.position..].windows(3).position(|start_code| start_code == [0, 0, 1]) else {
+                self.position = self.position.max(self.pending.len().saturating_sub(2));
+                return None;
+            };
+            let start_code = self.position + next;
+            let header = start_code + 3;
+            let Some(nal_unit_type) = self.pending.get(header).map(|header| header & 0x1F) else {
+                self.position = start_code;
+                return None;
+            };
+            // As in section 7.4.1.2.3 of the H.264 spec. A picture's first slice is the one with
+            // first_mb_in_slice = 0, which is coded as a single 1 bit.
+            let begins_access_unit = match nal_unit_type {
+                // SEI, SPS, PPS, access unit delimiter, and types 14 to 18
+                6..=9 | 14..=18 => true,
+                // Slices (and data partition A, which has the slice header)
+                1 | 2 | 5 => match self.pending.get(header + 1) {
+                    Some(first_byte) => first_byte & 0x80 != 0,
+                    None => {
+                        self.position = start_code;
+                        return None;
+                    }
+                },
+                _ => false,
+            };
+            if has_slice && begins_access_unit {
+                // A NAL unit does not end with a zero byte, so zeros before the next start code
+                // belong to it (as in a 4-byte start code). This stops at the 1 of the first start code.
+                let mut end = start_code;
+                while self.pending[end - 1] == 0 {
+                    end -= 1;
+                }
+                return Some(end);
+            }
+            has_slice |= (1..=5).contains(&nal_unit_type);
+            self.state = ParseState::AccessUnit { has_slice };
+            self.position = header;
+        }
+    }
+
+    /// Returns where the first record in pending ends (after its newline), if it does.
+    fn record_end(&mut self) -> Option<usize> {
+        match self.pending[self.position..].iter().position(|&byte| byte == b'\n') {
+            Some(newline) => Some(self.position + newline + 1),
+            None => {
+                self.position = self.pending.len();
+                None
+            }
+        }
+    }
+
+    /// Returns the data that did not end a frame (e.g. at the end of the fd), if any.
+    pub(crate) fn finish(&mut self) -> Option<Vec<u8>> {
+        let pending = std::mem::take(&mut self.pending);
+        let state = std::mem::replace(&mut self.state, ParseState::Start);
+        self.position = 0;
+        let complete = match self.format {
+            // Incomplete, but still worth decoding.
+            StreamFormat::Mjpeg => state != ParseState::Start,
+            // Ends at the end of the data.
+            StreamFormat::H264 => matches!(state, ParseState::AccessUnit { .. }),
+            // Not even a whole sample.
+            StreamFormat::Pcm => false,
+            StreamFormat::Ndjson => !pending.trim_ascii().is_empty(),
+        };
+        Some(pending).filter(|pending| complete && !pending.is_empty())
+    }
+}
+
+/// Returns the positions after the start codes in h264 data.
+fn start_codes(data: &[u8]) -> impl Iterator<Item = usize> + '_ {
+    data.windows(3).enumerate().filter(|(_, start_code)| *start_code == [0, 0, 1]).map(|(start, _)| start + 3)
+}
+
+/// Where the application writes the data of a stream without an fd.
+pub(crate) struct PushSource {
+    queue: Arc<StreamQueue>,
+    /// Also serializes writes, so that frames are queued in the order they were written.
+    framer: Mutex<Framer>,
+}
+
+impl PushSource {
+    /// Splits data into frames, as if it had been read from an fd, and queues the complete ones,
+    /// captured now, without waiting while the stream is paused (see StreamQueue::push).
+    /// If a frame is not queued, neither are the frames after it.
+    pub(crate) fn write(&self, data: &[u8]) -> Pushed {
+        let timestamp = clock();
+        let mut framer = self.framer.lock().unwrap();
+        for frame in framer.push(data) {
+            let flags = framer.flags(&frame);
+            match self.queue.push(frame, flags, timestamp, false) {
+                Pushed::Queued => {}
+                pushed => return pushed,
+            }
+        }
+        Pushed::Queued
+    }
+
+    /// Queues data as one frame, captured at timestamp (see clock).
+    pub(crate) fn write_frame(&self, frame: &[u8], timestamp: u64) -> Pushed {
+        let framer = self.framer.lock().unwrap();
+        self.queue.push(frame.to_vec(), framer.flags(frame), timestamp, false)
+    }
+}
+
+/// What the application writes the data of a stream without an fd through (see GetStreamWriter).
+/// It does not borrow the client handle, so it may be used from any thread.
+pub struct StreamWriter {
+    pub(crate) name: String,
+    pub(crate) source: Arc<PushSource>,
+}
+
+/// The default number of bytes that may be waiting in a rate controlled stream's socket
+/// (see SetStreamRateControl).
+pub(crate) const DEFAULT_MAX_UNSENT: usize = 64 * 1024;
+
+/// How often a rate controlled stream's writer checks whether its socket has drained.
+const RATE_CONTROL_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Returns the number of bytes in a socket that the server has not yet acknowledged, if the
+/// platform can tell.
+#[cfg(target_os = "linux")]
+fn unsent_bytes(socket: &impl AsRawFd) -> Option<usize> {
+    let mut unsent: libc::c_int = 0;
+    (unsafe { libc::ioctl(socket.as_raw_fd(), libc::TIOCOUTQ, &mut unsent) } == 0).then_some(unsent as usize)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsent_bytes(_socket: &impl AsRawFd) -> Option<usize> {
+    None
+}
+
+/// Adapts a stream to the rate at which the server receives it (see SetStreamRateControl).
+///
+/// Frames are only written while fewer than max_unsent bytes are waiting in the socket, so that
+/// when the connection is slower than the producer, frames wait in the stream's queue (where the
+/// buffer method discards whole frames) rather than in the socket (where they only add latency).
+/// Once per RATE_INTERVAL, if the stream was congested (the writer had to wait, or frames were
+/// discarded), the rate at which the server received the stream becomes the producer's target.
+/// Otherwise, an existing target is raised by a quarter to probe for more bandwidth, until the
+/// producer no longer uses it, when the target is removed.
+struct RateControl {
+    max_unsent: usize,
+    /// When the current interval started, and the counts at that time.
+    interval_start: Instant,
+    delivered_at_start: u64,
+    pushed_at_start: u64,
+    dropped_at_start: u64,
+    sent_at_start: u64,
+    data_sent_at_start: u64,
+    frames_sent_at_start: u64,
+    /// Whether the writer has had to wait for the socket to drain during the current interval.
+    waited: bool,
+    /// The current target bitrate and frame rate, or None if the producer is not limited.
+    target: Option<(f64, f64)>,
+}
+
+impl RateControl {
+    fn new(max_unsent: usize) -> Self {
+        Self {
+            max_unsent,
+            interval_start: Instant::now(),
+            delivered_at_start: 0,
+            pushed_at_start: 0,
+            dropped_at_start: 0,
+            sent_at_start: 0,
+            data_sent_at_start: 0,
+            frames_sent_at_start: 0,
+            waited: false,
+            target: None,
+        }
+    }
+
+    /// Waits until fewer than max_unsent bytes are waiting in the socket, or the queue is closed.
+    fn wait(&mut self, socket: &impl AsRawFd, queue: &StreamQueue, stats: &Mutex<WorkerStats>) {
+        loop {
+            let unsent = unsent_bytes(socket).unwrap_or(0);
+            self.update(unsent, queue, stats);
+            if unsent < self.max_unsent || queue.is_closed() {
+                return;
+            }
+            self.waited = true;
+            std::thread::sleep(RATE_CONTROL_POLL_INTERVAL);
+        }
+    }
+
+    /// Updates the target, if the current interval has ended.
+    fn update(&mut self, unsent: usize, queue: &StreamQueue, stats: &Mutex<WorkerStats>) {
+        let elapsed = self.interval_start.elapsed();
+        if elapsed < RATE_INTERVAL {
+            return;
+        }
+        let counts = queue.counts();
+        let mut stats = stats.lock().unwrap();
+        let delivered = stats.bytes_sent.saturating_sub(unsent as u64);
+        let seconds = elapsed.as_secs_f64();
+        let delivered_rate = delivered.saturating_sub(self.delivered_at_start) as f64 / seconds;
+        let pushed_rate = (counts.pushed - self.pushed_at_start) as f64 / seconds;
+        let congested = self.waited || counts.dropped > self.dropped_at_start;
+        // Only this interval's frames, since the producer's frames change length as it adapts.
+        let sent = stats.bytes_sent - self.sent_at_start;
+        let data_sent = stats.data_bytes_sent - self.data_sent_at_start;
+        let frames_sent = stats.frames_sent - self.frames_sent_at_start;
+
+        let target = match self.target {
+            // Nothing has been sent to tell how long a frame is.
+            _ if congested && frames_sent == 0 => self.target,
+            _ if congested => {
+                let frame_length = sent as f64 / frames_sent as f64;
+                // The producer's bitrate is of its data, without the headers sent with it.
+                let data_rate = delivered_rate * data_sent as f64 / sent as f64;
+                Some((data_rate * 8.0, delivered_rate / frame_length))
+            }
+            Some((_, frame_rate)) if pushed_rate < frame_rate * 0.8 => None,
+            Some((bitrate, frame_rate)) => Some((bitrate * 1.25, frame_rate * 1.25)),
+            None => None,
+        };
+        if target != self.target {
+            // No target is signalled as 0.
+            stats.rate_target = Some(target.unwrap_or((0.0, 0.0)));
+            self.target = target;
+        }
+
+        self.interval_start += elapsed;
+        self.delivered_at_start = delivered;
+        self.pushed_at_start = counts.pushed;
+        self.dropped_at_start = counts.dropped;
+        self.sent_at_start = stats.bytes_sent;
+        self.data_sent_at_start = stats.data_bytes_sent;
+        self.frames_sent_at_start = stats.frames_sent;
+        self.waited = false;
+    }
+}
+
+/// Sends a frame of a udp stream as datagrams (see fragment_frame), numbered from *packet_sequence.
+/// Returns the number of bytes sent, including the datagrams' headers.
+fn send_datagrams(socket: &UdpSocket, frame: &Frame, packet_sequence: &mut u64) -> std::io::Result<usize> {
+    fragment_frame(&frame.header, &frame.data, packet_sequence).iter()
+        .try_fold(0, |sent, datagram| Ok(sent + socket.send(datagram)?))
+}
+
+/// Returns whether a udp stream's frame that could not be sent should only be dropped, rather than
+/// fail the stream: the server's port was not open when an earlier datagram arrived (reported on a
+/// later send), or the network's buffers were full.
+fn is_transient_send_error(error: &std::io::Error) -> bool {
+    error.kind() == ErrorKind::ConnectionRefused || error.raw_os_error() == Some(libc::ENOBUFS)
+}
+
+/// What has become of the threads sending a stream to the server (see GetStreamStatus).
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[allow(clippy::enum_variant_names)] // C enumerators share a namespace with everything else.
+pub enum StreamStatus {
+    /// The stream is being sent to the server.
+    #[default]
+    StreamRunning = 0,
+    /// The stream's data has ended (its fd reached end-of-file), and all of it has been sent.
+    StreamEnded = 1,
+    /// Reading the stream's fd, or writing to the server, failed. The stream is no longer sent.
+    StreamFailed = 2,
+}
+
+/// The length of StreamStats::last_error, including the terminating NUL.
+pub const STREAM_ERROR_LENGTH: usize = 256;
+
+/// The send rate is measured over intervals of at least this long.
+const RATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Statistics of a stream being sent to the server (see GetStreamStats).
+#[repr(C)]
+pub struct StreamStats {
+    /// The number of bytes written to the server, including frame headers.
+    pub bytes_sent: u64,
+    /// The number of frames written to the server.
+    pub frames_sent: u64,
+    /// The number of frames discarded by the stream's buffer method, or because the server had
+    /// paused or stopped the stream, or (for udp streams) that could not be sent for the moment.
+    pub frames_dropped: u64,
+    /// The number of frames waiting to be sent.
+    pub frames_queued: u64,
+    /// The number of bytes written to the server per second, over about the last second.
+    pub send_rate: f64,
+    /// Whether the stream is still being sent to the server.
+    pub status: StreamStatus,
+    /// Whether the server has started, paused, or stopped the stream.
+    pub state: StreamState,
+    /// Why the stream failed (truncated if necessary), or an empty string if it has not failed.
+    pub last_error: [c_char; STREAM_ERROR_LENGTH],
+}
+
+/// What has become of a StreamWorker's threads, and what they have sent.
+struct WorkerStats {
+    status: StreamStatus,
+    /// Why the stream failed.
+    error: Option<String>,
+    bytes_sent: u64,
+    /// The length of the data of the frames sent, without their headers.
+    data_bytes_sent: u64,
+    frames_sent: u64,
+    /// When the current send rate interval started, and how many bytes have been sent in it.
+    rate_start: Instant,
+    rate_bytes: u64,
+    /// The send rate over the last complete interval.
+    send_rate: f64,
+    /// A target bitrate and frame rate for the producer (see RateControl), not yet passed to the
+    /// stream's rate callback.
+    rate_target: Option<(f64, f64)>,
+}
+
+impl WorkerStats {
+    fn new() -> Self {
+        Self {
+            status: StreamStatus::StreamRunning,
+            error: None,
+            bytes_sent: 0,
+            data_bytes_sent: 0,
+            frames_sent: 0,
+            rate_start: Instant::now(),
+            rate_bytes: 0,
+            send_rate: 0.0,
+            rate_target: None,
+        }
+    }
+
+    /// Records that a frame of length bytes (including its header), with data_length bytes of data,
+    /// was written to the server.
+    fn sent(&mut self, length: u64, data_length: u64) {
+        self.bytes_sent += length;
+        self.data_bytes_sent += data_length;
+        self.frames_sent += 1;
+        self.rate_bytes += length;
+        let elapsed = self.rate_start.elapsed();
+        if elapsed >= RATE_INTERVAL {
+            self.send_rate = self.rate_bytes as f64 / elapsed.as_secs_f64();
+            self.rate_start += elapsed;
+            self.rate_bytes = 0;
+        }
+    }
+
+    /// Returns the send rate, including the current interval once it is long enough (so that the
+    /// rate drops when nothing is being sent).
+    fn send_rate(&self) -> f64 {
+        let elapsed = self.rate_start.elapsed();
+        if elapsed >= RATE_INTERVAL {
+            self.rate_bytes as f64 / elapsed.as_secs_f64()
+        } else {
+            self.send_rate
+        }
+    }
+
+    /// Records that the stream failed, unless it has already failed or ended.
+    /// Returns whether the error was recorded (and should be reported).
+    fn fail(&mut self, error: String) -> bool {
+        if self.status != StreamStatus::StreamRunning {
+            return false;
+        }
+        self.status = StreamStatus::StreamFailed;
+        self.error = Some(error);
+        true
+    }
+}
+
+/// The threads sending a stream's data to the server: one reading the stream's fd (if it has one),
+/// and one writing the queued frames to the server's stream port.
+/// Dropping a StreamWorker stops its threads, and waits for them to exit.
+pub(crate) struct StreamWorker {
+    queue: Arc<StreamQueue>,
+    /// Where the application writes the stream's data, if it has no fd.
+    push_source: Option<Arc<PushSource>>,
+    stats: Arc<Mutex<WorkerStats>>,
+    /// Written to wake the reader thread, which polls it along with the stream's fd.
+    interrupt: Option<PipeWriter>,
+    /// A clone of the server socket, shut down to wake the writer thread if it is blocked writing.
+    socket: TcpStream,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl StreamWorker {
+    /// Starts sending a stream's data to the server over socket (or for a udp stream, udp_socket),
+    /// until the data ends, reading or writing fails (see status), or the StreamWorker is dropped.
+    pub(crate) fn start(name: &str, stream: &Stream, socket: TcpStream, udp_socket: Option<UdpSocket>) -> std::io::Result<Self> {
+        let queue = Arc::new(StreamQueue::new(stream.buffer_method, stream.buffer_size));
+        let stats = Arc::new(Mutex::new(WorkerStats::new()));
+        let mut threads = Vec::with_capacity(2);
+        let mut push_source = None;
+        let mut interrupt = None;
+
+        let mut framer = Framer::new(stream);
+        match stream.fd {
+            Some(fd) => {
+                let (interrupted, interrupt_writer) = std::io::pipe()?;
+                interrupt = Some(interrupt_writer);
+                let name = name.to_owned();
+                let queue = Arc::clone(&queue);
+                let stats = Arc::clone(&stats);
+                threads.push(std::thread::spawn(move || {
+                    let mut buf = vec![0; 65536];
+                    let mut file = unsafe { File::from_raw_fd(fd) };
+                    let mut poll_fds = [
+                        libc::pollfd { fd, events: libc::POLLIN, revents: 0 },
+                        libc::pollfd { fd: interrupted.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+                    ];
+                    let error = loop {
+                        if unsafe { libc::poll(poll_fds.as_mut_ptr(), poll_fds.len() as libc::nfds_t, -1) } < 0 {
+                            let e = std::io::Error::last_os_error();
+                            if e.kind() == ErrorKind::Interrupted {
+                                continue;
+                            }
+                            break Some(e);
+                        }
+                        if poll_fds[1].revents != 0 {
+                            // Stopping; the queue has been closed.
+                            break None;
+                        }
+                        if poll_fds[0].revents == 0 {
+                            continue;
+                        }
+                        match file.read(&mut buf[..]) {
+                            Ok(0) => break None,
+                            Ok(len) => {
+                                let timestamp = clock();
+                                if !framer.push(&buf[..len]).into_iter().all(|frame| {
+                                    let flags = framer.flags(&frame);
+                                    queue.push(frame, flags, timestamp, true) == Pushed::Queued
+                                }) {
+                                    break None;
+                                }
+                            }
+                            Err(e) if matches!(e.kind(), ErrorKind::Interrupted | ErrorKind::WouldBlock) => {}
+                            Err(e) => break Some(e),
+                        };
+                    };
+                    if let Some(e) = error {
+                        if stats.lock().unwrap().fail(format!("reading stream: {}", e)) {
+                            eprintln!("Error reading stream {:?}: {:?}", name, e);
+                        }
+                    } else if let Some(frame) = framer.finish() {
+                        let flags = framer.flags(&frame);
+                        queue.push(frame, flags, clock(), true);
+                    }
+                    queue.close();
+                }));
+            }
+            None => {
+                push_source = Some(Arc::new(PushSource { queue: Arc::clone(&queue), framer: Mutex::new(framer) }));
+            }
+        }
+
+        // Frame headers are small writes, which should not wait for the previous frame to be acknowledged.
+        if let Err(e) = socket.set_nodelay(true) {
+            eprintln!("Error setting TCP_NODELAY on stream socket: {:?}", e);
+        }
+        let writer_socket = socket.try_clone()?;
+        let name = name.to_owned();
+        let writer_queue = Arc::clone(&queue);
+        let writer_stats = Arc::clone(&stats);
+        let mut rate_control = stream.max_unsent.map(RateControl::new);
+        let mut packet_sequence = 0;
+        threads.push(std::thread::spawn(move || {
+            let (socket, queue, stats) = (writer_socket, writer_queue, writer_stats);
+            loop {
+                match (&mut rate_control, &udp_socket) {
+                    (Some(rate_control), Some(udp_socket)) => rate_control.wait(udp_socket, &queue, &stats),
+                    (Some(rate_control), None) => rate_control.wait(&socket, &queue, &stats),
+                    (None, _) => {}
+                }
+                let Some(frame) = queue.pop() else { break };
+                let written = match &udp_socket {
+                    Some(udp_socket) => send_datagrams(udp_socket, &frame, &mut packet_sequence),
+                    None => write_frame(&socket, &frame.header, &frame.data).map(|()| FrameHeader::SIZE + frame.data.len()),
+                };
+                match written {
+                    Ok(length) => stats.lock().unwrap().sent(length as u64, frame.data.len() as u64),
+                    Err(e) if udp_socket.is_some() && is_transient_send_error(&e) => queue.record_dropped(),
+                    Err(e) => {
+                        if stats.lock().unwrap().fail(format!("writing stream data to server: {}", e)) {
+                            eprintln!("Error writing stream {:?} data to server: {:?}", name, e);
+                        }
+                        break;
+                    }
+                }
+            }
+            // Unblocks the producer, if it is waiting for space in the queue.
+            queue.close();
+            let mut stats = stats.lock().unwrap();
+            if stats.status == StreamStatus::StreamRunning {
+                stats.status = StreamStatus::StreamEnded;
+            }
+        }));
+
+        Ok(Self { queue, push_source, stats, interrupt, socket, threads })
+    }
+
+    /// The stream's queue, through which the server controls it.
+    pub(crate) fn queue(&self) -> &StreamQueue {
+        &self.queue
+    }
+
+    /// Where the application writes the stream's data, if it has no fd.
+    pub(crate) fn push_source(&self) -> Option<&Arc<PushSource>> {
+        self.push_source.as_ref()
+    }
+
+    /// Returns whether the stream is still being sent, and if it failed, why.
+    pub(crate) fn status(&self) -> (StreamStatus, Option<String>) {
+        let stats = self.stats.lock().unwrap();
+        (stats.status, stats.error.clone())
+    }
+
+    /// Returns the target bitrate and frame rate for the stream's producer, if it has changed since
+    /// this was last called (see RateControl).
+    pub(crate) fn take_rate_target(&self) -> Option<(f64, f64)> {
+        self.stats.lock().unwrap().rate_target.take()
+    }
+
+    /// Returns the stream's statistics.
+    pub(crate) fn stats(&self) -> StreamStats {
+        let counts = self.queue.counts();
+        let stats = self.stats.lock().unwrap();
+        let mut last_error = [0; STREAM_ERROR_LENGTH];
+        if let Some(error) = &stats.error {
+            let mut length = error.len().min(STREAM_ERROR_LENGTH - 1);
+            while !error.is_char_boundary(length) {
+                length -= 1;
+            }
+            for (c, &byte) in last_error.iter_mut().zip(&error.as_bytes()[..length]) {
+                *c = byte as c_char;
+            }
+        }
+        StreamStats {
+            bytes_sent: stats.bytes_sent,
+            frames_sent: stats.frames_sent,
+            frames_dropped: counts.dropped,
+            frames_queued: counts.queued,
+            send_rate: stats.send_rate(),
+            status: stats.status,
+            state: counts.state,
+            last_error,
+        }
+    }
+}
+
+impl Drop for StreamWorker {
+    fn drop(&mut self) {
+        // Errors caused by stopping the threads are not errors in the stream.
+        {
+            let mut stats = self.stats.lock().unwrap();
+            if stats.status == StreamStatus::StreamRunning {
+                stats.status = StreamStatus::StreamEnded;
+            }
+        }
+        self.queue.abort();
+        if let Some(interrupt) = &mut self.interrupt {
+            let _ = interrupt.write_all(&[0]);
+        }
+        let _ = self.socket.shutdown(Shutdown::Both);
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn framer(format: StreamFormat, sample_frame_length: usize) -> Framer {
+        Framer { format, sample_frame_length, pending: vec![], position: 0, state: ParseState::Start }
+    }
+
+    /// Frames data read in two parts, split at every position, and checks that the frames, and the
+    /// data left at the end, do not depend on where it was split.
+    fn assert_frames(format: StreamFormat, data: &[u8], frames: &[&[u8]], rest: Option<&[u8]>) {
+        for split in 0..=data.len() {
+            let mut framer = framer(format, 1);
+            let mut framed = framer.push(&data[..split]);
+            framed.extend(framer.push(&data[split..]));
+            assert_eq!(framed, frames, "split at {}", split);
+            assert_eq!(framer.finish().as_deref(), rest, "split at {}", split);
+        }
+    }
+
+    const JPEG: &[u8] = &[
+        0xFF, 0xD8,
+        // APP0
+        0xFF, 0xE0, 0x00, 0x04, 0xAA, 0xBB,
+        // SOS
+        0xFF, 0xDA, 0x00, 0x03, 0x01,
+        // Entropy-coded data, with a stuffed 0xFF and RST3
+        0x12, 0xFF, 0x00, 0x34, 0xFF, 0xD3, 0x56, 0xFF,
+        0xFF, 0xD9,
+    ];
+
+    #[test]
+    fn jpeg_images_are_framed_whatever_the_reads() {
+        let mut data = b"--boundary\r\nContent-Type: image/jpeg\r\n\r\n".to_vec();
+        data.extend_from_slice(JPEG);
+        data.extend_from_slice(b"\r\n--boundary\r\n\xFF");
+        data.extend_from_slice(JPEG);
+        assert_frames(StreamFormat::Mjpeg, &data, &[JPEG, JPEG], None);
+    }
+
+    #[test]
+    fn jpeg_markers_in_app1_do_not_end_the_image() {
+        let mut data = vec![0xFF, 0xD8];
+        // APP1, with an EXIF thumbnail
+        data.extend_from_slice(&[0xFF, 0xE1, 0x00, 0x08, 0xFF, 0xD8, 0x12, 0x34, 0xFF, 0xD9]);
+        data.extend_from_slice(&JPEG[2..]);
+        assert_frames(StreamFormat::Mjpeg, &data, &[&data], None);
+    }
+
+    #[test]
+    fn incomplete_jpeg_image_is_finished() {
+        assert_frames(StreamFormat::Mjpeg, &JPEG[..JPEG.len() - 2], &[], Some(&JPEG[..JPEG.len() - 2]));
+    }
+
+    #[test]
+    fn h264_access_units_are_framed_whatever_the_reads() {
+        // SPS, PPS, and an IDR slice, after data before the first start code
+        let idr: &[u8] = &[0, 0, 0, 1, 0x67, 0x42, 0, 0, 1, 0x68, 0xCE, 0, 0, 1, 0x65, 0x88, 0xAA];
+        // Two slices of a picture
+        let slices: &[u8] = &[0, 0, 0, 1, 0x41, 0x9A, 0xBB, 0, 0, 1, 0x41, 0x1A, 0xCC];
+        let last: &[u8] = &[0, 0, 1, 0x41, 0x9A, 0xDD];
+        let data = [&[0xFF, 0xFF][..], idr, slices, last].concat();
+        assert_frames(StreamFormat::H264, &data, &[idr, slices], Some(last));
+
+        let framer = framer(StreamFormat::H264, 1);
+        assert_eq!(framer.flags(idr), FrameHeader::FLAG_KEYFRAME);
+        assert_eq!(framer.flags(slices), 0);
+    }
+
+    #[test]
+    fn h264_start_codes_split_across_reads() {
+        let data: &[u8] = &[0, 0, 0, 1, 0x65, 0x88, 0, 0, 0, 1, 0x41, 0x9A, 0, 0, 1, 0x41, 0x9A];
+        let mut framer = framer(StreamFormat::H264, 1);
+        let frames: Vec<_> = data.iter().flat_map(|&byte| framer.push(&[byte])).collect();
+        assert_eq!(frames, [&data[..6], &data[6..12]]);
+        assert_eq!(framer.finish().as_deref(), Some(&data[12..]));
+    }
+
+    #[test]
+    fn ndjson_blank_lines_are_discarded() {
+        let data = b"{\"a\":1}\n\n  \r\n{\"b\":2}\n{\"c\":";
+        assert_frames(StreamFormat::Ndjson, data, &[b"{\"a\":1}\n", b"{\"b\":2}\n"], Some(b"{\"c\":"));
+        assert_frames(StreamFormat::Ndjson, b"\n \n", &[], None);
+    }
+
+    #[test]
+    fn pcm_remainder_is_kept_for_the_next_frame() {
+        let mut framer = framer(StreamFormat::Pcm, 4);
+        assert_eq!(framer.push(&[1, 2, 3, 4, 5, 6]), [vec![1, 2, 3, 4]]);
+        assert_eq!(framer.push(&[7]), Vec::<Vec<u8>>::new());
+        assert_eq!(framer.push(&[8, 9, 10, 11]), [vec![5, 6, 7, 8]]);
+        // Not a whole sample
+        assert_eq!(framer.finish(), None);
+    }
+
+    fn push(queue: &StreamQueue, data: &[u8], flags: u64) -> Pushed {
+        queue.push(data.to_vec(), flags, 0, true)
+    }
+
+    /// Closes the queue, and takes the frames in it.
+    fn drain(queue: &StreamQueue) -> Vec<Vec<u8>> {
+        queue.close();
+        std::iter::from_fn(|| queue.pop()).map(|frame| frame.data).collect()
+    }
+
+    #[test]
+    fn frames_buffer_discards_the_oldest_frames() {
+        let queue = StreamQueue::new(BufferMethod::Frames, 2);
+        for data in [b"a", b"b", b"c"] {
+            assert_eq!(push(&queue, data, FrameHeader::FLAG_KEYFRAME), Pushed::Queued);
+        }
+        assert_eq!(queue.counts().dropped, 1);
+        assert_eq!(drain(&queue), [b"b", b"c"]);
+    }
+
+    #[test]
+    fn bytes_buffer_keeps_the_newest_frame() {
+        let queue = StreamQueue::new(BufferMethod::Bytes, 4);
+        push(&queue, b"ab", 0);
+        push(&queue, b"cd", 0);
+        assert_eq!(queue.counts().dropped, 0);
+        push(&queue, b"efghij", 0);
+        assert_eq!(queue.counts().dropped, 2);
+        assert_eq!(drain(&queue), [b"efghij"]);
+    }
+
+    #[test]
+    fn stopped_stream_starts_again_from_a_keyframe() {
+        let queue = StreamQueue::new(BufferMethod::Frames, 10);
+        push(&queue, b"a", FrameHeader::FLAG_KEYFRAME);
+        queue.set_stream_state(StreamState::StreamStopped);
+        push(&queue, b"b", FrameHeader::FLAG_KEYFRAME);
+        queue.set_stream_state(StreamState::StreamStarted);
+        push(&queue, b"c", 0);
+        push(&queue, b"d", FrameHeader::FLAG_KEYFRAME);
+        push(&queue, b"e", 0);
+        let counts = queue.counts();
+        assert_eq!((counts.pushed, counts.dropped), (5, 3));
+        assert_eq!(drain(&queue), [b"d", b"e"]);
+    }
+
+    #[test]
+    fn paused_no_discard_stream_returns_to_writers() {
+        let queue = StreamQueue::new(BufferMethod::NoDiscard, 0);
+        queue.set_stream_state(StreamState::StreamPaused);
+        assert_eq!(queue.push(b"a".to_vec(), 0, 0, false), Pushed::Paused);
+        queue.set_stream_state(StreamState::StreamStarted);
+        assert_eq!(queue.push(b"b".to_vec(), 0, 0, false), Pushed::Queued);
+        assert_eq!(drain(&queue), [b"b"]);
+        assert_eq!(queue.push(b"c".to_vec(), 0, 0, false), Pushed::Closed);
+    }
+
+    #[test]
+    fn paused_stream_keeps_its_frames_after_eof() {
+        let queue = StreamQueue::new(BufferMethod::Frames, 10);
+        push(&queue, b"a", FrameHeader::FLAG_KEYFRAME);
+        push(&queue, b"b", 0);
+        queue.set_stream_state(StreamState::StreamPaused);
+        queue.close();
+        std::thread::scope(|scope| {
+            let popped = scope.spawn(|| std::iter::from_fn(|| queue.pop()).map(|frame| frame.data).collect::<Vec<_>>());
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            assert!(!popped.is_finished());
+            queue.set_stream_state(StreamState::StreamStarted);
+            assert_eq!(popped.join().unwrap(), [b"a", b"b"]);
+        });
+
+        // Stopping the stream discards them instead.
+        let queue = StreamQueue::new(BufferMethod::Frames, 10);
+        push(&queue, b"a", FrameHeader::FLAG_KEYFRAME);
+        queue.set_stream_state(StreamState::StreamPaused);
+        queue.close();
+        queue.set_stream_state(StreamState::StreamStopped);
+        assert!(queue.pop().is_none());
+        assert_eq!(queue.counts().dropped, 1);
+    }
+}
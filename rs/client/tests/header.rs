@@ -0,0 +1,30 @@
+//! Checks that include/client.h matches the FFI definitions in this crate.
+//! Set UPDATE_HEADER=1 when running this test to regenerate the header instead.
+
+use std::path::Path;
+
+#[test]
+fn header_is_up_to_date() {
+    let crate_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml"))
+        .expect("Failed to read cbindgen.toml");
+    let bindings = cbindgen::Builder::new()
+        .with_crate(crate_dir)
+        .with_config(config)
+        .generate()
+        .expect("Failed to generate header");
+    let mut generated = Vec::new();
+    bindings.write(&mut generated);
+
+    let header = crate_dir.join("../../include/client.h");
+    if std::env::var_os("UPDATE_HEADER").is_some() {
+        std::fs::write(&header, &generated).expect("Failed to write include/client.h");
+        return;
+    }
+    let checked_in = std::fs::read(&header).expect("Failed to read include/client.h");
+    assert!(
+        checked_in == generated,
+        "include/client.h does not match the FFI definitions in rs/client; \
+        regenerate it with `UPDATE_HEADER=1 cargo test -p client --test header`",
+    );
+}
@@ -0,0 +1,523 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt::Write;
+use common::message::{Axis, BufferMethod, Function, Metadata, PcmFormat, SampleFormat, Sensor, Stream, Transport, TypeDefinition};
+use crate::types::{identifier, string_literal, InputItem, Local, Type};
+
+/// The contents of a machine_description message.
+pub(crate) struct MachineDescription {
+    pub(crate) name: String,
+    pub(crate) functions: HashMap<String, Function>,
+    pub(crate) sensors: HashMap<String, Sensor>,
+    pub(crate) axes: HashMap<String, Axis>,
+    pub(crate) streams: HashMap<String, Stream>,
+    pub(crate) types: HashMap<String, TypeDefinition>,
+}
+
+/// C identifiers that have been used in a scope, to report names that collide after
+/// being made into identifiers (e.g. "a-b" and "a_b").
+#[derive(Default)]
+struct Scope(HashSet<String>);
+
+impl Scope {
+    fn add(&mut self, identifier: String, what: impl FnOnce() -> String) -> Result<String, Box<dyn Error>> {
+        if !self.0.insert(identifier.clone()) {
+            return Err(format!("the C identifier {:?} for {} is already used", identifier, what()).into());
+        }
+        Ok(identifier)
+    }
+}
+
+/// Items of a HashMap, sorted by name, so that the generated code does not depend on the order of the
+/// machine description.
+fn sorted<V>(map: &HashMap<String, V>) -> Vec<(&String, &V)> {
+    let mut items: Vec<_> = map.iter().collect();
+    items.sort_by_key(|(name, _)| *name);
+    items
+}
+
+/// Orders the named types so that each type comes after the types its fields use,
+/// as they must be registered in that order.
+fn type_order(types: &HashMap<String, TypeDefinition>) -> Result<Vec<&String>, Box<dyn Error>> {
+    fn visit<'a>(
+        name: &'a String,
+        types: &'a HashMap<String, TypeDefinition>,
+        order: &mut Vec<&'a String>,
+    ) -> Result<(), Box<dyn Error>> {
+        if order.contains(&name) {
+            return Ok(());
+        }
+        if let Some(TypeDefinition::Struct { fields }) = types.get(name) {
+            for field in fields {
+                let ty = Type::parse(&field.field_type, types)
+                    .map_err(|e| format!("field {:?} of type {:?}: {}", field.name, name, e))?;
+                for dependency in ty.named_types() {
+                    let (dependency, _) = types.get_key_value(dependency).expect("parsed types are defined");
+                    visit(dependency, types, order)?;
+                }
+            }
+        }
+        order.push(name);
+        Ok(())
+    }
+    let mut order = vec![];
+    for (name, _) in sorted(types) {
+        visit(name, types, &mut order)?;
+    }
+    Ok(order)
+}
+
+/// A function parameter, return, or sensor value, and its C representation.
+struct Value<'a> {
+    name: &'a str,
+    type_descriptor: String,
+    metadata: Option<&'a Metadata>,
+    /// For parameters, the locals it is unpacked into.
+    items: Vec<InputItem>,
+    /// For returns, the pointer locals it is unpacked into.
+    locals: Vec<Local>,
+}
+
+impl Value<'_> {
+    fn handler_parameters(&self) -> Vec<&Local> {
+        let mut locals = InputItem::locals(&self.items);
+        locals.extend(&self.locals);
+        locals
+    }
+}
+
+/// Generates a C header and source file that register the machine described by description
+/// with a client handle. base_name is the name of the generated files, without the extension.
+/// Returns (header, source).
+pub(crate) fn generate(base_name: &str, description: &MachineDescription) -> Result<(String, String), Box<dyn Error>> {
+    let mut globals = Scope::default();
+    let register = globals.add(format!("register_{}", identifier(base_name)), || "the registration function".into())?;
+    let guard = format!("{}_H", identifier(base_name).to_ascii_uppercase());
+
+    let mut header = String::new();
+    let mut source = String::new();
+    // Declarations that the registration function needs, and the statements it consists of.
+    let mut declarations = String::new();
+    let mut statements = String::new();
+    let mut register_parameters = Scope::default();
+    register_parameters.add("handle".into(), String::new)?;
+    register_parameters.add("result".into(), String::new)?;
+    let mut register_fds = vec![];
+
+    writeln!(header, "// Generated by codegen from the machine description of {}. Do not edit.", string_literal(&description.name))?;
+    writeln!(header, "#ifndef {}", guard)?;
+    writeln!(header, "#define {}", guard)?;
+    writeln!(header)?;
+    writeln!(header, "#ifdef __cplusplus")?;
+    writeln!(header, "extern \"C\" {{")?;
+    writeln!(header, "#endif // def __cplusplus")?;
+    writeln!(header)?;
+    writeln!(header, "#include \"client.h\"")?;
+
+    writeln!(source, "// Generated by codegen from the machine description of {}. Do not edit.", string_literal(&description.name))?;
+    writeln!(source, "#include <stddef.h>")?;
+    writeln!(source, "#include \"{}.h\"", base_name)?;
+
+    let call = |statements: &mut String, call: String| -> std::fmt::Result {
+        writeln!(statements, "    result = {};", call)?;
+        writeln!(statements, "    if (result != NoError) return result;")
+    };
+    call(&mut statements, format!("SetName(handle, {})", string_literal(&description.name)))?;
+
+    for name in type_order(&description.types)? {
+        let c_name = identifier(name);
+        match &description.types[name] {
+            TypeDefinition::Struct { fields } => {
+                let array = globals.add(format!("{}_fields", c_name), || format!("the fields of type {:?}", name))?;
+                writeln!(declarations)?;
+                writeln!(declarations, "static const char *{}[][2] = {{", array)?;
+                for field in fields {
+                    writeln!(declarations, "    {{{}, {}}},", string_literal(&field.name), string_literal(&field.field_type))?;
+                }
+                writeln!(declarations, "    {{NULL, NULL}},")?;
+                writeln!(declarations, "}};")?;
+                call(&mut statements, format!("RegisterStruct(handle, {}, {})", string_literal(name), array))?;
+            },
+            TypeDefinition::Enum { values } => {
+                let tag = globals.add(format!("enum {}", c_name), || format!("type {:?}", name))?;
+                writeln!(header)?;
+                writeln!(header, "/**")?;
+                writeln!(header, "* The values of the enum type {}.", string_literal(name))?;
+                writeln!(header, "*/")?;
+                writeln!(header, "{} {{", tag)?;
+                for (i, value) in values.iter().enumerate() {
+                    let constant = format!("{}_{}", c_name, identifier(value)).to_ascii_uppercase();
+                    let constant = globals.add(constant, || format!("value {:?} of type {:?}", value, name))?;
+                    writeln!(header, "    {} = {},", constant, i)?;
+                }
+                writeln!(header, "}};")?;
+
+                let array = globals.add(format!("{}_values", c_name), || format!("the values of type {:?}", name))?;
+                let values: Vec<_> = values.iter().map(|value| string_literal(value)).collect();
+                writeln!(declarations)?;
+                writeln!(declarations, "static const char *const {}[] = {{{}, NULL}};", array, values.join(", "))?;
+                call(&mut statements, format!("RegisterEnum(handle, {}, {})", string_literal(name), array))?;
+            },
+        }
+    }
+
+    for (name, function) in sorted(&description.functions) {
+        let c_name = identifier(name);
+        let handler = globals.add(format!("{}_function", c_name), || format!("function {:?}", name))?;
+        let callback = globals.add(format!("{}_function_callback", c_name), || format!("function {:?}", name))?;
+        let mut locals = Scope::default();
+        locals.add("parameters".into(), String::new)?;
+        locals.add("returns".into(), String::new)?;
+
+        let parameters = sorted(&function.parameters).into_iter().enumerate().map(|(i, (parameter, ty))| {
+            let parsed = Type::parse(ty, &description.types)
+                .map_err(|e| format!("parameter {:?} of function {:?}: {}", parameter, name, e))?;
+            let src = format!("parameters[{}]", i);
+            let mut items = parsed.input_items(&identifier(parameter), &src);
+            let default = function.defaults.get(parameter);
+            let type_descriptor = if let Some(default) = default {
+                format!("{} = {}", ty, default.get())
+            } else if function.optional.contains(parameter) {
+                // An omitted parameter is passed as NULL, so guard unpacking it.
+                let present = format!("{}_present", identifier(parameter));
+                items = match &mut items[..] {
+                    [InputItem::Guarded { present: nullable_present, .. }] => {
+                        nullable_present.value = format!("{} != NULL && {}", src, nullable_present.value);
+                        items
+                    },
+                    _ => vec![InputItem::Guarded {
+                        present: Local { c_type: "bool".into(), name: present, value: format!("{} != NULL", src) },
+                        items,
+                    }],
+                };
+                format!("{} optional", ty)
+            } else {
+                ty.clone()
+            };
+            Ok(Value {
+                name: parameter,
+                type_descriptor,
+                metadata: function.parameter_metadata.get(parameter),
+                items,
+                locals: vec![],
+            })
+        }).collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+        let returns = sorted(&function.returns).into_iter().enumerate().map(|(i, (ret, ty))| {
+            let parsed = Type::parse(ty, &description.types)
+                .map_err(|e| format!("return {:?} of function {:?}: {}", ret, name, e))?;
+            Ok(Value {
+                name: ret,
+                type_descriptor: ty.clone(),
+                metadata: function.return_metadata.get(ret),
+                items: vec![],
+                locals: parsed.output_locals(&identifier(ret), &format!("returns[{}]", i)),
+            })
+        }).collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+        for value in parameters.iter().chain(&returns) {
+            for local in value.handler_parameters() {
+                if globals.0.contains(&local.name) {
+                    return Err(format!("the C identifier {:?} for {:?} of function {:?} is already used", local.name, value.name, name).into());
+                }
+                locals.add(local.name.clone(), || format!("{:?} of function {:?}", value.name, name))?;
+            }
+        }
+
+        // The handler, which the user implements.
+        writeln!(header)?;
+        writeln!(header, "/**")?;
+        write_metadata_doc(&mut header, &function.metadata)?;
+        writeln!(header, "* Implements the function {}.", string_literal(name))?;
+        for value in &parameters {
+            write_value_doc(&mut header, value, "")?;
+        }
+        for value in &returns {
+            write_value_doc(&mut header, value, "(return) ")?;
+        }
+        writeln!(header, "* @returns 0 on success, or nonzero on failure (see SetCallbackError)")?;
+        writeln!(header, "*/")?;
+        let handler_parameters: Vec<_> = parameters.iter().chain(&returns).flat_map(Value::handler_parameters).collect();
+        writeln!(header, "int {}({});", handler, parameter_list(&handler_parameters))?;
+
+        // The descriptors and the callback, which unpacks the parameters and returns for the handler.
+        for (array, values) in [("parameters", &parameters), ("returns", &returns)] {
+            let array = globals.add(format!("{}_function_{}", c_name, array), || format!("function {:?}", name))?;
+            writeln!(declarations)?;
+            writeln!(declarations, "static const char *{}[][2] = {{", array)?;
+            for value in values {
+                writeln!(declarations, "    {{{}, {}}},", string_literal(value.name), string_literal(&value.type_descriptor))?;
+            }
+            writeln!(declarations, "    {{NULL, NULL}},")?;
+            writeln!(declarations, "}};")?;
+        }
+        writeln!(declarations)?;
+        writeln!(declarations, "static int {}(const void *const *const parameters, void *const *const returns) {{", callback)?;
+        if parameters.is_empty() {
+            writeln!(declarations, "    (void)parameters;")?;
+        }
+        if returns.is_empty() {
+            writeln!(declarations, "    (void)returns;")?;
+        }
+        for value in &parameters {
+            write_input_items(&mut declarations, &value.items, 1, false)?;
+        }
+        for value in &returns {
+            for local in &value.locals {
+                writeln!(declarations, "    {} = {};", declaration(local), local.value)?;
+            }
+        }
+        let arguments: Vec<_> = handler_parameters.iter().map(|local| &*local.name).collect();
+        writeln!(declarations, "    return {}({});", handler, arguments.join(", "))?;
+        writeln!(declarations, "}}")?;
+
+        call(&mut statements, format!(
+            "RegisterFunctionEx(handle, {}, {}_function_parameters, {}_function_returns, {})",
+            string_literal(name), c_name, c_name, callback,
+        ))?;
+        if !function.metadata.is_empty() {
+            call(&mut statements, format!("SetFunctionMetadata(handle, {}, {})", string_literal(name), metadata_arguments(&function.metadata)))?;
+        }
+        for value in parameters.iter().chain(&returns) {
+            if let Some(metadata) = value.metadata.filter(|metadata| !metadata.is_empty()) {
+                call(&mut statements, format!(
+                    "SetParameterMetadata(handle, {}, {}, {})",
+                    string_literal(name), string_literal(value.name), metadata_arguments(metadata),
+                ))?;
+            }
+        }
+    }
+
+    for (name, sensor) in sorted(&description.sensors) {
+        let c_name = identifier(name);
+        let handler = globals.add(format!("{}_sensor", c_name), || format!("sensor {:?}", name))?;
+        let ty = Type::parse(&sensor.output_type, &description.types)
+            .map_err(|e| format!("sensor {:?}: {}", name, e))?;
+        let value = Value {
+            name: "value",
+            type_descriptor: sensor.output_type.clone(),
+            metadata: None,
+            items: vec![],
+            locals: ty.output_locals("value", "output"),
+        };
+        let mut locals = Scope::default();
+        locals.add("output".into(), String::new)?;
+        for local in &value.locals {
+            locals.add(local.name.clone(), || format!("sensor {:?}", name))?;
+        }
+
+        writeln!(header)?;
+        writeln!(header, "/**")?;
+        write_metadata_doc(&mut header, &sensor.metadata)?;
+        writeln!(header, "* Reads the sensor {}.", string_literal(name))?;
+        write_value_doc(&mut header, &value, "(return) ")?;
+        writeln!(header, "* @returns 0 on success, or nonzero on failure (see SetCallbackError)")?;
+        writeln!(header, "*/")?;
+        writeln!(header, "int {}({});", handler, parameter_list(&value.locals.iter().collect::<Vec<_>>()))?;
+
+        if ty == Type::Prim(crate::types::PrimType::Double) {
+            call(&mut statements, format!(
+                "RegisterSensorEx(handle, {}, {:?}, {:?}, {})",
+                string_literal(name), sensor.min.unwrap_or(0.0), sensor.max.unwrap_or(0.0), handler,
+            ))?;
+        } else {
+            let callback = globals.add(format!("{}_sensor_callback", c_name), || format!("sensor {:?}", name))?;
+            writeln!(declarations)?;
+            writeln!(declarations, "static int {}(void *const output) {{", callback)?;
+            for local in &value.locals {
+                writeln!(declarations, "    {} = {};", declaration(local), local.value)?;
+            }
+            let arguments: Vec<_> = value.locals.iter().map(|local| &*local.name).collect();
+            writeln!(declarations, "    return {}({});", handler, arguments.join(", "))?;
+            writeln!(declarations, "}}")?;
+            call(&mut statements, format!(
+                "RegisterSensorTyped(handle, {}, {}, {})",
+                string_literal(name), string_literal(&sensor.output_type), callback,
+            ))?;
+        }
+        if !sensor.metadata.is_empty() {
+            call(&mut statements, format!("SetSensorMetadata(handle, {}, {})", string_literal(name), metadata_arguments(&sensor.metadata)))?;
+        }
+    }
+
+    for (name, axis) in sorted(&description.axes) {
+        if axis.input_type != "double" {
+            return Err(format!("axis {:?} has unsupported type {:?}", name, axis.input_type).into());
+        }
+        let handler = globals.add(format!("{}_axis", identifier(name)), || format!("axis {:?}", name))?;
+        writeln!(header)?;
+        writeln!(header, "/**")?;
+        write_metadata_doc(&mut header, &axis.metadata)?;
+        writeln!(header, "* Moves the axis {}.", string_literal(name))?;
+        writeln!(header, "* @param value  The new value of the axis{}", unit_suffix(&axis.metadata))?;
+        writeln!(header, "* @returns 0 on success, or nonzero on failure (see SetCallbackError)")?;
+        writeln!(header, "*/")?;
+        writeln!(header, "int {}(double value);", handler)?;
+        call(&mut statements, format!(
+            "RegisterAxisEx(handle, {}, {:?}, {:?}, {}, {}, {})",
+            string_literal(name), axis.min, axis.max, string_literal(&axis.group), string_literal(&axis.direction), handler,
+        ))?;
+        if !axis.metadata.is_empty() {
+            call(&mut statements, format!("SetAxisMetadata(handle, {}, {})", string_literal(name), metadata_arguments(&axis.metadata)))?;
+        }
+    }
+
+    for (name, stream) in sorted(&description.streams) {
+        let fd = register_parameters.add(format!("{}_fd", identifier(name)), || format!("stream {:?}", name))?;
+        let buffer_method = match stream.buffer_method {
+            BufferMethod::Frames => "BufferFrames",
+            BufferMethod::Bytes => "BufferBytes",
+            BufferMethod::NoDiscard => "BufferNoDiscard",
+        };
+        let register_stream = match stream.pcm {
+            Some(PcmFormat { sample_rate, channels, sample_format }) => {
+                let sample_format = match sample_format {
+                    SampleFormat::U8 => "SampleU8",
+                    SampleFormat::S16le => "SampleS16LE",
+                    SampleFormat::S16be => "SampleS16BE",
+                    SampleFormat::S32le => "SampleS32LE",
+                    SampleFormat::S32be => "SampleS32BE",
+                    SampleFormat::F32le => "SampleF32LE",
+                    SampleFormat::F32be => "SampleF32BE",
+                };
+                format!(
+                    "RegisterPcmStream(handle, {}, {}, {}, {}, {}, {}, {})",
+                    string_literal(name), fd, buffer_method, stream.buffer_size, sample_rate, channels, sample_format,
+                )
+            }
+            None => format!(
+                "RegisterStreamEx(handle, {}, {}, {}, {}, {})",
+                string_literal(name), string_literal(stream.format.name()), fd, buffer_method, stream.buffer_size,
+            ),
+        };
+        call(&mut statements, register_stream)?;
+        if stream.transport == Transport::Udp {
+            call(&mut statements, format!("SetStreamTransport(handle, {}, TransportUdp)", string_literal(name)))?;
+        }
+        if !stream.metadata.is_empty() {
+            call(&mut statements, format!("SetStreamMetadata(handle, {}, {})", string_literal(name), metadata_arguments(&stream.metadata)))?;
+        }
+        register_fds.push((name, fd));
+    }
+
+    let mut register_signature = format!("enum ErrorCode {}(ClientHandle handle", register);
+    for (_, fd) in &register_fds {
+        write!(register_signature, ", int {}", fd)?;
+    }
+    register_signature.push(')');
+
+    writeln!(header)?;
+    writeln!(header, "/**")?;
+    writeln!(header, "* Sets the name of the machine, and registers its types, functions, sensors, axes, and streams,")?;
+    writeln!(header, "* with their metadata. Call this before ConnectToServer.")?;
+    writeln!(header, "* @param handle     The client handle")?;
+    for (name, fd) in &register_fds {
+        writeln!(header, "* @param {}  The file descriptor to read the stream {} from, or -1 to write it with StreamWrite", fd, string_literal(name))?;
+    }
+    writeln!(header, "* @returns enum ErrorCode success (Was everything registered successfully)")?;
+    writeln!(header, "*/")?;
+    writeln!(header, "{};", register_signature)?;
+    writeln!(header)?;
+    writeln!(header, "#ifdef __cplusplus")?;
+    writeln!(header, "}} // extern \"C\"")?;
+    writeln!(header, "#endif // def __cplusplus")?;
+    writeln!(header)?;
+    writeln!(header, "#endif // ndef {}", guard)?;
+
+    source.push_str(&declarations);
+    writeln!(source)?;
+    writeln!(source, "{} {{", register_signature)?;
+    writeln!(source, "    enum ErrorCode result;")?;
+    source.push_str(&statements);
+    writeln!(source, "    return NoError;")?;
+    writeln!(source, "}}")?;
+
+    Ok((header, source))
+}
+
+/// The C declaration of a local, e.g. "const double *x".
+fn declaration(local: &Local) -> String {
+    if local.c_type.ends_with('*') {
+        format!("{}{}", local.c_type, local.name)
+    } else {
+        format!("{} {}", local.c_type, local.name)
+    }
+}
+
+fn parameter_list(locals: &[&Local]) -> String {
+    if locals.is_empty() {
+        "void".to_owned()
+    } else {
+        locals.iter().map(|local| declaration(local)).collect::<Vec<_>>().join(", ")
+    }
+}
+
+/// Writes statements that unpack items. If declared, the locals of the items have already
+/// been declared (because they are inside a guard).
+fn write_input_items(out: &mut String, items: &[InputItem], indent: usize, declared: bool) -> std::fmt::Result {
+    let pad = "    ".repeat(indent);
+    for item in items {
+        match item {
+            InputItem::Local(local) if declared => writeln!(out, "{}{} = {};", pad, local.name, local.value)?,
+            InputItem::Local(local) => writeln!(out, "{}{} = {};", pad, declaration(local), local.value)?,
+            InputItem::Guarded { present, items } => {
+                if declared {
+                    writeln!(out, "{}{} = {};", pad, present.name, present.value)?;
+                } else {
+                    writeln!(out, "{}{} = {};", pad, declaration(present), present.value)?;
+                    for local in InputItem::locals(items) {
+                        writeln!(out, "{}{} = {};", pad, declaration(local), local.zero())?;
+                    }
+                }
+                writeln!(out, "{}if ({}) {{", pad, present.name)?;
+                write_input_items(out, items, indent + 1, true)?;
+                writeln!(out, "{}}}", pad)?;
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Writes the description of an item as the first line(s) of its doc comment.
+fn write_metadata_doc(out: &mut String, metadata: &Metadata) -> std::fmt::Result {
+    for line in metadata.description.lines() {
+        writeln!(out, "* {}", line)?;
+    }
+    Ok(())
+}
+
+fn unit_suffix(metadata: &Metadata) -> String {
+    if metadata.unit.is_empty() {
+        String::new()
+    } else {
+        format!(" [{}]", metadata.unit)
+    }
+}
+
+/// Documents the handler parameters of a value (its locals), with its type and metadata.
+fn write_value_doc(out: &mut String, value: &Value, prefix: &str) -> std::fmt::Result {
+    let metadata = value.metadata.cloned().unwrap_or_default();
+    let mut doc = format!("{}{}", value.type_descriptor, unit_suffix(&metadata));
+    if !metadata.description.is_empty() {
+        write!(doc, " ({})", metadata.description.replace('\n', " "))?;
+    }
+    let name = identifier(value.name);
+    for local in value.handler_parameters() {
+        if local.name == name {
+            writeln!(out, "* @param {}  {}{}", local.name, prefix, doc)?;
+        } else {
+            writeln!(out, "* @param {}  {}part of {}: {}", local.name, prefix, value.name, doc)?;
+        }
+    }
+    Ok(())
+}
+
+/// The description, unit, and tags arguments of a Set*Metadata call.
+fn metadata_arguments(metadata: &Metadata) -> String {
+    let optional = |s: &str| if s.is_empty() { "NULL".to_owned() } else { string_literal(s) };
+    let tags = if metadata.tags.is_empty() {
+        "NULL".to_owned()
+    } else {
+        let tags: Vec<_> = metadata.tags.iter().map(|tag| string_literal(tag)).collect();
+        format!("(const char *const[]){{{}, NULL}}", tags.join(", "))
+    };
+    format!("{}, {}, {}", optional(&metadata.description), optional(&metadata.unit), tags)
+}
@@ -0,0 +1,53 @@
+//! Generates C code that registers a machine with the client library, from its machine description
+//! (e.g. as exported by ExportMachineDescription).
+//!
+//! Usage: codegen <machine description JSON file, or - for stdin> <output path without extension>
+//!
+//! Writes <output path>.h, which declares a typed handler for each function, sensor, and axis
+//! (which the user implements) and a registration function, and <output path>.c, which implements
+//! the registration function and the callbacks that unpack parameters and returns for the handlers.
+
+mod generate;
+mod types;
+
+use std::io::Read;
+use std::path::Path;
+use common::message::{Message, MessageInner};
+use generate::MachineDescription;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("Usage: {} <machine description JSON file, or - for stdin> <output path without extension>", args[0]);
+        std::process::exit(2);
+    }
+
+    let json = if args[1] == "-" {
+        let mut json = String::new();
+        std::io::stdin().read_to_string(&mut json)?;
+        json
+    } else {
+        std::fs::read_to_string(&args[1]).map_err(|e| format!("Failed to read {:?}: {}", args[1], e))?
+    };
+    // Also accept the fields of a machine description without the message_type.
+    let mut object: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&json)
+        .map_err(|e| format!("Invalid machine description: {}", e))?;
+    object.entry("message_type").or_insert_with(|| "machine_description".into());
+    let message: Message = serde_json::from_str(&serde_json::to_string(&object)?)
+        .map_err(|e| format!("Invalid machine description: {}", e))?;
+    let description = match message.inner {
+        MessageInner::MachineDescription { name, functions, sensors, axes, streams, types } =>
+            MachineDescription { name, functions, sensors, axes, streams, types },
+        _ => return Err("Invalid machine description: message_type is not \"machine_description\"".into()),
+    };
+
+    let base_name = Path::new(&args[2]).file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| format!("Invalid output path {:?}", args[2]))?;
+    let (header, source) = generate::generate(base_name, &description)?;
+    for (extension, contents) in [("h", header), ("c", source)] {
+        let path = format!("{}.{}", args[2], extension);
+        std::fs::write(&path, contents).map_err(|e| format!("Failed to write {:?}: {}", path, e))?;
+    }
+    Ok(())
+}
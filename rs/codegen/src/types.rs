@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+use common::message::TypeDefinition;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PrimType {
+    Bool,
+    Byte,
+    Short,
+    Int,
+    Long,
+    UByte,
+    UShort,
+    UInt,
+    ULong,
+    Float,
+    Double,
+}
+
+impl PrimType {
+    fn from_str(s: &str) -> Option<Self> {
+        use PrimType::*;
+        Some(match s {
+            "bool" => Bool,
+            "byte" | "i8" => Byte,
+            "short" | "i16" => Short,
+            "int" | "i32" => Int,
+            "long" | "i64" => Long,
+            "ubyte" | "u8" => UByte,
+            "ushort" | "u16" => UShort,
+            "uint" | "u32" => UInt,
+            "ulong" | "u64" => ULong,
+            "float" => Float,
+            "double" => Double,
+            _ => return None,
+        })
+    }
+
+    /// The C type of a value of this type, as passed to and from callbacks.
+    pub(crate) fn c_type(self) -> &'static str {
+        use PrimType::*;
+        match self {
+            Bool => "bool",
+            Byte => "int8_t",
+            Short => "int16_t",
+            Int => "int32_t",
+            Long => "int64_t",
+            UByte => "uint8_t",
+            UShort => "uint16_t",
+            UInt => "uint32_t",
+            ULong => "uint64_t",
+            Float => "float",
+            Double => "double",
+        }
+    }
+}
+
+/// A type in a machine description, with named types resolved to their definitions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Type {
+    Prim(PrimType),
+    PrimArray(PrimType),
+    String,
+    StringArray,
+    Bytes,
+    /// A named struct type, with its fields in order.
+    Struct(String, Vec<(String, Type)>),
+    /// A named enum type, with its values in order.
+    Enum(String, Vec<String>),
+    FixedArray(PrimType, Vec<usize>),
+    Map(Box<Type>),
+    Nullable(Box<Type>),
+}
+
+/// A C local variable (or callback parameter) and the expression it is initialized with.
+#[derive(Debug, Clone)]
+pub(crate) struct Local {
+    pub(crate) c_type: String,
+    pub(crate) name: String,
+    pub(crate) value: String,
+}
+
+impl Local {
+    fn new(c_type: impl Into<String>, name: &str, value: String) -> Self {
+        Self { c_type: c_type.into(), name: name.to_owned(), value }
+    }
+
+    /// The value the local has when it is not assigned (i.e. when the parameter is absent).
+    pub(crate) fn zero(&self) -> &'static str {
+        if self.c_type == "bool" {
+            "false"
+        } else if self.c_type.ends_with('*') {
+            "NULL"
+        } else {
+            "0"
+        }
+    }
+}
+
+/// How an input parameter is unpacked into locals.
+#[derive(Debug, Clone)]
+pub(crate) enum InputItem {
+    Local(Local),
+    /// Locals that are only assigned if the bool local `present` is true.
+    Guarded { present: Local, items: Vec<InputItem> },
+}
+
+impl InputItem {
+    /// All locals of these items, in order, including the present flags.
+    pub(crate) fn locals(items: &[InputItem]) -> Vec<&Local> {
+        let mut locals = vec![];
+        for item in items {
+            match item {
+                InputItem::Local(local) => locals.push(local),
+                InputItem::Guarded { present, items } => {
+                    locals.push(present);
+                    locals.extend(InputItem::locals(items));
+                },
+            }
+        }
+        locals
+    }
+}
+
+impl Type {
+    /// Parses a type string of a machine description (as produced by the client library),
+    /// resolving named types using types.
+    pub(crate) fn parse(s: &str, types: &HashMap<String, TypeDefinition>) -> Result<Self, String> {
+        Self::parse_inner(s, types, &mut vec![])
+    }
+
+    /// resolving holds the named types currently being resolved, to reject recursive structs.
+    fn parse_inner(s: &str, types: &HashMap<String, TypeDefinition>, resolving: &mut Vec<String>) -> Result<Self, String> {
+        let s = s.trim();
+        if let Some(inner) = s.strip_suffix('?') {
+            return Ok(Type::Nullable(Box::new(Self::parse_inner(inner, types, resolving)?)));
+        }
+        if let Some(inner) = s.strip_prefix("map<").and_then(|s| s.strip_suffix('>')) {
+            let (key, value) = inner.split_once(',').ok_or_else(|| format!("invalid map type {:?}", s))?;
+            if key.trim() != "string" {
+                return Err(format!("invalid map type {:?} (keys must be strings)", s));
+            }
+            return Ok(Type::Map(Box::new(Self::parse_inner(value, types, resolving)?)));
+        }
+        if let Some(base) = s.strip_suffix("[]") {
+            return match base.trim() {
+                "string" => Ok(Type::StringArray),
+                base => PrimType::from_str(base).map(Type::PrimArray).ok_or_else(|| format!("invalid array type {:?}", s)),
+            };
+        }
+        if s.ends_with(']') {
+            let mut dimensions = vec![];
+            let mut base = s;
+            while let Some(rest) = base.strip_suffix(']') {
+                let (rest, length) = rest.rsplit_once('[').ok_or_else(|| format!("invalid fixed-size array type {:?}", s))?;
+                dimensions.push(length.trim().parse::<usize>().map_err(|_| format!("invalid fixed-size array type {:?}", s))?);
+                base = rest.trim_end();
+            }
+            dimensions.reverse();
+            let prim = PrimType::from_str(base).ok_or_else(|| format!("invalid fixed-size array type {:?}", s))?;
+            return Ok(Type::FixedArray(prim, dimensions));
+        }
+        match s {
+            "string" => return Ok(Type::String),
+            "bytes" => return Ok(Type::Bytes),
+            _ => {},
+        }
+        if let Some(prim) = PrimType::from_str(s) {
+            return Ok(Type::Prim(prim));
+        }
+        match types.get(s) {
+            Some(TypeDefinition::Enum { values }) => Ok(Type::Enum(s.to_owned(), values.clone())),
+            Some(TypeDefinition::Struct { fields }) => {
+                if resolving.iter().any(|name| name == s) {
+                    return Err(format!("struct type {:?} contains itself", s));
+                }
+                resolving.push(s.to_owned());
+                let fields = fields.iter()
+                    .map(|field| Ok((field.name.clone(), Self::parse_inner(&field.field_type, types, resolving)?)))
+                    .collect::<Result<Vec<_>, String>>()?;
+                resolving.pop();
+                Ok(Type::Struct(s.to_owned(), fields))
+            },
+            None => Err(format!("unknown type {:?}", s)),
+        }
+    }
+
+    /// Named types that this type refers to directly (i.e. not through another named type).
+    pub(crate) fn named_types(&self) -> Vec<&str> {
+        match self {
+            Type::Struct(name, _) | Type::Enum(name, _) => vec![name],
+            Type::Map(inner) | Type::Nullable(inner) => inner.named_types(),
+            _ => vec![],
+        }
+    }
+
+    /// How a parameter of this type named name, where src is a `const void *` expression
+    /// pointing to the parameter, is unpacked into typed locals.
+    /// Structs are unpacked field by field, into locals named name_field.
+    pub(crate) fn input_items(&self, name: &str, src: &str) -> Vec<InputItem> {
+        let local = |c_type: String, name: &str, value: String| InputItem::Local(Local::new(c_type, name, value));
+        match self {
+            Type::Prim(prim) => {
+                let c_type = prim.c_type();
+                vec![local(c_type.to_owned(), name, format!("*(const {} *){}", c_type, src))]
+            },
+            Type::String => vec![
+                local("const char *".to_owned(), name, format!("((const struct StringInputParameter_t *){})->string", src)),
+            ],
+            Type::PrimArray(_) | Type::StringArray | Type::Bytes => {
+                let element = match self {
+                    Type::PrimArray(prim) => prim.c_type(),
+                    Type::StringArray => "const char *",
+                    _ => "uint8_t",
+                };
+                let array = format!("((const struct ArrayInputParameter_t *){})", src);
+                let pointer = const_pointer(element);
+                vec![
+                    local("int".to_owned(), &format!("{}_length", name), format!("{}->length", array)),
+                    local(pointer.clone(), name, format!("({}){}->data", pointer, array)),
+                ]
+            },
+            Type::Struct(_, fields) => fields.iter().enumerate()
+                .flat_map(|(i, (field, ty))| ty.input_items(
+                    &format!("{}_{}", name, identifier(field)),
+                    &format!("((const void *const *){})[{}]", src, i),
+                ))
+                .collect(),
+            Type::Enum(enum_name, _) => {
+                let c_type = format!("enum {}", identifier(enum_name));
+                let value = format!("({})*(const int *){}", c_type, src);
+                vec![local(c_type, name, value)]
+            },
+            Type::FixedArray(prim, _) => {
+                let pointer = const_pointer(prim.c_type());
+                vec![local(pointer.clone(), name, format!("({}){}", pointer, src))]
+            },
+            Type::Map(value) => {
+                let element = match &**value {
+                    Type::Prim(prim) => prim.c_type(),
+                    _ => "const char *",
+                };
+                let map = format!("((const struct MapInputParameter_t *){})", src);
+                let pointer = const_pointer(element);
+                vec![
+                    local("int".to_owned(), &format!("{}_length", name), format!("{}->length", map)),
+                    local("const char *const *".to_owned(), &format!("{}_keys", name), format!("{}->keys", map)),
+                    local(pointer.clone(), &format!("{}_values", name), format!("({}){}->values", pointer, map)),
+                ]
+            },
+            Type::Nullable(inner) => {
+                let nullable = format!("((const struct NullableInputParameter_t *){})", src);
+                vec![InputItem::Guarded {
+                    present: Local::new("bool", &format!("{}_present", name), format!("{}->present", nullable)),
+                    items: inner.input_items(name, &format!("{}->value", nullable)),
+                }]
+            },
+        }
+    }
+
+    /// How a return of this type named name, where src is a `void *` expression
+    /// pointing to the return, is unpacked into typed pointer locals.
+    /// Structs are unpacked field by field, into locals named name_field.
+    pub(crate) fn output_locals(&self, name: &str, src: &str) -> Vec<Local> {
+        let pointer = |c_type: &str| Local::new(format!("{} *", c_type), name, format!("({} *){}", c_type, src));
+        match self {
+            Type::Prim(prim) | Type::FixedArray(prim, _) => vec![pointer(prim.c_type())],
+            Type::String => vec![pointer("struct StringOutputParameter_t")],
+            Type::PrimArray(_) | Type::StringArray | Type::Bytes => vec![pointer("struct ArrayOutputParameter_t")],
+            Type::Struct(_, fields) => fields.iter().enumerate()
+                .flat_map(|(i, (field, ty))| ty.output_locals(
+                    &format!("{}_{}", name, identifier(field)),
+                    &format!("((void *const *){})[{}]", src, i),
+                ))
+                .collect(),
+            Type::Enum(..) => vec![pointer("int")],
+            Type::Map(_) => vec![pointer("struct MapOutputParameter_t")],
+            Type::Nullable(inner) => {
+                let nullable = format!("((struct NullableOutputParameter_t *){})", src);
+                let mut locals = vec![Local::new("bool *", &format!("{}_present", name), format!("&{}->present", nullable))];
+                locals.extend(inner.output_locals(name, &format!("{}->value", nullable)));
+                locals
+            },
+        }
+    }
+}
+
+/// The C type of a pointer to const elements of the given C type.
+fn const_pointer(element: &str) -> String {
+    if element.ends_with('*') {
+        format!("{}const *", element)
+    } else {
+        format!("const {} *", element)
+    }
+}
+
+const C_KEYWORDS: &[&str] = &[
+    "auto", "bool", "break", "case", "char", "const", "continue", "default", "do", "double",
+    "else", "enum", "extern", "false", "float", "for", "goto", "if", "inline", "int", "long",
+    "register", "restrict", "return", "short", "signed", "sizeof", "static", "struct", "switch",
+    "true", "typedef", "union", "unsigned", "void", "volatile", "while", "NULL",
+];
+
+/// Makes a name (of a function, parameter, type, etc.) into a valid C identifier,
+/// by replacing invalid characters with underscores.
+pub(crate) fn identifier(name: &str) -> String {
+    let mut identifier: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if identifier.is_empty() || identifier.starts_with(|c: char| c.is_ascii_digit()) {
+        identifier.insert(0, '_');
+    }
+    if C_KEYWORDS.contains(&&*identifier) {
+        identifier.push('_');
+    }
+    identifier
+}
+
+/// Makes a C string literal with the given contents.
+pub(crate) fn string_literal(s: &str) -> String {
+    let mut literal = String::with_capacity(s.len() + 2);
+    literal.push('"');
+    let mut previous = 0;
+    for &b in s.as_bytes() {
+        match b {
+            b'"' => literal.push_str("\\\""),
+            b'\\' => literal.push_str("\\\\"),
+            b'\n' => literal.push_str("\\n"),
+            b'\t' => literal.push_str("\\t"),
+            // Octal escapes are at most 3 digits, so they can't absorb following characters.
+            // "??" is escaped so that it can't start a trigraph.
+            b if !(0x20..0x7f).contains(&b) || (b == b'?' && previous == b'?') => literal.push_str(&format!("\\{:03o}", b)),
+            b => literal.push(b as char),
+        }
+        previous = b;
+    }
+    literal.push('"');
+    literal
+}
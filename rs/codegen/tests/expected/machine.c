@@ -0,0 +1,151 @@
+// Generated by codegen from the machine description of "Test \"machine\"". Do not edit.
+#include <stddef.h>
+#include "machine.h"
+
+static const char *const level_values[] = {"low", "high", "very-high", NULL};
+
+static const char *point_fields[][2] = {
+    {"x", "double"},
+    {"y", "double"},
+    {NULL, NULL},
+};
+
+static const char *pose_fields[][2] = {
+    {"position", "point"},
+    {"level", "level"},
+    {"id", "int?"},
+    {NULL, NULL},
+};
+
+static const char *move_to_function_parameters[][2] = {
+    {"label", "string?"},
+    {"level", "level = \"low\""},
+    {"note", "string optional"},
+    {"speed", "double"},
+    {"target", "point"},
+    {NULL, NULL},
+};
+
+static const char *move_to_function_returns[][2] = {
+    {"distances", "map<string, double>"},
+    {"path", "double[]"},
+    {"reached", "bool"},
+    {NULL, NULL},
+};
+
+static int move_to_function_callback(const void *const *const parameters, void *const *const returns) {
+    bool label_present = ((const struct NullableInputParameter_t *)parameters[0])->present;
+    const char *label = NULL;
+    if (label_present) {
+        label = ((const struct StringInputParameter_t *)((const struct NullableInputParameter_t *)parameters[0])->value)->string;
+    }
+    enum level level = (enum level)*(const int *)parameters[1];
+    bool note_present = parameters[2] != NULL;
+    const char *note = NULL;
+    if (note_present) {
+        note = ((const struct StringInputParameter_t *)parameters[2])->string;
+    }
+    double speed = *(const double *)parameters[3];
+    double target_x = *(const double *)((const void *const *)parameters[4])[0];
+    double target_y = *(const double *)((const void *const *)parameters[4])[1];
+    struct MapOutputParameter_t *distances = (struct MapOutputParameter_t *)returns[0];
+    struct ArrayOutputParameter_t *path = (struct ArrayOutputParameter_t *)returns[1];
+    bool *reached = (bool *)returns[2];
+    return move_to_function(label_present, label, level, note_present, note, speed, target_x, target_y, distances, path, reached);
+}
+
+static const char *reset_function_parameters[][2] = {
+    {NULL, NULL},
+};
+
+static const char *reset_function_returns[][2] = {
+    {NULL, NULL},
+};
+
+static int reset_function_callback(const void *const *const parameters, void *const *const returns) {
+    (void)parameters;
+    (void)returns;
+    return reset_function();
+}
+
+static const char *upload_function_parameters[][2] = {
+    {"data", "bytes"},
+    {"matrix", "float[2][3]"},
+    {"names", "string[]"},
+    {NULL, NULL},
+};
+
+static const char *upload_function_returns[][2] = {
+    {"checksum", "int"},
+    {"pose", "pose?"},
+    {NULL, NULL},
+};
+
+static int upload_function_callback(const void *const *const parameters, void *const *const returns) {
+    int data_length = ((const struct ArrayInputParameter_t *)parameters[0])->length;
+    const uint8_t *data = (const uint8_t *)((const struct ArrayInputParameter_t *)parameters[0])->data;
+    const float *matrix = (const float *)parameters[1];
+    int names_length = ((const struct ArrayInputParameter_t *)parameters[2])->length;
+    const char *const *names = (const char *const *)((const struct ArrayInputParameter_t *)parameters[2])->data;
+    int32_t *checksum = (int32_t *)returns[0];
+    bool *pose_present = &((struct NullableOutputParameter_t *)returns[1])->present;
+    double *pose_position_x = (double *)((void *const *)((void *const *)((struct NullableOutputParameter_t *)returns[1])->value)[0])[0];
+    double *pose_position_y = (double *)((void *const *)((void *const *)((struct NullableOutputParameter_t *)returns[1])->value)[0])[1];
+    int *pose_level = (int *)((void *const *)((struct NullableOutputParameter_t *)returns[1])->value)[1];
+    bool *pose_id_present = &((struct NullableOutputParameter_t *)((void *const *)((struct NullableOutputParameter_t *)returns[1])->value)[2])->present;
+    int32_t *pose_id = (int32_t *)((struct NullableOutputParameter_t *)((void *const *)((struct NullableOutputParameter_t *)returns[1])->value)[2])->value;
+    return upload_function(data_length, data, matrix, names_length, names, checksum, pose_present, pose_position_x, pose_position_y, pose_level, pose_id_present, pose_id);
+}
+
+static int humidity_sensor_callback(void *const output) {
+    bool *value_present = &((struct NullableOutputParameter_t *)output)->present;
+    double *value = (double *)((struct NullableOutputParameter_t *)output)->value;
+    return humidity_sensor(value_present, value);
+}
+
+static int position_sensor_callback(void *const output) {
+    double *value_x = (double *)((void *const *)output)[0];
+    double *value_y = (double *)((void *const *)output)[1];
+    return position_sensor(value_x, value_y);
+}
+
+enum ErrorCode register_machine(ClientHandle handle, int camera_fd, int log_fd, int microphone_fd) {
+    enum ErrorCode result;
+    result = SetName(handle, "Test \"machine\"");
+    if (result != NoError) return result;
+    result = RegisterEnum(handle, "level", level_values);
+    if (result != NoError) return result;
+    result = RegisterStruct(handle, "point", point_fields);
+    if (result != NoError) return result;
+    result = RegisterStruct(handle, "pose", pose_fields);
+    if (result != NoError) return result;
+    result = RegisterFunctionEx(handle, "move-to", move_to_function_parameters, move_to_function_returns, move_to_function_callback);
+    if (result != NoError) return result;
+    result = SetFunctionMetadata(handle, "move-to", "Moves to a point.\nSlowly.", NULL, (const char *const[]){"motion", NULL});
+    if (result != NoError) return result;
+    result = SetParameterMetadata(handle, "move-to", "speed", "How fast", "m/s", NULL);
+    if (result != NoError) return result;
+    result = RegisterFunctionEx(handle, "reset", reset_function_parameters, reset_function_returns, reset_function_callback);
+    if (result != NoError) return result;
+    result = RegisterFunctionEx(handle, "upload", upload_function_parameters, upload_function_returns, upload_function_callback);
+    if (result != NoError) return result;
+    result = RegisterSensorTyped(handle, "humidity", "double?", humidity_sensor_callback);
+    if (result != NoError) return result;
+    result = RegisterSensorTyped(handle, "position", "point", position_sensor_callback);
+    if (result != NoError) return result;
+    result = RegisterSensorEx(handle, "temperature", -40.0, 125.0, temperature_sensor);
+    if (result != NoError) return result;
+    result = SetSensorMetadata(handle, "temperature", NULL, "C", NULL);
+    if (result != NoError) return result;
+    result = RegisterAxisEx(handle, "x", -1.0, 1.0, "movement", "x", x_axis);
+    if (result != NoError) return result;
+    result = RegisterStreamEx(handle, "camera", "mjpeg", camera_fd, BufferFrames, 2);
+    if (result != NoError) return result;
+    result = SetStreamTransport(handle, "camera", TransportUdp);
+    if (result != NoError) return result;
+    result = RegisterStreamEx(handle, "log", "ndjson", log_fd, BufferNoDiscard, 0);
+    if (result != NoError) return result;
+    result = RegisterPcmStream(handle, "microphone", microphone_fd, BufferBytes, 4096, 48000, 2, SampleS16LE);
+    if (result != NoError) return result;
+    return NoError;
+}
@@ -0,0 +1,108 @@
+// Generated by codegen from the machine description of "Test \"machine\"". Do not edit.
+#ifndef MACHINE_H
+#define MACHINE_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif // def __cplusplus
+
+#include "client.h"
+
+/**
+* The values of the enum type "level".
+*/
+enum level {
+    LEVEL_LOW = 0,
+    LEVEL_HIGH = 1,
+    LEVEL_VERY_HIGH = 2,
+};
+
+/**
+* Moves to a point.
+* Slowly.
+* Implements the function "move-to".
+* @param label_present  part of label: string?
+* @param label  string?
+* @param level  level = "low"
+* @param note_present  part of note: string optional
+* @param note  string optional
+* @param speed  double [m/s] (How fast)
+* @param target_x  part of target: point
+* @param target_y  part of target: point
+* @param distances  (return) map<string, double>
+* @param path  (return) double[]
+* @param reached  (return) bool
+* @returns 0 on success, or nonzero on failure (see SetCallbackError)
+*/
+int move_to_function(bool label_present, const char *label, enum level level, bool note_present, const char *note, double speed, double target_x, double target_y, struct MapOutputParameter_t *distances, struct ArrayOutputParameter_t *path, bool *reached);
+
+/**
+* Implements the function "reset".
+* @returns 0 on success, or nonzero on failure (see SetCallbackError)
+*/
+int reset_function(void);
+
+/**
+* Implements the function "upload".
+* @param data_length  part of data: bytes
+* @param data  bytes
+* @param matrix  float[2][3]
+* @param names_length  part of names: string[]
+* @param names  string[]
+* @param checksum  (return) int
+* @param pose_present  (return) part of pose: pose?
+* @param pose_position_x  (return) part of pose: pose?
+* @param pose_position_y  (return) part of pose: pose?
+* @param pose_level  (return) part of pose: pose?
+* @param pose_id_present  (return) part of pose: pose?
+* @param pose_id  (return) part of pose: pose?
+* @returns 0 on success, or nonzero on failure (see SetCallbackError)
+*/
+int upload_function(int data_length, const uint8_t *data, const float *matrix, int names_length, const char *const *names, int32_t *checksum, bool *pose_present, double *pose_position_x, double *pose_position_y, int *pose_level, bool *pose_id_present, int32_t *pose_id);
+
+/**
+* Reads the sensor "humidity".
+* @param value_present  (return) part of value: double?
+* @param value  (return) double?
+* @returns 0 on success, or nonzero on failure (see SetCallbackError)
+*/
+int humidity_sensor(bool *value_present, double *value);
+
+/**
+* Reads the sensor "position".
+* @param value_x  (return) part of value: point
+* @param value_y  (return) part of value: point
+* @returns 0 on success, or nonzero on failure (see SetCallbackError)
+*/
+int position_sensor(double *value_x, double *value_y);
+
+/**
+* Reads the sensor "temperature".
+* @param value  (return) double
+* @returns 0 on success, or nonzero on failure (see SetCallbackError)
+*/
+int temperature_sensor(double *value);
+
+/**
+* Moves the axis "x".
+* @param value  The new value of the axis
+* @returns 0 on success, or nonzero on failure (see SetCallbackError)
+*/
+int x_axis(double value);
+
+/**
+* Sets the name of the machine, and registers its types, functions, sensors, axes, and streams,
+* with their metadata. Call this before ConnectToServer.
+* @param handle     The client handle
+* @param camera_fd  The file descriptor to read the stream "camera" from, or -1 to write it with StreamWrite
+* @param log_fd  The file descriptor to read the stream "log" from, or -1 to write it with StreamWrite
+* @param microphone_fd  The file descriptor to read the stream "microphone" from, or -1 to write it with StreamWrite
+* @returns enum ErrorCode success (Was everything registered successfully)
+*/
+enum ErrorCode register_machine(ClientHandle handle, int camera_fd, int log_fd, int microphone_fd);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // def __cplusplus
+
+#endif // ndef MACHINE_H
@@ -0,0 +1,58 @@
+//! Checks the code generated from tests/machine.json against tests/expected, and that it compiles
+//! against include/client.h.
+//! Set UPDATE_EXPECTED=1 when running this test to regenerate the expected code instead.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Runs codegen on tests/machine.json, writing to a directory of its own (since tests run in
+/// parallel), and returns the path of the output without its extension.
+fn generate(directory: &str) -> PathBuf {
+    let crate_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let directory = Path::new(env!("CARGO_TARGET_TMPDIR")).join(directory);
+    std::fs::create_dir_all(&directory).expect("Failed to create output directory");
+    let output = directory.join("machine");
+    let status = Command::new(env!("CARGO_BIN_EXE_codegen"))
+        .arg(crate_dir.join("tests/machine.json"))
+        .arg(&output)
+        .status()
+        .expect("Failed to run codegen");
+    assert!(status.success(), "codegen failed: {}", status);
+    output
+}
+
+#[test]
+fn generated_code_is_as_expected() {
+    let output = generate("expected");
+    let expected_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/expected");
+    for extension in ["h", "c"] {
+        let generated = std::fs::read_to_string(output.with_extension(extension)).expect("Failed to read generated code");
+        let expected = expected_dir.join(format!("machine.{}", extension));
+        if std::env::var_os("UPDATE_EXPECTED").is_some() {
+            std::fs::write(&expected, &generated).expect("Failed to write expected code");
+            continue;
+        }
+        let checked_in = std::fs::read_to_string(&expected).expect("Failed to read expected code");
+        assert!(
+            checked_in == generated,
+            "the code generated from tests/machine.json does not match tests/expected/machine.{}; \
+            if the change is intended, regenerate it with `UPDATE_EXPECTED=1 cargo test -p codegen --test generate`",
+            extension,
+        );
+    }
+}
+
+#[test]
+fn generated_code_compiles() {
+    let output = generate("compiles");
+    let include = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../include");
+    let compiler = std::env::var_os("CC").unwrap_or_else(|| "cc".into());
+    let status = Command::new(compiler)
+        .args(["-std=c11", "-Wall", "-Wextra", "-Wshadow", "-Werror", "-c"])
+        .arg("-I").arg(include)
+        .arg(output.with_extension("c"))
+        .arg("-o").arg(output.with_extension("o"))
+        .status()
+        .expect("Failed to run the C compiler");
+    assert!(status.success(), "the generated code does not compile: {}", status);
+}
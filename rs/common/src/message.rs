@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 use std::sync::atomic::{AtomicUsize, AtomicI64, Ordering};
 use std::net::TcpStream;
-use std::io::{Write, BufRead, BufReader};
+use std::io::{Read, Write, BufRead, BufReader};
 use std::time::Duration;
 use serde_json::value::{RawValue, to_raw_value};
 use polling::{Poller, Event};
@@ -33,6 +33,7 @@ impl Message {
 /// * the name of the field in the variant AND the json (must be the same),
 /// * the type of the field in the variant
 /// * a description of the field (used for error reporting when a field is not found).
+///
 /// After the braces are the serialized "message_type" value, whether or not a reply is expected,
 /// and (if it exists) the variant field that contains the message_id of the message this message is a reply to
 macro_rules! message_inner_enum_with_metadata {
@@ -80,7 +81,7 @@ macro_rules! message_inner_enum_with_metadata {
                 use $name::*;
                 let $reply_to = &None::<i64>;
                 match self {
-                    $( $variant { $($field),* } => {
+                    $( #[allow(unused_variables)] $variant { $($field),* } => {
                         // If this variant has reply_to, .into() will be i64 -> Option<i64>
                         // else it will use the above local variable, and .into() will be a no-op
                         (*$reply_to).into()
@@ -205,6 +206,7 @@ pub enum MessageInner {
         sensors: HashMap<String, Sensor>: "sensor names and descriptors",
         axes: HashMap<String, Axis>: "axis names and descriptors",
         streams: HashMap<String, Stream>: "stream names and descriptors",
+        types: HashMap<String, TypeDefinition>: "named type names and definitions",
     } = "machine_description" no_reply,
     /// Message from the server representing a request to call a function.
     FunctionCall {
@@ -245,11 +247,48 @@ pub enum MessageInner {
     Reset {} = "reset" no_reply,
     /// Message to/from the server representing that the sender has disconnected.
     Disconnect {} = "disconnect" no_reply,
-    /// Message to the server on a stream connection to identify the stream
+    /// Message to the server on a stream connection to identify the stream.
+    /// It is followed by the stream's data, as frames each preceded by a FrameHeader, unless the
+    /// stream's transport is udp, when the data is sent as datagrams (see FragmentHeader) from
+    /// udp_port to the server's stream port, and the connection only carries this message.
     StreamDescription {
         machine: String: "the name of the machine",
         stream: String: "the name of the stream",
+        udp_port: Option<u16>: "the client's UDP port the stream's datagrams are sent from, for (and only for) udp streams",
     } = "stream_descriptor" no_reply,
+    /// Message from the server to start (or resume) sending a stream's data.
+    /// Streams are started when the client connects.
+    StreamStart {
+        stream: String: "the name of the stream",
+    } = "stream_start" no_reply,
+    /// Message from the server to pause sending a stream's data, keeping what has been buffered.
+    StreamPause {
+        stream: String: "the name of the stream",
+    } = "stream_pause" no_reply,
+    /// Message from the server to stop sending a stream's data (e.g. because nobody is watching it),
+    /// discarding what has been buffered. When it is started again, it starts from a keyframe.
+    StreamStop {
+        stream: String: "the name of the stream",
+    } = "stream_stop" no_reply,
+    /// Message from the server to estimate the offset between its clock and the client's.
+    /// The client replies immediately, with its monotonic clock (as used by FrameHeader::timestamp).
+    ClockSync {
+        server_time: u64: "the server's clock when it sent the message, in microseconds",
+    } = "clock_sync" expects_reply,
+    /// Message to the server representing a reply to a clock sync.
+    /// The offset (client_time - server_time) is estimated as client_time minus the midpoint of
+    /// server_time and when the server received the reply, with at most half the round trip as error.
+    ClockSyncReply {
+        reply_to: i64: "message_id of the message this is a return of",
+        server_time: u64: "server_time of the clock sync",
+        client_time: u64: "the client's monotonic clock when it handled the clock sync, in microseconds",
+    } = "clock_sync_reply" no_reply,
+    /// Message from the server with its estimate of the clock offset (see ClockSyncReply),
+    /// so the client can convert between the clocks (see GetClockOffset).
+    ClockOffset {
+        offset: i64: "the client's clock minus the server's clock, in microseconds",
+        uncertainty: u64: "the maximum error of offset, in microseconds",
+    } = "clock_offset" no_reply,
     /// Message to/from the server representing a keepalive/"heartbeat" request/reply
     Heartbeat {
         is_reply: bool: "is this heartbeat a reply",
@@ -259,20 +298,76 @@ pub enum MessageInner {
 }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Function {
     pub parameters: HashMap<String, String>,
     pub returns: HashMap<String, String>,
+    /// Parameters that the server may omit (including those with a default).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub optional: Vec<String>,
+    /// Default values of parameters that the server may omit.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub defaults: HashMap<String, Box<RawValue>>,
+    #[serde(default, skip_serializing_if = "Metadata::is_empty")]
+    pub metadata: Metadata,
+    /// Metadata of parameters that have any.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub parameter_metadata: HashMap<String, Metadata>,
+    /// Metadata of returns that have any.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub return_metadata: HashMap<String, Metadata>,
+}
+
+/// Human-readable information about a function, parameter, sensor, axis, or stream.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    /// The unit of the value (e.g. "m/s", "degrees", or "A").
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub unit: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        self.description.is_empty() && self.unit.is_empty() && self.tags.is_empty()
+    }
+}
+
+/// Definition of a named type that functions and sensors can use by name.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TypeDefinition {
+    /// A JSON object with exactly the given fields.
+    Struct {
+        fields: Vec<Field>,
+    },
+    /// A JSON string that must be one of the given values.
+    Enum {
+        values: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Field {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub field_type: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Sensor {
     #[serde(rename = "type")]
     pub output_type: String,
-    #[serde(default)]
-    pub min: f64,
-    #[serde(default)]
-    pub max: f64,
+    /// The range of a double sensor, if it has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+    #[serde(default, skip_serializing_if = "Metadata::is_empty")]
+    pub metadata: Metadata,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -287,9 +382,11 @@ pub struct Axis {
     pub direction: String,
     #[serde(default)]
     pub group: String,
+    #[serde(default, skip_serializing_if = "Metadata::is_empty")]
+    pub metadata: Metadata,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
 pub enum BufferMethod {
     /// Buffer a certain number of frames, then discard
     Frames,
@@ -297,18 +394,127 @@ pub enum BufferMethod {
     Bytes,
     /// Do not discard any stream contents.
     /// Implies that the stream may only be connected to once.
+    #[default]
     NoDiscard,
 }
 
-impl Default for BufferMethod {
-    fn default() -> Self { Self::NoDiscard }
+/// How a stream's data is sent to the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// Over the stream's connection to the server's stream port. No data is lost, but a lost
+    /// packet delays the rest of the stream until it is resent.
+    #[default]
+    Tcp,
+    /// As datagrams to the server's stream port (see FragmentHeader). A lost datagram loses its
+    /// frame, but does not delay the rest of the stream.
+    Udp,
+}
+
+/// The format of a stream's data, which determines how it is split into frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamFormat {
+    /// Motion JPEG: each frame is one JPEG image.
+    Mjpeg,
+    /// H.264 in Annex B byte stream format: each frame is one access unit (all of
+    /// its NAL units, with their start codes).
+    H264,
+    /// Raw PCM audio (see PcmFormat): each frame is a whole number of samples for every channel.
+    Pcm,
+    /// Newline-delimited JSON: each frame is one record, with its newline.
+    Ndjson,
+}
+
+impl StreamFormat {
+    pub const NAMES: &'static [&'static str] = &["mjpeg", "h264", "pcm", "ndjson"];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            StreamFormat::Mjpeg => "mjpeg",
+            StreamFormat::H264 => "h264",
+            StreamFormat::Pcm => "pcm",
+            StreamFormat::Ndjson => "ndjson",
+        }
+    }
+}
+
+impl std::fmt::Display for StreamFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for StreamFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mjpeg" => Ok(StreamFormat::Mjpeg),
+            "h264" => Ok(StreamFormat::H264),
+            "pcm" => Ok(StreamFormat::Pcm),
+            "ndjson" => Ok(StreamFormat::Ndjson),
+            _ => Err(format!("unknown stream format {:?} (expected one of {})", s, StreamFormat::NAMES.join(", "))),
+        }
+    }
+}
+
+/// The layout of the samples of a pcm stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PcmFormat {
+    /// Samples per second, per channel.
+    pub sample_rate: u32,
+    /// The number of channels, whose samples are interleaved.
+    pub channels: u16,
+    pub sample_format: SampleFormat,
+}
+
+impl PcmFormat {
+    /// The length of one sample for every channel, in bytes.
+    pub fn frame_length(&self) -> usize {
+        usize::from(self.channels) * self.sample_format.length()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SampleFormat {
+    U8,
+    S16le,
+    S16be,
+    S32le,
+    S32be,
+    F32le,
+    F32be,
+}
+
+impl SampleFormat {
+    /// The length of one sample in bytes.
+    pub fn length(self) -> usize {
+        match self {
+            SampleFormat::U8 => 1,
+            SampleFormat::S16le | SampleFormat::S16be => 2,
+            SampleFormat::S32le | SampleFormat::S32be | SampleFormat::F32le | SampleFormat::F32be => 4,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Stream {
-    pub format: String,
+    pub format: StreamFormat,
+    /// The sample layout, for (and only for) pcm streams.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pcm: Option<PcmFormat>,
     #[serde(default)]
     pub buffer_method: BufferMethod,
+    /// The number of frames (for Frames) or bytes (for Bytes) to buffer.
+    /// Unused for NoDiscard.
+    #[serde(default)]
+    pub buffer_size: u64,
+    #[serde(default)]
+    pub transport: Transport,
+    #[serde(default, skip_serializing_if = "Metadata::is_empty")]
+    pub metadata: Metadata,
 }
 
 #[allow(dead_code)]
@@ -347,10 +553,10 @@ impl<'de> serde::de::Deserialize<'de> for Message {
         // Get the message id, or error.
         let message_id = json.remove("message_id");
         let message_id = message_id.map(|message_id|
-            serde_json::from_str::<i64>(message_id.get()).or_else(|_| Err(D::Error::invalid_type(
+            serde_json::from_str::<i64>(message_id.get()).map_err(|_| D::Error::invalid_type(
                 Unexpected::Other("TODO: unknown"),
                 &"an integer",
-            )))
+            ))
         ).unwrap_or(Ok(-1))?;
         // Get the message type, or error.
         let message_type = json.remove("message_type")
@@ -368,7 +574,7 @@ impl<'de> serde::de::Deserialize<'de> for Message {
                 MESSAGE_INNER_VARIANT_NAMES,
             ))?;
         // Complete deserialization.
-        Ok(inner_deserializer(message_id, json).map_err(DeserializeError::into_serde)?)
+        inner_deserializer(message_id, json).map_err(DeserializeError::into_serde)
     }
 }
 
@@ -379,13 +585,20 @@ lazy_static::lazy_static! {
 }
 
 pub fn try_read_message(stream: &mut BufReader<TcpStream>, timeout: Option<Duration>) -> Result<Option<Message>, Box<dyn std::error::Error + Send + Sync + 'static>> {
-    let poller: &Poller = &*POLLER;
+    // A message may already have been read into the buffer along with the previous one,
+    // in which case the socket will not become readable for it.
+    if !stream.buffer().is_empty() {
+        let mut msg_buf = String::with_capacity(4096);
+        stream.read_line(&mut msg_buf)?;
+        return Ok(Some(serde_json::from_str::<Message>(&msg_buf)?));
+    }
+    let poller: &Poller = &POLLER;
     let key = KEY.fetch_add(1, Ordering::Relaxed);
     poller.add(stream.get_ref(), Event::readable(key))?;
     let mut events = Vec::with_capacity(1);
     poller.wait(&mut events, timeout)?;
     poller.delete(stream.get_ref())?;
-    if events.len() > 0 {
+    if !events.is_empty() {
         let mut msg_buf = String::with_capacity(4096);
         stream.read_line(&mut msg_buf)?;
         Ok(Some(serde_json::from_str::<Message>(&msg_buf)?))
@@ -401,3 +614,332 @@ pub fn try_write_message(mut stream: impl Write, msg: &Message) -> Result<(), Bo
     stream.write_all(&data)?;
     Ok(())
 }
+
+/// Precedes each frame of stream data, after the StreamDescription message on a stream connection.
+/// Sent as four big-endian u64s, in field order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    /// The length of the frame's data in bytes.
+    pub length: u64,
+    /// Counts the frames of the stream from 0, including those discarded by the buffer method,
+    /// so a gap means frames were discarded.
+    pub sequence: u64,
+    /// When the client captured the frame, on its monotonic clock (CLOCK_MONOTONIC), in
+    /// microseconds. The server can convert it to its own clock with ClockSync.
+    pub timestamp: u64,
+    /// A combination of the FrameHeader::FLAG_ constants.
+    pub flags: u64,
+}
+
+impl FrameHeader {
+    pub const SIZE: usize = 32;
+    /// The frame can be decoded without the frames before it (for h264, it contains an IDR
+    /// picture; frames of other formats always can).
+    pub const FLAG_KEYFRAME: u64 = 1;
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0..8].copy_from_slice(&self.length.to_be_bytes());
+        bytes[8..16].copy_from_slice(&self.sequence.to_be_bytes());
+        bytes[16..24].copy_from_slice(&self.timestamp.to_be_bytes());
+        bytes[24..32].copy_from_slice(&self.flags.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        let field = |i: usize| u64::from_be_bytes(bytes[i..i + 8].try_into().unwrap());
+        Self { length: field(0), sequence: field(8), timestamp: field(16), flags: field(24) }
+    }
+}
+
+pub fn write_frame(mut stream: impl Write, header: &FrameHeader, data: &[u8]) -> std::io::Result<()> {
+    stream.write_all(&header.to_bytes())?;
+    stream.write_all(data)
+}
+
+/// Returns None if the stream ends before the next frame.
+pub fn read_frame(mut stream: impl Read) -> std::io::Result<Option<(FrameHeader, Vec<u8>)>> {
+    let mut bytes = [0; FrameHeader::SIZE];
+    let mut read = 0;
+    while read < bytes.len() {
+        match stream.read(&mut bytes[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(len) => read += len,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    let header = FrameHeader::from_bytes(&bytes);
+    let mut data = vec![];
+    stream.by_ref().take(header.length).read_to_end(&mut data)?;
+    if data.len() as u64 != header.length {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Some((header, data)))
+}
+
+/// Precedes each datagram of a udp stream (see Transport), which carries the part of a frame
+/// starting at offset. Sent as six big-endian u64s: packet_sequence, the frame's FrameHeader, and offset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FragmentHeader {
+    /// Counts the stream's datagrams from 0, so a gap means datagrams were lost (or reordered).
+    pub packet_sequence: u64,
+    pub frame: FrameHeader,
+    pub offset: u64,
+}
+
+impl FragmentHeader {
+    pub const SIZE: usize = 48;
+    /// The maximum length of a datagram, including its header, chosen so that datagrams are not
+    /// fragmented by IP on any link (IPv6 requires an MTU of at least 1280 bytes).
+    pub const MAX_DATAGRAM_LENGTH: usize = 1200;
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0..8].copy_from_slice(&self.packet_sequence.to_be_bytes());
+        bytes[8..40].copy_from_slice(&self.frame.to_bytes());
+        bytes[40..48].copy_from_slice(&self.offset.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        Self {
+            packet_sequence: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            frame: FrameHeader::from_bytes(bytes[8..40].try_into().unwrap()),
+            offset: u64::from_be_bytes(bytes[40..48].try_into().unwrap()),
+        }
+    }
+}
+
+/// Splits a frame into datagrams (see FragmentHeader), numbered from *packet_sequence,
+/// which is advanced past them. A frame with no data is sent as one datagram.
+pub fn fragment_frame(header: &FrameHeader, data: &[u8], packet_sequence: &mut u64) -> Vec<Vec<u8>> {
+    let payload_length = FragmentHeader::MAX_DATAGRAM_LENGTH - FragmentHeader::SIZE;
+    let chunks: Vec<&[u8]> = match data.is_empty() {
+        true => vec![&[]],
+        false => data.chunks(payload_length).collect(),
+    };
+    chunks.into_iter().enumerate().map(|(i, chunk)| {
+        let fragment = FragmentHeader { packet_sequence: *packet_sequence, frame: *header, offset: (i * payload_length) as u64 };
+        *packet_sequence += 1;
+        let mut datagram = Vec::with_capacity(FragmentHeader::SIZE + chunk.len());
+        datagram.extend_from_slice(&fragment.to_bytes());
+        datagram.extend_from_slice(chunk);
+        datagram
+    }).collect()
+}
+
+/// A frame of a udp stream that has been partly received.
+struct PartialFrame {
+    header: FrameHeader,
+    /// The payloads of the datagrams received, by offset, so that memory is only used for data
+    /// that has arrived, and duplicates are not counted twice.
+    fragments: std::collections::BTreeMap<u64, Vec<u8>>,
+    /// The number of bytes of data received.
+    received: u64,
+}
+
+impl PartialFrame {
+    /// Joins the fragments of a frame whose data has all been received, or returns None if they
+    /// overlap (which datagrams from fragment_frame do not).
+    fn assemble(self) -> Option<Vec<u8>> {
+        let mut data = Vec::with_capacity(self.received as usize);
+        for (offset, fragment) in self.fragments {
+            if offset != data.len() as u64 {
+                return None;
+            }
+            data.extend_from_slice(&fragment);
+        }
+        Some(data)
+    }
+}
+
+/// Reassembles the frames of a udp stream from its datagrams (see fragment_frame), counting
+/// what was lost.
+#[derive(Default)]
+pub struct Reassembler {
+    /// Frames that have been partly received, by sequence number.
+    partial: std::collections::BTreeMap<u64, PartialFrame>,
+    /// The sequence number after the last frame completed (or abandoned). Older frames are ignored.
+    next_sequence: u64,
+    /// The packet sequence number after the newest datagram received.
+    next_packet_sequence: u64,
+    /// The packet sequence numbers (up to MAX_MISSING_PACKETS of the newest) that were skipped,
+    /// so that only a late datagram, and not a duplicate, is taken off packets_lost.
+    missing_packets: std::collections::BTreeSet<u64>,
+    /// The number of datagrams that were not received (yet: a late datagram is taken off again).
+    pub packets_lost: u64,
+    /// The number of frames that were abandoned, because a later frame was completed first, or
+    /// too many later frames were partly received.
+    pub frames_lost: u64,
+}
+
+impl Reassembler {
+    /// Frames longer than this are ignored, so that a corrupt datagram cannot use unbounded memory.
+    pub const MAX_FRAME_LENGTH: u64 = 64 * 1024 * 1024;
+    /// The number of frames that may be partly received at once. The oldest is abandoned to make room.
+    pub const MAX_PARTIAL_FRAMES: usize = 4;
+    /// The number of skipped packet sequence numbers remembered. A datagram that arrives later than
+    /// this many others stays counted as lost.
+    pub const MAX_MISSING_PACKETS: u64 = 1024;
+
+    /// Adds a datagram. Returns the frame it completed, if any.
+    /// Invalid datagrams, and datagrams of frames that were completed or abandoned, are ignored.
+    pub fn push(&mut self, datagram: &[u8]) -> Option<(FrameHeader, Vec<u8>)> {
+        let (header, payload) = datagram.split_first_chunk::<{ FragmentHeader::SIZE }>()?;
+        let header = FragmentHeader::from_bytes(header);
+        // Sequence numbers start at 0, so the last ones can only come from a corrupt datagram.
+        let next_packet_sequence = header.packet_sequence.checked_add(1)?;
+        let next_sequence = header.frame.sequence.checked_add(1)?;
+        if header.packet_sequence >= self.next_packet_sequence {
+            self.packets_lost += header.packet_sequence - self.next_packet_sequence;
+            let remembered = header.packet_sequence.saturating_sub(Self::MAX_MISSING_PACKETS).max(self.next_packet_sequence);
+            self.missing_packets.extend(remembered..header.packet_sequence);
+            while self.missing_packets.len() as u64 > Self::MAX_MISSING_PACKETS {
+                self.missing_packets.pop_first();
+            }
+            self.next_packet_sequence = next_packet_sequence;
+        } else if self.missing_packets.remove(&header.packet_sequence) {
+            self.packets_lost -= 1;
+        }
+
+        let frame = header.frame;
+        let end = header.offset.checked_add(payload.len() as u64)?;
+        if frame.sequence < self.next_sequence || frame.length > Self::MAX_FRAME_LENGTH || end > frame.length {
+            return None;
+        }
+        let partial = self.partial.entry(frame.sequence).or_insert_with(|| PartialFrame {
+            header: frame,
+            fragments: Default::default(),
+            received: 0,
+        });
+        if partial.header != frame || partial.fragments.contains_key(&header.offset) {
+            return None;
+        }
+        partial.fragments.insert(header.offset, payload.to_vec());
+        partial.received += payload.len() as u64;
+        if partial.received < frame.length {
+            while self.partial.len() > Self::MAX_PARTIAL_FRAMES {
+                let (oldest, _) = self.partial.pop_first().unwrap();
+                self.frames_lost += 1;
+                self.next_sequence = oldest + 1;
+            }
+            return None;
+        }
+
+        let complete = self.partial.remove(&frame.sequence).unwrap();
+        // Frames are sent in order, so older incomplete frames will not be completed.
+        let abandoned = self.partial.split_off(&frame.sequence);
+        self.frames_lost += std::mem::replace(&mut self.partial, abandoned).len() as u64;
+        self.next_sequence = next_sequence;
+        Some((complete.header, complete.assemble()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAYLOAD_LENGTH: usize = FragmentHeader::MAX_DATAGRAM_LENGTH - FragmentHeader::SIZE;
+
+    /// Fragments a frame of length bytes with the given sequence number.
+    fn fragments(sequence: u64, length: usize, packet_sequence: &mut u64) -> (FrameHeader, Vec<u8>, Vec<Vec<u8>>) {
+        let header = FrameHeader { length: length as u64, sequence, timestamp: 1234, flags: FrameHeader::FLAG_KEYFRAME };
+        let data: Vec<u8> = (0..length).map(|i| i as u8).collect();
+        let datagrams = fragment_frame(&header, &data, packet_sequence);
+        (header, data, datagrams)
+    }
+
+    #[test]
+    fn frames_are_fragmented_into_datagrams() {
+        let mut packet_sequence = 5;
+        for (length, count) in [(0, 1), (1, 1), (PAYLOAD_LENGTH, 1), (PAYLOAD_LENGTH + 1, 2), (3 * PAYLOAD_LENGTH, 3)] {
+            let (_, _, datagrams) = fragments(0, length, &mut packet_sequence);
+            assert_eq!(datagrams.len(), count, "length {}", length);
+            assert!(datagrams.iter().all(|datagram| datagram.len() <= FragmentHeader::MAX_DATAGRAM_LENGTH));
+        }
+        assert_eq!(packet_sequence, 5 + 1 + 1 + 1 + 2 + 3);
+    }
+
+    #[test]
+    fn reordered_datagrams_are_reassembled() {
+        let mut reassembler = Reassembler::default();
+        let (header, data, datagrams) = fragments(0, 2 * PAYLOAD_LENGTH + 10, &mut 0);
+        assert_eq!(reassembler.push(&datagrams[2]), None);
+        assert_eq!(reassembler.packets_lost, 2);
+        assert_eq!(reassembler.push(&datagrams[0]), None);
+        assert_eq!(reassembler.push(&datagrams[1]), Some((header, data)));
+        assert_eq!((reassembler.packets_lost, reassembler.frames_lost), (0, 0));
+    }
+
+    #[test]
+    fn duplicate_datagrams_are_ignored() {
+        let mut reassembler = Reassembler::default();
+        let mut packet_sequence = 0;
+        let (_, _, first) = fragments(0, 2 * PAYLOAD_LENGTH, &mut packet_sequence);
+        // A datagram of the first frame is lost.
+        packet_sequence += 1;
+        let (header, data, second) = fragments(1, 10, &mut packet_sequence);
+        assert_eq!(reassembler.push(&first[0]), None);
+        assert_eq!(reassembler.push(&first[0]), None);
+        assert_eq!(reassembler.push(&second[0]), Some((header, data)));
+        assert_eq!((reassembler.packets_lost, reassembler.frames_lost), (2, 1));
+        // Duplicates of datagrams that were received do not take lost ones off.
+        assert_eq!(reassembler.push(&first[0]), None);
+        assert_eq!(reassembler.push(&second[0]), None);
+        assert_eq!(reassembler.packets_lost, 2);
+        // But a late one does, even though its frame was abandoned.
+        assert_eq!(reassembler.push(&first[1]), None);
+        assert_eq!(reassembler.packets_lost, 1);
+    }
+
+    #[test]
+    fn partial_frames_are_limited() {
+        let mut reassembler = Reassembler::default();
+        let mut packet_sequence = 0;
+        let partial: Vec<_> = (0..=Reassembler::MAX_PARTIAL_FRAMES as u64)
+            .map(|sequence| fragments(sequence, 2 * PAYLOAD_LENGTH, &mut packet_sequence))
+            .collect();
+        for (_, _, datagrams) in &partial {
+            assert_eq!(reassembler.push(&datagrams[0]), None);
+        }
+        assert_eq!(reassembler.frames_lost, 1);
+        // The oldest frame was abandoned, and the others can still be completed.
+        assert_eq!(reassembler.push(&partial[0].2[1]), None);
+        let (header, data, datagrams) = &partial[1];
+        assert_eq!(reassembler.push(&datagrams[1]), Some((*header, data.clone())));
+    }
+
+    #[test]
+    fn invalid_datagrams_are_ignored() {
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(&[0; FragmentHeader::SIZE - 1]), None);
+        let header = FragmentHeader {
+            packet_sequence: 0,
+            frame: FrameHeader { length: 4, sequence: 0, timestamp: 0, flags: 0 },
+            offset: 2,
+        };
+        let datagram = [&header.to_bytes()[..], &[1, 2, 3]].concat();
+        assert_eq!(reassembler.push(&datagram), None);
+        let header = FragmentHeader { frame: FrameHeader { length: Reassembler::MAX_FRAME_LENGTH + 1, ..header.frame }, ..header };
+        assert_eq!(reassembler.push(&header.to_bytes()), None);
+    }
+
+    #[test]
+    fn last_sequence_numbers_are_ignored() {
+        let mut reassembler = Reassembler::default();
+        let header = FragmentHeader {
+            packet_sequence: u64::MAX,
+            frame: FrameHeader { length: 0, sequence: 0, timestamp: 0, flags: 0 },
+            offset: 0,
+        };
+        assert_eq!(reassembler.push(&header.to_bytes()), None);
+        assert_eq!(reassembler.packets_lost, 0);
+        let header = FragmentHeader { packet_sequence: 0, frame: FrameHeader { sequence: u64::MAX, ..header.frame }, ..header };
+        assert_eq!(reassembler.push(&header.to_bytes()), None);
+        // Neither was taken as the newest, so the frames after them are still reassembled.
+        let (header, data, datagrams) = fragments(0, 10, &mut 0);
+        assert_eq!(reassembler.push(&datagrams[0]), Some((header, data)));
+    }
+}
@@ -1,3 +1,40 @@
+use std::ptr::NonNull;
+
+/// Conversion to an Option, used by unwrap_or_return!.
+/// Raw pointers are converted to None if they are null.
+pub trait IntoOption {
+    type Item;
+    fn into_option(self) -> Option<Self::Item>;
+}
+
+impl<T> IntoOption for Option<T> {
+    type Item = T;
+    fn into_option(self) -> Option<T> {
+        self
+    }
+}
+
+impl<T, E> IntoOption for Result<T, E> {
+    type Item = T;
+    fn into_option(self) -> Option<T> {
+        self.ok()
+    }
+}
+
+impl<T> IntoOption for *const T {
+    type Item = NonNull<T>;
+    fn into_option(self) -> Option<NonNull<T>> {
+        NonNull::new(self as *mut T)
+    }
+}
+
+impl<T> IntoOption for *mut T {
+    type Item = NonNull<T>;
+    fn into_option(self) -> Option<NonNull<T>> {
+        NonNull::new(self)
+    }
+}
+
 #[macro_export]
 macro_rules! unwrap_or_return {
     ( $value:expr, $retval:expr , with_message($e:pat) $( $msg:tt )* ) => {
@@ -12,9 +49,8 @@ macro_rules! unwrap_or_return {
         }
     };
     ( $value:expr, $retval:expr $(, $( with_message $( $msg:tt )* )? )? ) => {
-        // Option<T> -> Option<Option<T>> -> Option<T>
-        // Result<T> -> Result<Option<T>> -> Option<T>
-        match ($value).map(Some).unwrap_or_default() {
+        // Option<T>, Result<T, E>, or a (nullable) raw pointer -> Option<T>
+        match $crate::util::IntoOption::into_option($value) {
             Some(x) => x,
             None => {
                 $( $( eprintln!($($msg)*); )? )?
@@ -1,6 +1,5 @@
-use std::io::Read;
 use std::{collections::HashMap, io::BufReader};
-use std::net::{TcpListener, ToSocketAddrs};
+use std::net::{SocketAddr, TcpListener, UdpSocket};
 //use std::thread;
 use serde_json::value::{RawValue, to_raw_value};
 use common::message::*;
@@ -8,6 +7,8 @@ use common::message::*;
 fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
     let srv = TcpListener::bind("localhost:45575").unwrap();
     let stream_srv = TcpListener::bind("localhost:45577").unwrap();
+    // Udp streams send their data to the same address as their stream connection.
+    let udp_stream_srv = UdpSocket::bind(stream_srv.local_addr()?).unwrap();
 //    let mut threads = vec![];
 
 //    loop {
@@ -24,12 +25,16 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
                 }
             };
             dbg!(&machine_description);
-            let (name, functions, sensors, axes, streams) = match machine_description.unwrap() {
-                Message{inner: MessageInner::MachineDescription { name, functions, sensors, axes, streams }, ..} => {
+            let machine_description = machine_description.unwrap();
+            print_machine_description(&machine_description);
+            let (_name, _functions, _sensors, _axes, streams) = match machine_description {
+                Message{inner: MessageInner::MachineDescription { name, functions, sensors, axes, streams, .. }, ..} => {
                     (name, functions, sensors, axes, streams)
                 },
                 _ => panic!("no stream"),
             };
+            // The streams whose data is sent as datagrams, by the address they are sent from.
+            let mut udp_streams = HashMap::<SocketAddr, String>::new();
             for _ in 0..streams.len() {
                 // let (_, stream) = streams.iter().next().unwrap();
                 // let addr = format!("http://{}:{}", stream.address, stream.port);
@@ -41,19 +46,25 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
                 let msg = try_read_message(&mut stream_read_stream, None)?;
                 let msg = msg.unwrap();
                 let stream_name = match msg.inner {
-                    MessageInner::StreamDescription { machine, stream: stream_name } => stream_name,
+                    MessageInner::StreamDescription { machine: _, stream: stream_name, udp_port } => {
+                        if let Some(udp_port) = udp_port {
+                            udp_streams.insert(SocketAddr::new(stream_addr.ip(), udp_port), stream_name.clone());
+                        }
+                        stream_name
+                    }
                     _ => unreachable!("should have a stream"),
                 };
-                let stream_thread = std::thread::spawn(move || {
-                    let mut buf = vec![0; 4096];
+                // For a udp stream, there is no data on the connection, but it still ends with the stream.
+                let _stream_thread = std::thread::spawn(move || {
+                    let mut next_sequence = 0;
                     loop {
-                        match stream_read_stream.read(&mut buf[..]) {
-                            Ok(0) => {
+                        match read_frame(&mut stream_read_stream) {
+                            Ok(None) => {
                                 println!("EOF on stream {stream_name:?}");
                                 break;
                             }
-                            Ok(n) => {
-                                println!("{n} bytes on stream {stream_name:?}");
+                            Ok(Some((header, _data))) => {
+                                print_frame(&stream_name, &header, &mut next_sequence);
                             }
                             Err(e) => {
                                 println!("Error on stream {stream_name:?}: {e:?}");
@@ -64,6 +75,62 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
                 });
             }
 
+            if !udp_streams.is_empty() {
+                let _udp_stream_thread = std::thread::spawn(move || {
+                    let mut reassemblers = HashMap::<SocketAddr, (Reassembler, u64)>::new();
+                    let mut datagram = [0; FragmentHeader::MAX_DATAGRAM_LENGTH];
+                    loop {
+                        let (length, addr) = match udp_stream_srv.recv_from(&mut datagram) {
+                            Ok(received) => received,
+                            Err(e) => {
+                                println!("Error on udp streams: {:?}", e);
+                                break;
+                            }
+                        };
+                        let Some(stream_name) = udp_streams.get(&addr) else {
+                            println!("Datagram from unknown address {}", addr);
+                            continue;
+                        };
+                        let (reassembler, next_sequence) = reassemblers.entry(addr).or_default();
+                        let lost = (reassembler.packets_lost, reassembler.frames_lost);
+                        if let Some((header, _data)) = reassembler.push(&datagram[..length]) {
+                            print_frame(stream_name, &header, next_sequence);
+                        }
+                        if (reassembler.packets_lost, reassembler.frames_lost) != lost {
+                            println!(
+                                "{} datagrams and {} incomplete frames lost so far on stream {stream_name:?}",
+                                reassembler.packets_lost, reassembler.frames_lost,
+                            );
+                        }
+                    }
+                });
+            }
+
+            // Keeps the estimate from the shortest round trip, which has the smallest uncertainty.
+            let server_epoch = std::time::Instant::now();
+            let server_clock = || server_epoch.elapsed().as_micros() as u64;
+            let mut clock_offset = None::<(i64, u64)>;
+            for _ in 0..5 {
+                let msg = Message::new(MessageInner::ClockSync { server_time: server_clock() });
+                try_write_message(&write_stream, &msg)?;
+                let (server_time, client_time) = loop {
+                    let Some(reply) = try_read_message(&mut read_stream, None)? else { continue };
+                    match reply.inner {
+                        MessageInner::ClockSyncReply { server_time, client_time, .. } => break (server_time, client_time),
+                        // e.g. a heartbeat
+                        inner => println!("Ignoring message while syncing clocks: {:?}", inner),
+                    }
+                };
+                let received = server_clock();
+                let uncertainty = (received - server_time) / 2;
+                let offset = client_time as i64 - (server_time + uncertainty) as i64;
+                if clock_offset.is_none_or(|(_, best)| uncertainty < best) {
+                    clock_offset = Some((offset, uncertainty));
+                }
+            }
+            let (offset, uncertainty) = clock_offset.unwrap();
+            println!("Client clock offset: {} us (+/- {} us)", offset, uncertainty);
+            try_write_message(&write_stream, &Message::new(MessageInner::ClockOffset { offset, uncertainty }))?;
 
             let msg = Message::new(
                 MessageInner::FunctionCall {
@@ -151,6 +218,16 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
             dbg!(&reply);
 
 
+            // Streams start when the client connects; pause and resume them.
+            for name in sorted_keys(&streams) {
+                for msg in [MessageInner::StreamPause { stream: name.clone() }, MessageInner::StreamStart { stream: name.clone() }] {
+                    let msg = Message::new(msg);
+                    dbg!(&msg);
+                    try_write_message(&write_stream, &msg)?;
+                    // No reply expected
+                }
+            }
+
             let msg = Message::new(
                 MessageInner::Reset {},
             );
@@ -172,3 +249,91 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
 
     Ok(())
 }
+
+/// Prints a frame received on a stream, after reporting a gap in the sequence numbers before it
+/// (frames discarded by the client's buffer method, or for a udp stream, also frames lost entirely).
+fn print_frame(stream_name: &str, header: &FrameHeader, next_sequence: &mut u64) {
+    if header.sequence > *next_sequence {
+        println!("{} frames discarded on stream {:?}", header.sequence - *next_sequence, stream_name);
+    }
+    *next_sequence = header.sequence + 1;
+    let keyframe = if header.flags & FrameHeader::FLAG_KEYFRAME != 0 { ", keyframe" } else { "" };
+    println!(
+        "frame {} ({} bytes{keyframe}, captured at {} us) on stream {stream_name:?}",
+        header.sequence, header.length, header.timestamp,
+    );
+}
+
+/// Formats an item's metadata as e.g. " [m/s] (description) #tag1 #tag2".
+fn format_metadata(metadata: &Metadata) -> String {
+    let mut s = String::new();
+    if !metadata.unit.is_empty() {
+        s += &format!(" [{}]", metadata.unit);
+    }
+    if !metadata.description.is_empty() {
+        s += &format!(" ({})", metadata.description);
+    }
+    for tag in &metadata.tags {
+        s += &format!(" #{}", tag);
+    }
+    s
+}
+
+fn sorted_keys<V>(map: &HashMap<String, V>) -> Vec<&String> {
+    let mut keys: Vec<&String> = map.keys().collect();
+    keys.sort();
+    keys
+}
+
+/// Prints a human-readable summary of a machine description.
+fn print_machine_description(machine_description: &Message) {
+    let MessageInner::MachineDescription { name, functions, sensors, axes, streams, types } = &machine_description.inner else {
+        return println!("Not a machine description: {:?}", machine_description);
+    };
+    println!("Machine {:?}", name);
+    for name in sorted_keys(types) {
+        println!("  type {}: {:?}", name, types[name]);
+    }
+    for name in sorted_keys(functions) {
+        let function = &functions[name];
+        println!("  function {}{}", name, format_metadata(&function.metadata));
+        let no_metadata = Metadata::default();
+        for parameter in sorted_keys(&function.parameters) {
+            let metadata = function.parameter_metadata.get(parameter).unwrap_or(&no_metadata);
+            println!("    parameter {}: {}{}", parameter, function.parameters[parameter], format_metadata(metadata));
+        }
+        for r#return in sorted_keys(&function.returns) {
+            let metadata = function.return_metadata.get(r#return).unwrap_or(&no_metadata);
+            println!("    return {}: {}{}", r#return, function.returns[r#return], format_metadata(metadata));
+        }
+    }
+    for name in sorted_keys(sensors) {
+        let sensor = &sensors[name];
+        let range = match (sensor.min, sensor.max) {
+            (Some(min), Some(max)) => format!(" in [{}, {}]", min, max),
+            _ => String::new(),
+        };
+        println!("  sensor {}: {}{}{}", name, sensor.output_type, range, format_metadata(&sensor.metadata));
+    }
+    for name in sorted_keys(axes) {
+        let axis = &axes[name];
+        println!("  axis {}: {} in [{}, {}]{}", name, axis.input_type, axis.min, axis.max, format_metadata(&axis.metadata));
+    }
+    for name in sorted_keys(streams) {
+        let stream = &streams[name];
+        let buffer = match stream.buffer_method {
+            BufferMethod::Frames => format!(", buffering {} frames", stream.buffer_size),
+            BufferMethod::Bytes => format!(", buffering {} bytes", stream.buffer_size),
+            BufferMethod::NoDiscard => String::new(),
+        };
+        let pcm = match &stream.pcm {
+            Some(pcm) => format!(" ({} Hz, {} channels, {:?})", pcm.sample_rate, pcm.channels, pcm.sample_format),
+            None => String::new(),
+        };
+        let transport = match stream.transport {
+            Transport::Tcp => "",
+            Transport::Udp => " over udp",
+        };
+        println!("  stream {}: {}{}{}{}{}", name, stream.format, pcm, buffer, transport, format_metadata(&stream.metadata));
+    }
+}
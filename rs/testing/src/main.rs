@@ -1,7 +1,5 @@
-use std::{collections::HashMap, io::BufReader};
-use std::net::{TcpListener, ToSocketAddrs};
-//use std::thread;
-use serde_json::value::{RawValue, to_raw_value};
+use std::collections::HashMap;
+use serde_json::value::to_raw_value;
 use common::message::*;
 
 fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
@@ -10,35 +8,54 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
         inner: MessageInner::MachineDescription {
             name: "machine name".to_owned(),
             functions: HashMap::from(
-                [("test".into(), Function{ parameters: [].into(), returns: [].into() })]
+                [("test".into(), Function{ parameters: [].into(), returns: [].into(), ..Default::default() })]
             ),
             sensors: HashMap::from(
-                [("test".into(), Sensor{ output_type: "double".into(), min: -1.0, max: 1.0 })]
+                [("test".into(), Sensor{
+                    output_type: "double".into(), min: Some(-1.0), max: Some(1.0),
+                    metadata: Metadata { description: "test sensor".into(), unit: "m/s".into(), tags: vec!["test".into()] },
+                })]
             ),
             axes: HashMap::from(
                 [
-                    ("test1".into(), Axis{ input_type: "double".into(), min: 0.0, max: 1.0, group: "movement".into(), direction: "y".into() }),
-                    ("test2".into(), Axis{ input_type: "double".into(), min: -1.0, max: 1.0, group: "movement".into(), direction: "x".into() }),
+                    ("test1".into(), Axis{ input_type: "double".into(), min: 0.0, max: 1.0, group: "movement".into(), direction: "y".into(), metadata: Default::default() }),
+                    ("test2".into(), Axis{ input_type: "double".into(), min: -1.0, max: 1.0, group: "movement".into(), direction: "x".into(), metadata: Default::default() }),
                 ]
             ),
             streams: HashMap::from(
                 [("test".into(), Stream{
-                    format: "mjpeg".into(),
+                    format: StreamFormat::Mjpeg,
+                    pcm: None,
                     buffer_method: BufferMethod::Frames,
+                    buffer_size: 30,
+                    transport: Transport::Tcp,
+                    metadata: Default::default(),
+                })]
+            ),
+            types: HashMap::from(
+                [("pose".into(), TypeDefinition::Struct {
+                    fields: vec![
+                        Field { name: "x".into(), field_type: "double".into() },
+                        Field { name: "y".into(), field_type: "double".into() },
+                        Field { name: "theta".into(), field_type: "double".into() },
+                    ],
+                }),
+                ("speed".into(), TypeDefinition::Enum {
+                    values: vec!["low".into(), "medium".into(), "high".into()],
                 })]
             ),
         },
     };
-    dbg!(to_raw_value(&msg));
+    dbg!(to_raw_value(&msg)?);
     let msg = Message{
         message_id: 4096,
         inner: MessageInner::AxisChange { name: "xAxis".into(), value: 3.0 },
     };
-    dbg!(to_raw_value(&msg));
+    dbg!(to_raw_value(&msg)?);
     let msg = Message{
         message_id: 4096,
-        inner: MessageInner::StreamDescription { machine: "machine name".into(), stream: "stream name".into() },
+        inner: MessageInner::StreamDescription { machine: "machine name".into(), stream: "stream name".into(), udp_port: None },
     };
-    dbg!(to_raw_value(&msg));
+    dbg!(to_raw_value(&msg)?);
     Ok(())
 }
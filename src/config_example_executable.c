@@ -0,0 +1,117 @@
+#include <math.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <stdbool.h>
+#include <string.h>
+#include "client.h"
+#include "unistd.h"
+
+// The functions, sensors, axes, and streams of this example are declared in example_config.toml,
+// and their callbacks are found by name with lookup_callback.
+// Usage: config_example [config file] [--export]
+// With --export, prints the machine description instead of connecting to the server.
+
+// Signature: (int x, int y) -> (int product)
+// so parameters[0] points to a const int x
+// so parameters[1] points to a const int y
+// so parameters[2] is NULL
+// so returns[0] points to an int product
+// so returns[1] is NULL
+void multiply_callback(const void *const*const parameters, void *const*const returns) {
+    int x = *(const int*)parameters[0];
+    int y = *(const int*)parameters[1];
+    int *product = (int*)returns[0];
+    *product = x * y;
+}
+
+// Struct pose {double x, double y}
+// Signature: (pose pose) -> (double distance)
+// so parameters[0] points to a const void *const[2], whose elements point to const doubles x and y
+// so parameters[1] is NULL
+// so returns[0] points to a double
+// so returns[1] is NULL
+void distance_callback(const void *const*const parameters, void *const*const returns) {
+    const void *const *pose = (const void *const *)parameters[0];
+    double x = *(const double*)pose[0];
+    double y = *(const double*)pose[1];
+    *(double*)returns[0] = sqrt(x * x + y * y);
+}
+
+// Sensor (double count)
+void count_sensor(double *const value) {
+    static int count = 0;
+    ++count;
+    *value = count;
+}
+
+// Axis (double example)
+void example_axis(const double value) {
+    printf("Axis got %lf.\n", value);
+}
+
+struct NamedCallback {
+    const char *name;
+    GenericCallback_t callback;
+};
+const struct NamedCallback callbacks[] = {
+    {"multiply", (GenericCallback_t)multiply_callback},
+    {"distance", (GenericCallback_t)distance_callback},
+    {"count", (GenericCallback_t)count_sensor},
+    {"example", (GenericCallback_t)example_axis},
+    {NULL, NULL},
+};
+
+GenericCallback_t lookup_callback(const char *name, void *user_data) {
+    (void)user_data;
+    for (int i = 0; callbacks[i].name; ++i) {
+        if (strcmp(callbacks[i].name, name) == 0) {
+            return callbacks[i].callback;
+        }
+    }
+    return NULL;
+}
+
+int main(int argc, char **argv) {
+    const char *config_path = argc > 1 ? argv[1] : "example_config.toml";
+
+    ClientHandle handle = InitializeLibrary();
+    printf("handle: %p\n", handle);
+
+    enum ErrorCode result;
+
+    printf("loading %s\n", config_path);
+    result = LoadMachineConfig(handle, config_path, lookup_callback, NULL);
+    printf("result: %d\n", (int)result);
+    if (result != NoError) {
+        ShutdownLibrary(handle);
+        return 1;
+    }
+
+    if (argc > 2 && strcmp(argv[2], "--export") == 0) {
+        char *json = NULL;
+        result = ExportMachineDescription(handle, &json);
+        if (result == NoError) {
+            printf("%s\n", json);
+            FreeString(json);
+        }
+        ShutdownLibrary(handle);
+        return result == NoError ? 0 : 1;
+    }
+
+    printf("connecting\n");
+    result = ConnectToServer(handle, "localhost", 45575, 45577);
+    printf("result: %d\n", (int)result);
+
+    //for (int i = 0; i < 10; ++i) {
+    while(true) {
+        sleep(1);
+
+        printf("updating\n");
+        result = LibraryUpdate(handle);
+        printf("result: %d\n", (int)result);
+
+    }
+
+    printf("shutting down\n");
+    ShutdownLibrary(handle);
+}
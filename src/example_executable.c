@@ -1,6 +1,7 @@
 #include <stdio.h>
 #include <stdlib.h>
 #include <stdbool.h>
+#include <string.h>
 #include "client.h"
 #include "unistd.h"
 
@@ -20,6 +21,34 @@ const char *print_returns[][2] = {
     {NULL, NULL},
 };
 
+// Signature: (String name, int times = 1, String suffix optional) -> ()
+// so parameters[0] points to a const char *name
+// so parameters[1] points to a const int times, which is 1 if the server did not supply it
+// so parameters[2] points to a const char *suffix, or is NULL if the server did not supply it
+// so parameters[3] is NULL
+// so returns[0] is NULL
+void greet_callback(const void *const*const parameters, void *const*const returns) {
+    (void)returns;
+    const char *name = *((const char**)parameters[0]);
+    int times = *(const int*)parameters[1];
+    const char *suffix = parameters[2] ? *((const char**)parameters[2]) : "!";
+    if (!ParameterSupplied(1)) {
+        printf("(times defaulted to %d)\n", times);
+    }
+    for (int i = 0; i < times; ++i) {
+        printf("Hello, %s%s\n", name, suffix);
+    }
+}
+const char *greet_parameters[][2] = {
+    {"name", "string"},
+    {"times", "int = 1"},
+    {"suffix", "string optional"},
+    {NULL, NULL},
+};
+const char *greet_returns[][2] = {
+    {NULL, NULL},
+};
+
 // Signature: (int x, int y) -> (int product)
 // so parameters[0] points to a const int x
 // so parameters[1] points to a const int y
@@ -129,6 +158,256 @@ const char *count_bools_returns[][2] = {
     {NULL, NULL},
 };
 
+// Signature: (int x, int y) -> (int quotient), can fail
+// so parameters[0] points to a const int x
+// so parameters[1] points to a const int y
+// so parameters[2] is NULL
+// so returns[0] points to an int quotient
+// so returns[1] is NULL
+int divide_callback(const void *const*const parameters, void *const*const returns) {
+    int x = *(const int*)parameters[0];
+    int y = *(const int*)parameters[1];
+    int *quotient = (int*)returns[0];
+    if (y == 0) {
+        SetCallbackError("division by zero");
+        return 1;
+    }
+    *quotient = x / y;
+    return 0;
+}
+const char *divide_parameters[][2] = {
+    {"x", "int"},
+    {"y", "int"},
+    {NULL, NULL},
+};
+const char *divide_returns[][2] = {
+    {"quotient", "int"},
+    {NULL, NULL},
+};
+
+// Signature: (bytes data) -> (uint checksum)
+// so parameters[0] points to a struct ArrayInputParameter_t {const int length; const void *data;}, and data points to const uint8_t[length]
+// so parameters[1] is NULL
+// so returns[0] points to a uint32_t checksum
+// so returns[1] is NULL
+void checksum_callback(const void *const*const parameters, void *const*const returns) {
+    const struct ArrayInputParameter_t data_struct = *(const struct ArrayInputParameter_t*)parameters[0];
+    const uint8_t *data = (const uint8_t*)data_struct.data;
+    uint32_t *checksum = (uint32_t*)returns[0];
+
+    *checksum = 0;
+    for (int i = 0; i < data_struct.length; ++i) {
+        *checksum += data[i];
+    }
+}
+const char *checksum_parameters[][2] = {
+    {"data", "bytes"},
+    {NULL, NULL},
+};
+const char *checksum_returns[][2] = {
+    {"checksum", "uint"},
+    {NULL, NULL},
+};
+
+// Struct pose {double x, double y, double theta}
+const char *pose_fields[][2] = {
+    {"x", "double"},
+    {"y", "double"},
+    {"theta", "double"},
+    {NULL, NULL},
+};
+
+// Signature: (pose target) -> (pose reached)
+// so parameters[0] points to a const void *const[3], whose elements point to const doubles x, y, and theta
+// so parameters[1] is NULL
+// so returns[0] points to a void *const[3], whose elements point to doubles x, y, and theta
+// so returns[1] is NULL
+void move_to_callback(const void *const*const parameters, void *const*const returns) {
+    const void *const*const target = (const void *const*)parameters[0];
+    void *const*const reached = (void *const*)returns[0];
+    printf("Moving to (%lf, %lf, %lf).\n", *(const double*)target[0], *(const double*)target[1], *(const double*)target[2]);
+    *(double*)reached[0] = *(const double*)target[0];
+    *(double*)reached[1] = *(const double*)target[1];
+    *(double*)reached[2] = *(const double*)target[2];
+}
+const char *move_to_parameters[][2] = {
+    {"target", "pose"},
+    {NULL, NULL},
+};
+const char *move_to_returns[][2] = {
+    {"reached", "pose"},
+    {NULL, NULL},
+};
+
+// Enum speed_level {low, medium, high}
+const char *speed_level_values[] = {"low", "medium", "high", NULL};
+
+// Signature: (speed_level level) -> (speed_level previous)
+// so parameters[0] points to a const int, the index of the level in speed_level_values
+// so parameters[1] is NULL
+// so returns[0] points to an int, the index of the previous level in speed_level_values
+// so returns[1] is NULL
+void set_speed_callback(const void *const*const parameters, void *const*const returns) {
+    static int speed = 0;
+    int level = *(const int*)parameters[0];
+    int *previous = (int*)returns[0];
+    printf("Speed set to %s.\n", speed_level_values[level]);
+    *previous = speed;
+    speed = level;
+}
+const char *set_speed_parameters[][2] = {
+    {"level", "speed_level"},
+    {NULL, NULL},
+};
+const char *set_speed_returns[][2] = {
+    {"previous", "speed_level"},
+    {NULL, NULL},
+};
+
+// Signature: (double[3][3] matrix, double[3] vector) -> (double[3] product)
+// so parameters[0] points to a const double[3][3], in row-major order
+// so parameters[1] points to a const double[3]
+// so parameters[2] is NULL
+// so returns[0] points to a double[3]
+// so returns[1] is NULL
+void transform_callback(const void *const*const parameters, void *const*const returns) {
+    const double (*matrix)[3] = (const double (*)[3])parameters[0];
+    const double *vector = (const double*)parameters[1];
+    double *product = (double*)returns[0];
+    for (int i = 0; i < 3; i++) {
+        product[i] = 0.0;
+        for (int j = 0; j < 3; j++) {
+            product[i] += matrix[i][j] * vector[j];
+        }
+    }
+}
+const char *transform_parameters[][2] = {
+    {"matrix", "double[3][3]"},
+    {"vector", "double[3]"},
+    {NULL, NULL},
+};
+const char *transform_returns[][2] = {
+    {"product", "double[3]"},
+    {NULL, NULL},
+};
+
+// Signature: (map<string, double> gains) -> (map<string, double> current)
+// so parameters[0] points to a struct MapInputParameter_t {const int length; const char *const *keys; const void *values;},
+// and values points to a const double[length]
+// so parameters[1] is NULL
+// so returns[0] points to a struct MapOutputParameter_t
+// {int length; struct StringOutputParameter_t *keys; void *values; void(*release)(int, struct StringOutputParameter_t*, void*)}
+// so returns[1] is NULL
+const char *gain_names[] = {"kp", "ki", "kd"};
+double gain_values[] = {1.0, 0.0, 0.0};
+void set_gains_free(int length, struct StringOutputParameter_t *keys, void *values) {
+    (void)length;
+    free(keys);
+    free(values);
+}
+int set_gains_callback(const void *const*const parameters, void *const*const returns) {
+    const struct MapInputParameter_t gains = *(const struct MapInputParameter_t*)parameters[0];
+    const double *values = (const double*)gains.values;
+    for (int i = 0; i < gains.length; ++i) {
+        int found = 0;
+        for (int j = 0; j < 3; ++j) {
+            if (strcmp(gains.keys[i], gain_names[j]) == 0) {
+                gain_values[j] = values[i];
+                found = 1;
+            }
+        }
+        if (!found) {
+            SetCallbackError("unknown gain");
+            return 1;
+        }
+    }
+
+    struct MapOutputParameter_t *current = (struct MapOutputParameter_t*)returns[0];
+    struct StringOutputParameter_t *keys = malloc(3 * sizeof(struct StringOutputParameter_t));
+    double *current_values = malloc(3 * sizeof(double));
+    if (!keys || !current_values) {
+        free(keys);
+        free(current_values);
+        SetCallbackError("out of memory");
+        return 1;
+    }
+    for (int j = 0; j < 3; ++j) {
+        keys[j] = (struct StringOutputParameter_t){gain_names[j], NULL};
+        current_values[j] = gain_values[j];
+    }
+    current->length = 3;
+    current->keys = keys;
+    current->values = current_values;
+    current->release = set_gains_free;
+    return 0;
+}
+const char *set_gains_parameters[][2] = {
+    {"gains", "map<string, double>"},
+    {NULL, NULL},
+};
+const char *set_gains_returns[][2] = {
+    {"current", "map<string, double>"},
+    {NULL, NULL},
+};
+
+// Sensor (bytes serial_number)
+// so output points to a struct ArrayOutputParameter_t {int length; void *data;, void(*release)(int, void*)}
+int serial_number_sensor(void *const output) {
+    static uint8_t serial_number[] = {0xde, 0xad, 0xbe, 0xef};
+    struct ArrayOutputParameter_t *value = (struct ArrayOutputParameter_t*)output;
+    value->length = sizeof(serial_number);
+    value->data = serial_number;
+    return 0;
+}
+
+// Sensor (double? temperature)
+// so output points to a struct NullableOutputParameter_t {bool present; void *const value;},
+// and value points to a double
+int temperature_sensor(void *const output) {
+    static int reads = 0;
+    struct NullableOutputParameter_t *temperature = (struct NullableOutputParameter_t*)output;
+    // No reading until the sensor has warmed up
+    if (++reads > 3) {
+        temperature->present = true;
+        *(double*)temperature->value = 21.5;
+    }
+    return 0;
+}
+
+// Signature: (int[] values, int target, int? start = null) -> (int? index)
+// so parameters[0] points to a struct ArrayInputParameter_t {const int length; const void *data;}, and data points to a const int[length]
+// so parameters[1] points to a const int
+// so parameters[2] points to a struct NullableInputParameter_t {const bool present; const void *value;},
+// and value points to a const int if present
+// so parameters[3] is NULL
+// so returns[0] points to a struct NullableOutputParameter_t {bool present; void *const value;}, and value points to an int
+// so returns[1] is NULL
+void find_callback(const void *const*const parameters, void *const*const returns) {
+    const struct ArrayInputParameter_t values_struct = *(const struct ArrayInputParameter_t*)parameters[0];
+    const int *values = (const int*)values_struct.data;
+    const int target = *(const int*)parameters[1];
+    const struct NullableInputParameter_t start = *(const struct NullableInputParameter_t*)parameters[2];
+    struct NullableOutputParameter_t *index = (struct NullableOutputParameter_t*)returns[0];
+    const int first = start.present ? *(const int*)start.value : 0;
+    for (int i = first > 0 ? first : 0; i < values_struct.length; ++i) {
+        if (values[i] == target) {
+            index->present = true;
+            *(int*)index->value = i;
+            return;
+        }
+    }
+}
+const char *find_parameters[][2] = {
+    {"values", "int[]"},
+    {"target", "int"},
+    {"start", "int? = null"},
+    {NULL, NULL},
+};
+const char *find_returns[][2] = {
+    {"index", "int?"},
+    {NULL, NULL},
+};
+
 // Sensor (double count)
 void count_sensor(double *const value) {
     static int count = 0;
@@ -166,6 +445,10 @@ int main() {
     result = RegisterFunction(handle, "print", print_parameters, print_returns, print_callback);
     printf("result: %d\n", (int)result);
 
+    printf("registering \"greet\" function\n");
+    result = RegisterFunction(handle, "greet", greet_parameters, greet_returns, greet_callback);
+    printf("result: %d\n", (int)result);
+
     printf("registering \"multiply\" function\n");
     result = RegisterFunction(handle, "multiply", multiply_parameters, multiply_returns, multiply_callback);
     printf("result: %d\n", (int)result);
@@ -182,6 +465,50 @@ int main() {
     result = RegisterFunction(handle, "count_bools", count_bools_parameters, count_bools_returns, count_bools_callback);
     printf("result: %d\n", (int)result);
 
+    printf("registering \"divide\" function\n");
+    result = RegisterFunctionEx(handle, "divide", divide_parameters, divide_returns, divide_callback);
+    printf("result: %d\n", (int)result);
+
+    printf("registering \"pose\" struct\n");
+    result = RegisterStruct(handle, "pose", pose_fields);
+    printf("result: %d\n", (int)result);
+
+    printf("registering \"move_to\" function\n");
+    result = RegisterFunction(handle, "move_to", move_to_parameters, move_to_returns, move_to_callback);
+    printf("result: %d\n", (int)result);
+
+    printf("registering \"speed_level\" enum\n");
+    result = RegisterEnum(handle, "speed_level", speed_level_values);
+    printf("result: %d\n", (int)result);
+
+    printf("registering \"set_speed\" function\n");
+    result = RegisterFunction(handle, "set_speed", set_speed_parameters, set_speed_returns, set_speed_callback);
+    printf("result: %d\n", (int)result);
+
+    printf("registering \"transform\" function\n");
+    result = RegisterFunction(handle, "transform", transform_parameters, transform_returns, transform_callback);
+    printf("result: %d\n", (int)result);
+
+    printf("registering \"set_gains\" function\n");
+    result = RegisterFunctionEx(handle, "set_gains", set_gains_parameters, set_gains_returns, set_gains_callback);
+    printf("result: %d\n", (int)result);
+
+    printf("registering \"checksum\" function\n");
+    result = RegisterFunction(handle, "checksum", checksum_parameters, checksum_returns, checksum_callback);
+    printf("result: %d\n", (int)result);
+
+    printf("registering \"serial_number\" sensor\n");
+    result = RegisterSensorTyped(handle, "serial_number", "bytes", serial_number_sensor);
+    printf("result: %d\n", (int)result);
+
+    printf("registering \"find\" function\n");
+    result = RegisterFunction(handle, "find", find_parameters, find_returns, find_callback);
+    printf("result: %d\n", (int)result);
+
+    printf("registering \"temperature\" sensor\n");
+    result = RegisterSensorTyped(handle, "temperature", "double?", temperature_sensor);
+    printf("result: %d\n", (int)result);
+
     printf("registering \"count\" sensor\n");
     result = RegisterSensor(handle, "count", 0.0, 100000.0, count_sensor);
     printf("result: %d\n", (int)result);
@@ -194,6 +521,25 @@ int main() {
     result = RegisterStream(handle, "webcam", "mjpeg", STDIN_FILENO);
     printf("result: %d\n", (int)result);
 
+    const char *set_gains_tags[] = {"control", "tuning", NULL};
+    printf("setting \"set_gains\" metadata\n");
+    result = SetFunctionMetadata(handle, "set_gains", "Sets PID controller gains", NULL, set_gains_tags);
+    printf("result: %d\n", (int)result);
+    result = SetParameterMetadata(handle, "set_gains", "gains", "Gains to change, by name (kp, ki, or kd)", NULL, NULL);
+    printf("result: %d\n", (int)result);
+
+    printf("setting \"temperature\" metadata\n");
+    result = SetSensorMetadata(handle, "temperature", "Ambient temperature, once warmed up", "degrees C", NULL);
+    printf("result: %d\n", (int)result);
+
+    printf("setting \"example\" metadata\n");
+    result = SetAxisMetadata(handle, "example", "Example axis", "m/s", NULL);
+    printf("result: %d\n", (int)result);
+
+    printf("setting \"webcam\" metadata\n");
+    result = SetStreamMetadata(handle, "webcam", "Front camera", NULL, NULL);
+    printf("result: %d\n", (int)result);
+
     printf("connecting\n");
     result = ConnectToServer(handle, "localhost", 45575, 45577);
     printf("result: %d\n", (int)result);